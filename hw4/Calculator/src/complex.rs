@@ -0,0 +1,234 @@
+/*
+ * Reverse Polish Notation: complex.rs
+ * See `rpn.md` for the overview.
+ *
+ * Complex numbers, written `3+4i`. Arithmetic is done in rectangular form; the polar form is only
+ * computed for `abs`, `arg` and exponentiation.
+ */
+
+use std::fmt;
+
+use super::rpn::{fmt_float, DEFAULT_PRECISION};
+
+/// A complex number `re + im*i`.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Complex {
+    pub re: f64,
+    pub im: f64,
+}
+
+impl Complex {
+    pub fn new(re: f64, im: f64) -> Self {
+        Complex { re, im }
+    }
+
+    /// The complex number with magnitude `r` and argument `theta` (in radians).
+    pub fn from_polar(r: f64, theta: f64) -> Self {
+        Complex::new(r * theta.cos(), r * theta.sin())
+    }
+
+    /// Read a literal like `3+4i`, `-2.5-1e3i`, `3-i` or `4i`. Plain real numbers and the bare
+    /// name `i` are not complex literals.
+    pub fn parse(tok: &str) -> Option<Complex> {
+        let body = tok.strip_suffix('i')?;
+        if body.is_empty() || !body.chars().all(|c| "0123456789.eE+-".contains(c)) {
+            return None;
+        }
+
+        // The real and imaginary parts are split at the last sign that isn't part of an exponent
+        let split = body
+            .char_indices()
+            .skip(1)
+            .filter(|&(idx, c)| (c == '+' || c == '-') && !body[..idx].ends_with(['e', 'E']))
+            .map(|(idx, _)| idx)
+            .last();
+        let (re, im) = match split {
+            Some(idx) => (body[..idx].parse().ok()?, &body[idx..]),
+            None => return Some(Complex::new(0.0, body.parse().ok()?)),
+        };
+        let im = match im {
+            "+" => 1.0,
+            "-" => -1.0,
+            _ => im.parse().ok()?,
+        };
+        Some(Complex::new(re, im))
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.re == 0.0 && self.im == 0.0
+    }
+
+    pub fn add(&self, other: &Complex) -> Complex {
+        Complex::new(self.re + other.re, self.im + other.im)
+    }
+
+    pub fn sub(&self, other: &Complex) -> Complex {
+        Complex::new(self.re - other.re, self.im - other.im)
+    }
+
+    pub fn mul(&self, other: &Complex) -> Complex {
+        Complex::new(
+            self.re * other.re - self.im * other.im,
+            self.re * other.im + self.im * other.re,
+        )
+    }
+
+    /// Quotient of two complex numbers; dividing by zero gives infinities and NaNs, so callers
+    /// should check `is_zero` first.
+    pub fn div(&self, other: &Complex) -> Complex {
+        let norm = other.re * other.re + other.im * other.im;
+        let num = self.mul(&other.conj());
+        Complex::new(num.re / norm, num.im / norm)
+    }
+
+    pub fn conj(&self) -> Complex {
+        Complex::new(self.re, -self.im)
+    }
+
+    /// The magnitude `|z|`.
+    pub fn abs(&self) -> f64 {
+        self.re.hypot(self.im)
+    }
+
+    /// The argument of `z`, in radians between -pi and pi.
+    pub fn arg(&self) -> f64 {
+        self.im.atan2(self.re)
+    }
+
+    pub fn exp(&self) -> Complex {
+        Complex::from_polar(self.re.exp(), self.im)
+    }
+
+    /// The principal natural logarithm; `None` for zero.
+    pub fn ln(&self) -> Option<Complex> {
+        if self.is_zero() {
+            return None;
+        }
+        Some(Complex::new(self.abs().ln(), self.arg()))
+    }
+
+    /// The principal value of `self^exp`. Zero to a power with a positive real part is zero, and
+    /// any other power of zero is undefined.
+    pub fn pow(&self, exp: &Complex) -> Option<Complex> {
+        if self.is_zero() {
+            return match exp {
+                _ if exp.is_zero() => Some(Complex::new(1.0, 0.0)),
+                _ if exp.re > 0.0 => Some(Complex::new(0.0, 0.0)),
+                _ => None,
+            };
+        }
+        Some(exp.mul(&self.ln()?).exp())
+    }
+
+    /// Whether two complex numbers are equal, up to floating-point rounding.
+    pub fn same_as(&self, other: &Complex) -> bool {
+        let scale = self.abs().max(other.abs());
+        self == other || self.sub(other).abs() <= scale * 1e-12
+    }
+
+    // Round both parts to `digits` significant digits of the larger one, so that rounding noise
+    // like the 1.2e-16 in `1i 2 pow` disappears
+    fn rounded(&self, digits: usize) -> Complex {
+        let biggest = self.re.abs().max(self.im.abs());
+        if biggest == 0.0 || !biggest.is_finite() {
+            return *self;
+        }
+        let scale = 10f64.powi(biggest.log10().floor() as i32 + 1 - digits as i32);
+        let round = |val: f64| (val / scale).round() * scale + 0.0;
+        Complex::new(round(self.re), round(self.im))
+    }
+}
+
+impl fmt::Display for Complex {
+    /// Written like the literals, `3+4i`. The formatter's precision, if any, is the number of
+    /// significant digits.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let digits = f.precision().unwrap_or(DEFAULT_PRECISION);
+        let Complex { re, im } = self.rounded(digits);
+        let (re, im_abs) = (fmt_float(re, digits), fmt_float(im.abs(), digits));
+        let sign = if im.is_sign_negative() { "-" } else { "+" };
+        match (re.as_str(), im_abs.as_str()) {
+            ("0", "0") => write!(f, "0+0i"),
+            ("0", _) if sign == "-" => write!(f, "-{}i", im_abs),
+            ("0", _) => write!(f, "{}i", im_abs),
+            _ => write!(f, "{}{}{}i", re, sign, im_abs),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f64::consts::PI;
+
+    fn parse(tok: &str) -> Complex {
+        Complex::parse(tok).unwrap()
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(parse("3+4i"), Complex::new(3.0, 4.0));
+        assert_eq!(parse("-2.5-1e3i"), Complex::new(-2.5, -1000.0));
+        assert_eq!(parse("1e-3+2E+2i"), Complex::new(0.001, 200.0));
+        assert_eq!(parse("3-i"), Complex::new(3.0, -1.0));
+        assert_eq!(parse("4i"), Complex::new(0.0, 4.0));
+        assert_eq!(parse("-0.5i"), Complex::new(0.0, -0.5));
+        for tok in &[
+            "i", "-i", "3", "3+4", "pi", "x+2i", "3+4j", "3++4i", "infi", "1e+i",
+        ] {
+            assert_eq!(Complex::parse(tok), None, "{}", tok);
+        }
+    }
+
+    #[test]
+    fn test_display() {
+        assert_eq!(Complex::new(3.0, 4.0).to_string(), "3+4i");
+        assert_eq!(Complex::new(3.0, -4.0).to_string(), "3-4i");
+        assert_eq!(Complex::new(0.0, -1.0).to_string(), "-1i");
+        assert_eq!(Complex::new(-2.0, 0.0).to_string(), "-2+0i");
+        assert_eq!(Complex::new(-0.0, -0.0).to_string(), "0+0i");
+        assert_eq!(
+            parse("1i")
+                .pow(&Complex::new(2.0, 0.0))
+                .unwrap()
+                .to_string(),
+            "-1+0i"
+        );
+
+        let z = Complex::new(1.0 / 3.0, PI);
+        assert_eq!(z.to_string(), "0.33333333333+3.14159265359i");
+        assert_eq!(format!("{:.3}", z), "0.33+3.14i");
+        assert_eq!(format!("{:.1}", z), "3i");
+    }
+
+    #[test]
+    fn test_arithmetic() {
+        let (a, b) = (parse("3+4i"), parse("1-2i"));
+        assert_eq!(a.add(&b), parse("4+2i"));
+        assert_eq!(a.sub(&b), parse("2+6i"));
+        assert_eq!(a.mul(&b), parse("11-2i"));
+        assert!(a.div(&b).same_as(&parse("-1+2i")));
+        assert_eq!(a.conj(), parse("3-4i"));
+        assert_eq!(a.abs(), 5.0);
+        assert_eq!(parse("-1+0i").arg(), PI);
+        assert!(Complex::from_polar(2.0, PI / 2.0).same_as(&parse("2i")));
+    }
+
+    #[test]
+    fn test_pow() {
+        let pow = |z: &str, w: &str| parse(z).pow(&parse(w));
+        // e^(i*pi) = -1, and i^i is real
+        let e = Complex::new(1f64.exp(), 0.0);
+        assert!(e
+            .pow(&Complex::new(0.0, PI))
+            .unwrap()
+            .same_as(&parse("-1+0i")));
+        assert!(pow("1i", "1i")
+            .unwrap()
+            .same_as(&Complex::new((-PI / 2.0).exp(), 0.0)));
+        assert!(pow("1+1i", "2+0i").unwrap().same_as(&parse("2i")));
+        assert_eq!(pow("0+0i", "2+1i"), Some(parse("0+0i")));
+        assert_eq!(pow("0+0i", "0+0i"), Some(parse("1+0i")));
+        assert_eq!(pow("0+0i", "-1+0i"), None);
+    }
+}
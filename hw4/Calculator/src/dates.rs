@@ -0,0 +1,508 @@
+/*
+ * Reverse Polish Notation: dates.rs
+ * See `rpn.md` for the overview.
+ *
+ * Calendar dates, times of day and durations, written as in ISO 8601: `2026-10-17`, `12:30` and
+ * `P1Y2M3DT4H`. Dates use the proleptic Gregorian calendar and count days from 1970-01-01; there
+ * are no time zones, and every day has 24 hours.
+ */
+
+use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const SECS_PER_DAY: i64 = 24 * 60 * 60;
+
+// The first and last dates we can write as `YYYY-MM-DD`
+const MIN_YEAR: i64 = 1;
+const MAX_YEAR: i64 = 9999;
+
+/// A calendar date, as a number of days since 1970-01-01.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub struct Date(i64);
+
+/// A time of day, as a number of seconds since midnight.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub struct Time(i64);
+
+/// A length of time. Months (and years) and days have different lengths depending on where in the
+/// calendar they start, so they are kept apart from the hours, minutes and seconds.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct Duration {
+    pub months: i64,
+    pub days: i64,
+    pub seconds: i64,
+}
+
+fn is_leap(year: i64) -> bool {
+    year % 4 == 0 && (year % 100 != 0 || year % 400 == 0)
+}
+
+fn days_in_month(year: i64, month: i64) -> i64 {
+    match month {
+        2 if is_leap(year) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+// Parse a field of exactly `len` ASCII digits
+fn digits(field: &str, len: usize) -> Option<i64> {
+    if field.len() != len || !field.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    field.parse().ok()
+}
+
+impl Date {
+    /// The date with the given year, month (1-12) and day of the month, if there is one.
+    pub fn new(year: i64, month: i64, day: i64) -> Option<Date> {
+        if !(MIN_YEAR..=MAX_YEAR).contains(&year)
+            || !(1..=12).contains(&month)
+            || !(1..=days_in_month(year, month)).contains(&day)
+        {
+            return None;
+        }
+
+        // Count from 0000-03-01, so that leap days come at the end of the year
+        let (year, month) = if month <= 2 {
+            (year - 1, month + 9)
+        } else {
+            (year, month - 3)
+        };
+        let era = year.div_euclid(400);
+        let year_of_era = year.rem_euclid(400);
+        let day_of_year = (153 * month + 2) / 5 + day - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+        Some(Date(era * 146_097 + day_of_era - 719_468))
+    }
+
+    /// Read a date written `YYYY-MM-DD`.
+    pub fn parse(tok: &str) -> Option<Date> {
+        let mut fields = tok.split('-');
+        let year = digits(fields.next()?, 4)?;
+        let month = digits(fields.next()?, 2)?;
+        let day = digits(fields.next()?, 2)?;
+        match fields.next() {
+            Some(_) => None,
+            None => Date::new(year, month, day),
+        }
+    }
+
+    /// Today's date, in UTC.
+    pub fn today() -> Date {
+        let secs = match SystemTime::now().duration_since(UNIX_EPOCH) {
+            Ok(since) => since.as_secs() as i64,
+            Err(before) => -(before.duration().as_secs() as i64),
+        };
+        Date(secs.div_euclid(SECS_PER_DAY))
+    }
+
+    // A date from its day count, if it's one we can write down
+    fn from_days(days: i64) -> Option<Date> {
+        let first = Date::new(MIN_YEAR, 1, 1)?;
+        let last = Date::new(MAX_YEAR, 12, 31)?;
+        match Date(days) {
+            date if first <= date && date <= last => Some(date),
+            _ => None,
+        }
+    }
+
+    /// The year, month and day of the month.
+    pub fn ymd(&self) -> (i64, i64, i64) {
+        let days = self.0 + 719_468;
+        let era = days.div_euclid(146_097);
+        let day_of_era = days.rem_euclid(146_097);
+        let year_of_era =
+            (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let month = (5 * day_of_year + 2) / 153;
+        let day = day_of_year - (153 * month + 2) / 5 + 1;
+        let month = if month < 10 { month + 3 } else { month - 9 };
+        let year = year_of_era + era * 400 + i64::from(month <= 2);
+        (year, month, day)
+    }
+
+    /// The ISO day of the week, from 1 for Monday to 7 for Sunday.
+    pub fn weekday(&self) -> i64 {
+        // 1970-01-01 was a Thursday
+        (self.0 + 3).rem_euclid(7) + 1
+    }
+
+    fn is_weekend(&self) -> bool {
+        self.weekday() > 5
+    }
+
+    /// The ISO week number, from 1 to 53. Weeks start on Monday, and week 1 is the one with the
+    /// year's first Thursday in it, so the first few days of January can be in the last week of
+    /// the year before, and the last few days of December in week 1 of the next.
+    pub fn week(&self) -> i64 {
+        // The week's Thursday decides which year the week belongs to
+        let thursday = Date(self.0 - self.weekday() + 4);
+        let (year, _, _) = thursday.ymd();
+        let jan_1 = Date::new(year, 1, 1).map_or(thursday.0, |date| date.0);
+        (thursday.0 - jan_1) / 7 + 1
+    }
+
+    /// The date a duration later. Adding months keeps the day of the month, except that it is cut
+    /// short at the end of shorter months: a month after January 31 is the end of February. Only
+    /// whole days can be added to a date.
+    pub fn add(&self, dur: &Duration) -> Option<Date> {
+        if dur.seconds % SECS_PER_DAY != 0 {
+            return None;
+        }
+
+        let (year, month, day) = self.ymd();
+        let months = (year * 12 + month - 1).checked_add(dur.months)?;
+        let (year, month) = (months.div_euclid(12), months.rem_euclid(12) + 1);
+        let day = day.min(days_in_month(year, month));
+        let date = Date::new(year, month, day)?;
+
+        let days = dur.days.checked_add(dur.seconds / SECS_PER_DAY)?;
+        Date::from_days(date.0.checked_add(days)?)
+    }
+
+    /// The number of days from `other` to this date, as a duration.
+    pub fn since(&self, other: &Date) -> Duration {
+        Duration::days(self.0 - other.0)
+    }
+
+    /// The date `count` business days later (or earlier, if `count` is negative), skipping
+    /// Saturdays and Sundays. Counting from a weekend starts from the nearest business day before
+    /// it, so a business day after a Saturday is the Monday after.
+    pub fn add_business_days(&self, count: i64) -> Option<Date> {
+        if count == 0 {
+            return Some(*self);
+        }
+        let step = count.signum();
+
+        let mut date = *self;
+        while date.is_weekend() {
+            date = Date(date.0 - step);
+        }
+
+        // Every five business days make a whole week, which lands on the same day of the week
+        let weeks = (count / 5).checked_mul(7)?;
+        let mut days = Date::from_days(date.0.checked_add(weeks)?)?.0;
+        for _ in 0..(count % 5).abs() {
+            days += step;
+            while Date(days).is_weekend() {
+                days += step;
+            }
+        }
+        Date::from_days(days)
+    }
+
+    /// The number of business days from `other` up to, but not including, this date; negative if
+    /// this date comes first.
+    pub fn business_days_since(&self, other: &Date) -> i64 {
+        // Business days before a date, counting from the Monday 1970-01-05
+        fn before(date: &Date) -> i64 {
+            let since_monday = date.0 - 4;
+            since_monday.div_euclid(7) * 5 + since_monday.rem_euclid(7).min(5)
+        }
+        before(self) - before(other)
+    }
+}
+
+impl fmt::Display for Date {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (year, month, day) = self.ymd();
+        write!(f, "{:04}-{:02}-{:02}", year, month, day)
+    }
+}
+
+impl Time {
+    /// Read a time of day written `HH:MM` or `HH:MM:SS`, on a 24-hour clock.
+    pub fn parse(tok: &str) -> Option<Time> {
+        let mut fields = tok.split(':');
+        let hours = digits(fields.next()?, 2)?;
+        let minutes = digits(fields.next()?, 2)?;
+        let seconds = match fields.next() {
+            Some(field) => digits(field, 2)?,
+            None => 0,
+        };
+        if fields.next().is_some() || hours > 23 || minutes > 59 || seconds > 59 {
+            return None;
+        }
+        Some(Time(hours * 3600 + minutes * 60 + seconds))
+    }
+
+    /// The time a duration later, going round the clock past midnight. Months have no fixed
+    /// length, so they can't be added to a time.
+    pub fn add(&self, dur: &Duration) -> Option<Time> {
+        if dur.months != 0 {
+            return None;
+        }
+        let secs = (dur.days.checked_mul(SECS_PER_DAY)?).checked_add(dur.seconds)?;
+        Some(Time(
+            (self.0 + secs.rem_euclid(SECS_PER_DAY)) % SECS_PER_DAY,
+        ))
+    }
+
+    /// The time from `other` to this time of day, as a duration.
+    pub fn since(&self, other: &Time) -> Duration {
+        Duration::seconds(self.0 - other.0)
+    }
+}
+
+impl fmt::Display for Time {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (hours, minutes, seconds) = (self.0 / 3600, self.0 / 60 % 60, self.0 % 60);
+        write!(f, "{:02}:{:02}", hours, minutes)?;
+        if seconds != 0 {
+            write!(f, ":{:02}", seconds)?;
+        }
+        Ok(())
+    }
+}
+
+impl Duration {
+    pub fn days(days: i64) -> Duration {
+        Duration {
+            days,
+            ..Duration::default()
+        }
+    }
+
+    pub fn seconds(seconds: i64) -> Duration {
+        Duration {
+            seconds,
+            ..Duration::default()
+        }
+    }
+
+    /// Read a duration like `P3D`, `PT12H30M`, `P1Y2M3W4DT5H6M7S` or `-P1W`. Every part is a
+    /// whole number, and the parts come in that order.
+    pub fn parse(tok: &str) -> Option<Duration> {
+        let (sign, rest) = match tok.strip_prefix('-') {
+            Some(rest) => (-1, rest),
+            None => (1, tok),
+        };
+        let rest = rest.strip_prefix('P')?;
+        let (date_part, time_part) = match rest.split_once('T') {
+            Some((date_part, time_part)) if !time_part.is_empty() => (date_part, Some(time_part)),
+            Some(_) => return None,
+            None => (rest, None),
+        };
+        if date_part.is_empty() && time_part.is_none() {
+            return None;
+        }
+
+        // Each unit is a designator and how much of the duration one of them is
+        let date_units = [
+            ('Y', 12, 0, 0),
+            ('M', 1, 0, 0),
+            ('W', 0, 7, 0),
+            ('D', 0, 1, 0),
+        ];
+        let time_units = [('H', 0, 0, 3600), ('M', 0, 0, 60), ('S', 0, 0, 1)];
+
+        let mut dur = Duration::default();
+        for (part, units) in [
+            (date_part, &date_units[..]),
+            (time_part.unwrap_or(""), &time_units[..]),
+        ] {
+            let mut rest = part;
+            let mut units = units.iter();
+            while !rest.is_empty() {
+                let len = rest.find(|c: char| !c.is_ascii_digit())?;
+                let count: i64 = rest[..len].parse().ok()?;
+                let designator = rest[len..].chars().next()?;
+                let &(_, months, days, seconds) = units.find(|unit| unit.0 == designator)?;
+                dur.months = dur.months.checked_add(count.checked_mul(months)?)?;
+                dur.days = dur.days.checked_add(count.checked_mul(days)?)?;
+                dur.seconds = dur.seconds.checked_add(count.checked_mul(seconds)?)?;
+                rest = &rest[len + designator.len_utf8()..];
+            }
+        }
+        dur.scale(sign)
+    }
+
+    pub fn add(&self, other: &Duration) -> Option<Duration> {
+        Some(Duration {
+            months: self.months.checked_add(other.months)?,
+            days: self.days.checked_add(other.days)?,
+            seconds: self.seconds.checked_add(other.seconds)?,
+        })
+    }
+
+    pub fn sub(&self, other: &Duration) -> Option<Duration> {
+        self.add(&other.scale(-1)?)
+    }
+
+    /// The duration `factor` times over.
+    pub fn scale(&self, factor: i64) -> Option<Duration> {
+        Some(Duration {
+            months: self.months.checked_mul(factor)?,
+            days: self.days.checked_mul(factor)?,
+            seconds: self.seconds.checked_mul(factor)?,
+        })
+    }
+}
+
+impl fmt::Display for Duration {
+    /// Written the way it would be typed in. A duration with all its parts negative is written with
+    /// a leading `-`; if only some of them are, each part carries its own sign.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let negative = self.months <= 0 && self.days <= 0 && self.seconds <= 0;
+        let Duration {
+            months,
+            days,
+            seconds,
+        } = match negative {
+            true => self.scale(-1).unwrap_or(*self),
+            false => *self,
+        };
+        if negative && *self != Duration::default() {
+            write!(f, "-")?;
+        }
+
+        write!(f, "P")?;
+        let parts = [(months / 12, "Y"), (months % 12, "M"), (days, "D")];
+        for &(count, designator) in parts.iter().filter(|part| part.0 != 0) {
+            write!(f, "{}{}", count, designator)?;
+        }
+        if seconds != 0 {
+            write!(f, "T")?;
+            let parts = [
+                (seconds / 3600, "H"),
+                (seconds / 60 % 60, "M"),
+                (seconds % 60, "S"),
+            ];
+            for &(count, designator) in parts.iter().filter(|part| part.0 != 0) {
+                write!(f, "{}{}", count, designator)?;
+            }
+        } else if months == 0 && days == 0 {
+            write!(f, "0D")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(tok: &str) -> Date {
+        Date::parse(tok).unwrap()
+    }
+
+    fn dur(tok: &str) -> Duration {
+        Duration::parse(tok).unwrap()
+    }
+
+    #[test]
+    fn test_parse_display() {
+        assert_eq!(date("1970-01-01"), Date(0));
+        assert_eq!(date("2026-10-17").to_string(), "2026-10-17");
+        assert_eq!(date("2000-02-29").ymd(), (2000, 2, 29));
+        assert_eq!(date("0001-01-01").to_string(), "0001-01-01");
+        for tok in &[
+            "2026-02-29",
+            "1900-02-29",
+            "2026-13-01",
+            "2026-1-01",
+            "26-01-01",
+            "0000-01-01",
+        ] {
+            assert_eq!(Date::parse(tok), None, "{}", tok);
+        }
+
+        assert_eq!(Time::parse("12:30").unwrap().to_string(), "12:30");
+        assert_eq!(Time::parse("23:59:59").unwrap().to_string(), "23:59:59");
+        for tok in &["24:00", "12:60", "1:30", "12:30:60", "12:30:00:00", "12"] {
+            assert_eq!(Time::parse(tok), None, "{}", tok);
+        }
+
+        assert_eq!(dur("P3D"), Duration::days(3));
+        assert_eq!(dur("P2W"), Duration::days(14));
+        assert_eq!(dur("PT1H30M"), Duration::seconds(5400));
+        assert_eq!(dur("P1Y2M3W4DT5H6M7S").to_string(), "P1Y2M25DT5H6M7S");
+        assert_eq!(dur("-P1M1D").to_string(), "-P1M1D");
+        assert_eq!(dur("P0D").to_string(), "P0D");
+        assert_eq!(dur("P1M").sub(&dur("P1D")).unwrap().to_string(), "P1M-1D");
+        for tok in &[
+            "P", "PT", "P3", "PD", "P1DT", "P1D2Y", "P1H", "PT1D", "P1.5D", "3D", "P1é",
+        ] {
+            assert_eq!(Duration::parse(tok), None, "{}", tok);
+        }
+    }
+
+    #[test]
+    fn test_calendar() {
+        // Every day from 1600 to 2400 round-trips through its year, month and day
+        let (start, end) = (date("1600-01-01").0, date("2400-12-31").0);
+        for days in start..=end {
+            let (year, month, day) = Date(days).ymd();
+            assert_eq!(Date::new(year, month, day), Some(Date(days)));
+        }
+        assert_eq!(end - start + 1, 801 * 365 + 195);
+
+        assert_eq!(date("2026-10-17").weekday(), 6);
+        assert_eq!(date("2000-01-01").weekday(), 6);
+        assert_eq!(date("1969-12-29").weekday(), 1);
+
+        assert_eq!(date("2026-10-17").week(), 42);
+        assert_eq!(date("2021-01-03").week(), 53);
+        assert_eq!(date("2024-12-30").week(), 1);
+        assert_eq!(date("2026-01-01").week(), 1);
+        assert_eq!(date("2027-01-01").week(), 53);
+    }
+
+    #[test]
+    fn test_arithmetic() {
+        let add = |d: &str, p: &str| date(d).add(&dur(p)).map(|d| d.to_string());
+        assert_eq!(add("2026-10-17", "P3D"), Some(String::from("2026-10-20")));
+        assert_eq!(add("2026-01-31", "P1M"), Some(String::from("2026-02-28")));
+        assert_eq!(add("2024-02-29", "P1Y"), Some(String::from("2025-02-28")));
+        assert_eq!(
+            add("2026-03-31", "-P1M1D"),
+            Some(String::from("2026-02-27"))
+        );
+        assert_eq!(add("2026-10-17", "PT48H"), Some(String::from("2026-10-19")));
+        assert_eq!(add("2026-10-17", "PT1H"), None);
+        assert_eq!(add("9999-12-31", "P1D"), None);
+
+        assert_eq!(
+            date("2026-12-25").since(&date("2026-10-17")),
+            Duration::days(69)
+        );
+
+        let time = Time::parse("22:30").unwrap();
+        assert_eq!(time.add(&dur("PT2H")).unwrap().to_string(), "00:30");
+        assert_eq!(time.add(&dur("-P1DT23H")).unwrap().to_string(), "23:30");
+        assert_eq!(time.add(&dur("P1M")), None);
+        assert_eq!(
+            Time::parse("09:15").unwrap().since(&time).to_string(),
+            "-PT13H15M"
+        );
+    }
+
+    #[test]
+    fn test_business_days() {
+        let add = |d: &str, n| date(d).add_business_days(n).unwrap().to_string();
+        // 2026-10-16 is a Friday
+        assert_eq!(add("2026-10-16", 1), "2026-10-19");
+        assert_eq!(add("2026-10-16", 5), "2026-10-23");
+        assert_eq!(add("2026-10-16", 7), "2026-10-27");
+        assert_eq!(add("2026-10-17", 1), "2026-10-19");
+        assert_eq!(add("2026-10-17", 0), "2026-10-17");
+        assert_eq!(add("2026-10-19", -1), "2026-10-16");
+        assert_eq!(add("2026-10-18", -1), "2026-10-16");
+        assert_eq!(add("2026-10-21", -12), "2026-10-05");
+
+        let between = |a: &str, b: &str| date(b).business_days_since(&date(a));
+        assert_eq!(between("2026-10-16", "2026-10-19"), 1);
+        assert_eq!(between("2026-10-17", "2026-10-19"), 0);
+        assert_eq!(between("2026-10-01", "2026-11-01"), 22);
+        assert_eq!(between("2026-11-01", "2026-10-01"), -22);
+
+        // Adding and counting business days agree
+        let start = date("2026-10-14");
+        for count in -30..30 {
+            let end = start.add_business_days(count).unwrap();
+            assert_eq!(end.business_days_since(&start), count);
+        }
+    }
+}
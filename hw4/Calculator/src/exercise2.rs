@@ -14,20 +14,277 @@
  * https://en.wikipedia.org/wiki/Matrix_multiplication#Definition
  *
  * Note that not all pairs of matrices can be multiplied: if we are multiplying matrices A * B, the
- * number of columns in A must be equal to the number of rows in B. Your program is should panic
- * (whether from `panic!`, `assert!`, `assert_eq!`, out of bounds, or whatever) if this condition
- * is not satisfied, though in a more polished implementation we would try to handle these errors
- * more gracefully.
+ * number of columns in A must be equal to the number of rows in B. Rather than panicking when this
+ * condition is not satisfied, every operation here reports a `MatError` describing the shapes
+ * involved, so that the calculator can hand the error back to the user.
  *
  * Also note that indexing with square brackets in Rust is checked. If you access something beyond
  * the end of the vector, your program will panic. Don't do this!
  */
 
+use std::convert::TryFrom;
+
 pub type Matrix = Vec<Vec<i32>>;
 
+/// Ways a matrix operation can fail. Shapes are given as (rows, columns).
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum MatError {
+    Ragged,                                   // Rows have different lengths
+    Mismatch((usize, usize), (usize, usize)), // Operand shapes don't fit together
+    NotSquare((usize, usize)),                // Operation needs a square matrix
+    Overflow,                                 // Some entry doesn't fit in an i32
+}
+
+pub type MatResult<T> = std::result::Result<T, MatError>;
+
+/// Return the (rows, columns) shape of a matrix, checking that every row has the same length.
+///
+/// A matrix without any rows has shape (0, 0).
+pub fn shape(mat: &Matrix) -> MatResult<(usize, usize)> {
+    let cols = match mat.first() {
+        Some(row) => row.len(),
+        None => return Ok((0, 0)),
+    };
+
+    if mat.iter().any(|row| row.len() != cols) {
+        return Err(MatError::Ragged);
+    }
+
+    Ok((mat.len(), cols))
+}
+
+/// Return the size of a square matrix.
+fn square_size(mat: &Matrix) -> MatResult<usize> {
+    let (rows, cols) = shape(mat)?;
+    if rows == cols {
+        Ok(rows)
+    } else {
+        Err(MatError::NotSquare((rows, cols)))
+    }
+}
+
+/// Narrow a wide intermediate result back down to a matrix entry.
+fn narrow<T>(val: T) -> MatResult<i32>
+where
+    i32: TryFrom<T>,
+{
+    i32::try_from(val).map_err(|_| MatError::Overflow)
+}
+
 /// Multiply two matrices and return the result in a new matrix
-pub fn mat_mult(_mat1: &Matrix, _mat2: &Matrix) -> Matrix {
-    todo!()
+pub fn mat_mult(mat1: &Matrix, mat2: &Matrix) -> MatResult<Matrix> {
+    mat_mult_checked(mat1, mat2, &mut || Ok(()))
+}
+
+/// Multiply two matrices like `mat_mult`, calling `check` before each row of the result and giving
+/// up with its error if it fails. This lets a caller stop a long product part of the way through.
+pub fn mat_mult_checked<E: From<MatError>>(
+    mat1: &Matrix,
+    mat2: &Matrix,
+    check: &mut dyn FnMut() -> Result<(), E>,
+) -> Result<Matrix, E> {
+    let (rows1, cols1) = shape(mat1)?;
+    let (rows2, cols2) = shape(mat2)?;
+    if cols1 != rows2 {
+        return Err(MatError::Mismatch((rows1, cols1), (rows2, cols2)).into());
+    }
+
+    let mut result = Vec::with_capacity(rows1);
+    for row in mat1 {
+        check()?;
+        let mut new_row = Vec::with_capacity(cols2);
+        for col in 0..cols2 {
+            let mut entry: i64 = 0;
+            for (each, row2) in row.iter().zip(mat2) {
+                let prod = i64::from(*each) * i64::from(row2[col]);
+                entry = entry.checked_add(prod).ok_or(MatError::Overflow)?;
+            }
+            new_row.push(narrow(entry)?);
+        }
+        result.push(new_row);
+    }
+
+    Ok(result)
+}
+
+/// Add two matrices of the same shape entry by entry.
+pub fn mat_add(mat1: &Matrix, mat2: &Matrix) -> MatResult<Matrix> {
+    let shape1 = shape(mat1)?;
+    let shape2 = shape(mat2)?;
+    if shape1 != shape2 {
+        return Err(MatError::Mismatch(shape1, shape2));
+    }
+
+    let mut result = Vec::with_capacity(shape1.0);
+    for (row1, row2) in mat1.iter().zip(mat2) {
+        let mut new_row = Vec::with_capacity(shape1.1);
+        for (x, y) in row1.iter().zip(row2) {
+            new_row.push(x.checked_add(*y).ok_or(MatError::Overflow)?);
+        }
+        result.push(new_row);
+    }
+
+    Ok(result)
+}
+
+/// Multiply every entry of a matrix by a scalar.
+pub fn mat_scale(scalar: i32, mat: &Matrix) -> MatResult<Matrix> {
+    shape(mat)?;
+
+    let mut result = Vec::with_capacity(mat.len());
+    for row in mat {
+        let mut new_row = Vec::with_capacity(row.len());
+        for each in row {
+            new_row.push(scalar.checked_mul(*each).ok_or(MatError::Overflow)?);
+        }
+        result.push(new_row);
+    }
+
+    Ok(result)
+}
+
+/// Flip a matrix over its diagonal.
+pub fn transpose(mat: &Matrix) -> MatResult<Matrix> {
+    let (rows, cols) = shape(mat)?;
+
+    let mut result = vec![Vec::with_capacity(rows); cols];
+    for row in mat {
+        for (col, each) in row.iter().enumerate() {
+            result[col].push(*each);
+        }
+    }
+
+    Ok(result)
+}
+
+/// Make the `n` by `n` identity matrix.
+pub fn identity(n: usize) -> Matrix {
+    let mut result = vec![vec![0; n]; n];
+    for (i, row) in result.iter_mut().enumerate() {
+        row[i] = 1;
+    }
+
+    result
+}
+
+/// Compute the determinant of a square matrix.
+///
+/// We use the fraction-free Bareiss elimination, so every intermediate value is itself the
+/// determinant of some minor and the division at each step is exact. The empty matrix has
+/// determinant 1.
+pub fn determinant(mat: &Matrix) -> MatResult<i32> {
+    determinant_checked(mat, &mut || Ok(()))
+}
+
+/// Compute the determinant like `determinant`, calling `check` before each elimination step.
+pub fn determinant_checked<E: From<MatError>>(
+    mat: &Matrix,
+    check: &mut dyn FnMut() -> Result<(), E>,
+) -> Result<i32, E> {
+    let n = square_size(mat)?;
+    let mut work: Vec<Vec<i128>> = mat
+        .iter()
+        .map(|row| row.iter().map(|each| i128::from(*each)).collect())
+        .collect();
+
+    let mut sign = 1;
+    let mut prev_pivot = 1;
+    for k in 0..n {
+        check()?;
+
+        // Find a row with a non-zero pivot, swapping it into place
+        match (k..n).find(|&i| work[i][k] != 0) {
+            Some(i) if i != k => {
+                work.swap(i, k);
+                sign = -sign;
+            }
+            Some(_) => (),
+            None => return Ok(0),
+        }
+
+        for i in (k + 1)..n {
+            for j in (k + 1)..n {
+                let cross = work[i][j]
+                    .checked_mul(work[k][k])
+                    .and_then(|a| work[i][k].checked_mul(work[k][j]).map(|b| (a, b)))
+                    .and_then(|(a, b)| a.checked_sub(b))
+                    .ok_or(MatError::Overflow)?;
+                work[i][j] = cross / prev_pivot;
+            }
+        }
+        prev_pivot = work[k][k];
+    }
+
+    match n {
+        0 => Ok(1),
+        _ => Ok(narrow(sign * work[n - 1][n - 1])?),
+    }
+}
+
+/// Raise a square matrix to a non-negative integer power by repeated squaring.
+pub fn mat_pow(mat: &Matrix, exp: u32) -> MatResult<Matrix> {
+    mat_pow_checked(mat, exp, &mut || Ok(()))
+}
+
+/// Raise a square matrix to a power like `mat_pow`, calling `check` before each row of every
+/// product.
+pub fn mat_pow_checked<E: From<MatError>>(
+    mat: &Matrix,
+    exp: u32,
+    check: &mut dyn FnMut() -> Result<(), E>,
+) -> Result<Matrix, E> {
+    let n = square_size(mat)?;
+
+    let mut result = identity(n);
+    let mut base = mat.clone();
+    let mut exp = exp;
+    while exp > 0 {
+        if exp % 2 == 1 {
+            result = mat_mult_checked(&result, &base, check)?;
+        }
+        exp /= 2;
+        if exp > 0 {
+            base = mat_mult_checked(&base, &base, check)?;
+        }
+    }
+
+    Ok(result)
+}
+
+/// Render a matrix for display.
+///
+/// The layout depends on the shape: a matrix with a single row stays on one line, while taller
+/// matrices put each row on its own line with the columns right-aligned. Either way, the output
+/// can be read back in as a matrix literal.
+pub fn pretty(mat: &Matrix) -> String {
+    let cells: Vec<Vec<String>> = mat
+        .iter()
+        .map(|row| row.iter().map(|each| each.to_string()).collect())
+        .collect();
+
+    if cells.len() <= 1 {
+        let row = cells.first().map(|row| row.join(" ")).unwrap_or_default();
+        return format!("[[{}]]", row);
+    }
+
+    let width = cells
+        .iter()
+        .flat_map(|row| row.iter().map(|cell| cell.len()))
+        .max()
+        .unwrap_or(0);
+
+    let lines: Vec<String> = cells
+        .iter()
+        .map(|row| {
+            let padded: Vec<String> = row
+                .iter()
+                .map(|cell| format!("{:>width$}", cell, width = width))
+                .collect();
+            format!("[{}]", padded.join(" "))
+        })
+        .collect();
+
+    format!("[{}]", lines.join("\n "))
 }
 
 /// You can put more tests here.
@@ -46,22 +303,120 @@ mod tests {
 
         let mat6 = vec![vec![8], vec![9]];
 
-        assert_eq!(&mat_mult(&mat1, &mat1), &mat4);
-        assert_eq!(&mat_mult(&mat1, &mat5), &mat6);
+        assert_eq!(mat_mult(&mat1, &mat1), Ok(mat4));
+        assert_eq!(mat_mult(&mat1, &mat5), Ok(mat6));
         assert_eq!(mat_mult(&mat1, &mat2), mat_mult(&mat2, &mat1));
         assert_eq!(
-            mat_mult(&mat_mult(&mat1, &mat2), &mat3),
-            mat_mult(&mat1, &mat_mult(&mat2, &mat3))
+            mat_mult(&mat_mult(&mat1, &mat2).unwrap(), &mat3),
+            mat_mult(&mat1, &mat_mult(&mat2, &mat3).unwrap())
         );
     }
 
-    #[should_panic]
     #[test]
     fn test_mat_mult_bad() {
         let mat1 = vec![vec![2, 3], vec![1, 4]];
 
         let mat2 = vec![vec![1], vec![2]];
 
-        mat_mult(&mat2, &mat1);
+        assert_eq!(
+            mat_mult(&mat2, &mat1),
+            Err(MatError::Mismatch((2, 1), (2, 2)))
+        );
+        assert_eq!(
+            mat_mult(&vec![vec![1, 2], vec![3]], &mat1),
+            Err(MatError::Ragged)
+        );
+    }
+
+    #[test]
+    fn test_mat_add_transpose() {
+        let mat1 = vec![vec![1, 2, 3], vec![4, 5, 6]];
+        let mat2 = vec![vec![1, 4], vec![2, 5], vec![3, 6]];
+
+        assert_eq!(transpose(&mat1), Ok(mat2.clone()));
+        assert_eq!(
+            mat_add(&mat1, &mat1),
+            Ok(vec![vec![2, 4, 6], vec![8, 10, 12]])
+        );
+        assert_eq!(
+            mat_add(&mat1, &mat2),
+            Err(MatError::Mismatch((2, 3), (3, 2)))
+        );
+        assert_eq!(
+            mat_add(&vec![vec![i32::MAX]], &vec![vec![1]]),
+            Err(MatError::Overflow)
+        );
+    }
+
+    #[test]
+    fn test_determinant() {
+        assert_eq!(determinant(&identity(4)), Ok(1));
+        assert_eq!(determinant(&vec![vec![2, 3], vec![1, 4]]), Ok(5));
+        assert_eq!(determinant(&vec![vec![0, 1], vec![1, 0]]), Ok(-1));
+        assert_eq!(
+            determinant(&vec![vec![1, 2, 3], vec![4, 5, 6], vec![7, 8, 9]]),
+            Ok(0)
+        );
+        assert_eq!(
+            determinant(&vec![vec![2, -3, 1], vec![2, 0, -1], vec![1, 4, 5]]),
+            Ok(49)
+        );
+        assert_eq!(determinant(&vec![]), Ok(1));
+        assert_eq!(
+            determinant(&vec![vec![1, 2]]),
+            Err(MatError::NotSquare((1, 2)))
+        );
+    }
+
+    #[test]
+    fn test_mat_pow() {
+        // Adjacency matrix of a 4-cycle: entry (i, j) of A^k counts walks of length k
+        let cycle = vec![
+            vec![0, 1, 0, 1],
+            vec![1, 0, 1, 0],
+            vec![0, 1, 0, 1],
+            vec![1, 0, 1, 0],
+        ];
+        let walks = mat_pow(&cycle, 4).unwrap();
+        assert_eq!(walks[0], vec![8, 0, 8, 0]);
+
+        let fib = vec![vec![1, 1], vec![1, 0]];
+        assert_eq!(mat_pow(&fib, 0), Ok(identity(2)));
+        assert_eq!(mat_pow(&fib, 10), Ok(vec![vec![89, 55], vec![55, 34]]));
+        assert_eq!(mat_pow(&fib, 50), Err(MatError::Overflow));
+    }
+
+    #[test]
+    fn test_checked() {
+        // The check runs once per row of each product, and its error stops the work
+        let mut rows = 0;
+        let mut count = || -> MatResult<()> {
+            rows += 1;
+            Ok(())
+        };
+
+        // A^4 takes three products: squaring twice, then multiplying into the result
+        mat_pow_checked(&identity(3), 4, &mut count).unwrap();
+        assert_eq!(rows, 3 * 3);
+
+        let mut stop = || Err(MatError::Ragged);
+        assert_eq!(
+            mat_mult_checked(&identity(2), &identity(2), &mut stop),
+            Err(MatError::Ragged)
+        );
+        assert_eq!(
+            determinant_checked(&identity(2), &mut stop),
+            Err(MatError::Ragged)
+        );
+    }
+
+    #[test]
+    fn test_pretty() {
+        assert_eq!(pretty(&vec![]), "[[]]");
+        assert_eq!(pretty(&vec![vec![1, 2, 3]]), "[[1 2 3]]");
+        assert_eq!(
+            pretty(&vec![vec![1, -2], vec![30, 4]]),
+            "[[ 1 -2]\n [30  4]]"
+        );
     }
 }
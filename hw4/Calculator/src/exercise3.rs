@@ -9,11 +9,294 @@
  * https://en.wikipedia.org/wiki/Sieve_of_Eratosthenes#Overview
  *
  * But you can choose any way you like.
+ *
+ * Our sieve is segmented: it only ever keeps the primes up to sqrt(n) plus one fixed-size window
+ * of candidates in memory, so it can walk through all the primes below 10^9 without allocating a
+ * gigabyte of flags. The rest of the file builds a small number-theory toolkit on top of it, which
+ * the calculator exposes as words.
  */
 
+/// Number of candidates examined per window of the segmented sieve.
+const SEGMENT_LEN: u64 = 1 << 16;
+
 /// Return the list of all prime numbers up to and including n.
-pub fn sieve(_n: u32) -> Vec<u32> {
-    todo!()
+pub fn sieve(n: u32) -> Vec<u32> {
+    primes(n).collect()
+}
+
+/// Count the prime numbers up to and including n, without storing them.
+pub fn prime_count(n: u32) -> usize {
+    primes(n).count()
+}
+
+/// Iterate over the prime numbers up to and including n in increasing order.
+pub fn primes(n: u32) -> Primes {
+    let limit = u64::from(n);
+    Primes {
+        limit,
+        base: small_sieve(isqrt(limit)),
+        low: 0,
+        segment: Vec::new(),
+        pos: 0,
+    }
+}
+
+/// Iterator state for the segmented sieve.
+///
+/// `segment[i]` records whether `low + i` is prime; `pos` is the next index to look at. Once the
+/// window is used up, we slide it forward and cross off multiples of the `base` primes again.
+pub struct Primes {
+    limit: u64,
+    base: Vec<u64>,
+    low: u64,
+    segment: Vec<bool>,
+    pos: usize,
+}
+
+impl Primes {
+    /// Move the window to start at `low` and sieve it.
+    fn fill(&mut self, low: u64) {
+        let high = std::cmp::min(low + SEGMENT_LEN - 1, self.limit);
+        self.low = low;
+        self.pos = 0;
+        self.segment.clear();
+        self.segment.resize((high - low + 1) as usize, true);
+
+        for n in low..std::cmp::min(2, high + 1) {
+            self.segment[(n - low) as usize] = false;
+        }
+
+        for &p in &self.base {
+            if p * p > high {
+                break;
+            }
+            let first = std::cmp::max(p * p, low.div_ceil(p) * p);
+            for multiple in (first..=high).step_by(p as usize) {
+                self.segment[(multiple - low) as usize] = false;
+            }
+        }
+    }
+}
+
+impl Iterator for Primes {
+    type Item = u32;
+
+    fn next(&mut self) -> Option<u32> {
+        loop {
+            while self.pos < self.segment.len() {
+                let idx = self.pos;
+                self.pos += 1;
+                if self.segment[idx] {
+                    return Some((self.low + idx as u64) as u32);
+                }
+            }
+
+            let next_low = self.low + self.segment.len() as u64;
+            if next_low > self.limit {
+                return None;
+            }
+            self.fill(next_low);
+        }
+    }
+}
+
+/// Integer square root: the largest r with r * r <= n.
+fn isqrt(n: u64) -> u64 {
+    let mut r = (n as f64).sqrt() as u64;
+    while r * r > n {
+        r -= 1;
+    }
+    while (r + 1) * (r + 1) <= n {
+        r += 1;
+    }
+    r
+}
+
+/// Plain sieve of Eratosthenes, used for the base primes of the segmented sieve.
+fn small_sieve(n: u64) -> Vec<u64> {
+    let n = n as usize;
+    let mut is_prime = vec![true; n + 1];
+    let mut result = Vec::new();
+    for i in 2..=n {
+        if is_prime[i] {
+            result.push(i as u64);
+            for multiple in (i * i..=n).step_by(i) {
+                is_prime[multiple] = false;
+            }
+        }
+    }
+    result
+}
+
+/// Greatest common divisor. By convention, gcd(0, 0) = 0.
+pub fn gcd(a: u64, b: u64) -> u64 {
+    let (mut a, mut b) = (a, b);
+    while b != 0 {
+        let r = a % b;
+        a = b;
+        b = r;
+    }
+    a
+}
+
+/// Least common multiple, or `None` if it does not fit in a u64.
+pub fn lcm(a: u64, b: u64) -> Option<u64> {
+    if a == 0 || b == 0 {
+        return Some(0);
+    }
+    (a / gcd(a, b)).checked_mul(b)
+}
+
+fn mul_mod(a: u64, b: u64, modulus: u64) -> u64 {
+    (u128::from(a) * u128::from(b) % u128::from(modulus)) as u64
+}
+
+/// Compute `base^exp mod modulus` by repeated squaring. The modulus must be positive.
+pub fn modpow(base: u64, exp: u64, modulus: u64) -> u64 {
+    let mut result = 1 % modulus;
+    let mut base = base % modulus;
+    let mut exp = exp;
+    while exp > 0 {
+        if exp % 2 == 1 {
+            result = mul_mod(result, base, modulus);
+        }
+        base = mul_mod(base, base, modulus);
+        exp /= 2;
+    }
+    result
+}
+
+/// Find the inverse of `a` modulo `modulus`, if `a` and `modulus` are coprime.
+pub fn modinv(a: u64, modulus: u64) -> Option<u64> {
+    if modulus == 0 {
+        return None;
+    }
+
+    // Extended Euclid, tracking only the coefficient of `a`
+    let (mut old_r, mut r) = (i128::from(a % modulus), i128::from(modulus));
+    let (mut old_s, mut s) = (1i128, 0i128);
+    while r != 0 {
+        let q = old_r / r;
+        let next_r = old_r - q * r;
+        old_r = r;
+        r = next_r;
+        let next_s = old_s - q * s;
+        old_s = s;
+        s = next_s;
+    }
+
+    if old_r == 1 || modulus == 1 {
+        Some(old_s.rem_euclid(i128::from(modulus)) as u64)
+    } else {
+        None
+    }
+}
+
+/// Small primes used for trial division before the heavier machinery kicks in.
+const SMALL_PRIMES: [u64; 12] = [2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37];
+
+/// Check if a number is prime.
+///
+/// This is Miller-Rabin with the first twelve primes as witnesses, which is known to give the
+/// right answer for every 64-bit input, so the test is deterministic.
+pub fn is_prime(n: u64) -> bool {
+    if n < 2 {
+        return false;
+    }
+    for &p in &SMALL_PRIMES {
+        if n.is_multiple_of(p) {
+            return n == p;
+        }
+    }
+
+    let mut d = n - 1;
+    let mut shift = 0;
+    while d.is_multiple_of(2) {
+        d /= 2;
+        shift += 1;
+    }
+
+    'witness: for &a in &SMALL_PRIMES {
+        let mut x = modpow(a, d, n);
+        if x == 1 || x == n - 1 {
+            continue;
+        }
+        for _ in 1..shift {
+            x = mul_mod(x, x, n);
+            if x == n - 1 {
+                continue 'witness;
+            }
+        }
+        return false;
+    }
+
+    true
+}
+
+/// Find some non-trivial divisor of an odd composite number with Pollard's rho method.
+fn pollard_rho(n: u64) -> u64 {
+    let step =
+        |x: u64, c: u64| ((u128::from(x) * u128::from(x) + u128::from(c)) % u128::from(n)) as u64;
+
+    // Each constant gives a different pseudo-random walk; retry if a walk fails to split n
+    for c in 1.. {
+        let (mut x, mut y, mut d) = (2, 2, 1);
+        while d == 1 {
+            x = step(x, c);
+            y = step(step(y, c), c);
+            d = gcd(x.abs_diff(y), n);
+        }
+        if d != n {
+            return d;
+        }
+    }
+
+    unreachable!()
+}
+
+/// Break a number into its prime factors, listed in increasing order with multiplicity.
+///
+/// The factorization of 0 and 1 is empty.
+pub fn factor(n: u64) -> Vec<u64> {
+    let mut result = Vec::new();
+    if n < 2 {
+        return result;
+    }
+
+    let mut n = n;
+    for &p in &SMALL_PRIMES {
+        while n.is_multiple_of(p) {
+            result.push(p);
+            n /= p;
+        }
+    }
+
+    let mut pending = vec![n];
+    while let Some(m) = pending.pop() {
+        if m == 1 {
+            continue;
+        } else if is_prime(m) {
+            result.push(m);
+        } else {
+            let d = pollard_rho(m);
+            pending.push(d);
+            pending.push(m / d);
+        }
+    }
+
+    result.sort_unstable();
+    result
+}
+
+/// Euler's totient: how many numbers in 1..=n are coprime to n. By convention, totient(0) = 0.
+pub fn totient(n: u64) -> u64 {
+    if n == 0 {
+        return 0;
+    }
+
+    let mut factors = factor(n);
+    factors.dedup();
+    factors.iter().fold(n, |acc, p| acc / p * (p - 1))
 }
 
 /// You can put more tests here.
@@ -27,4 +310,77 @@ mod tests {
 
         assert_eq!(sieve(12), primes_twelve);
     }
+
+    #[test]
+    fn test_sieve_edges() {
+        assert_eq!(sieve(0), []);
+        assert_eq!(sieve(1), []);
+        assert_eq!(sieve(2), [2]);
+
+        // Primes that straddle a segment boundary must show up exactly once
+        let boundary = SEGMENT_LEN as u32;
+        let around: Vec<u32> = sieve(boundary + 100)
+            .into_iter()
+            .filter(|&p| p > boundary - 100)
+            .collect();
+        let expected: Vec<u32> = (boundary - 99..=boundary + 100)
+            .filter(|&n| is_prime(u64::from(n)))
+            .collect();
+        assert_eq!(around, expected);
+    }
+
+    #[test]
+    fn test_prime_count() {
+        assert_eq!(prime_count(100), 25);
+        assert_eq!(prime_count(1_000_000), 78_498);
+        assert_eq!(prime_count(10_000_000), 664_579);
+    }
+
+    /// Slow in debug builds; run with `cargo test --release -- --ignored`.
+    #[test]
+    #[ignore]
+    fn test_prime_count_billion() {
+        assert_eq!(prime_count(1_000_000_000), 50_847_534);
+    }
+
+    #[test]
+    fn test_is_prime() {
+        let small: Vec<u64> = (0..100).filter(|&n| is_prime(n)).collect();
+        let sieved: Vec<u64> = sieve(100).into_iter().map(u64::from).collect();
+        assert_eq!(small, sieved);
+
+        assert!(!is_prime(561)); // Carmichael number
+        assert!(!is_prime(3_215_031_751)); // Strong pseudoprime to bases 2, 3, 5, 7
+        assert!(is_prime(2_305_843_009_213_693_951)); // 2^61 - 1
+        assert!(is_prime(18_446_744_073_709_551_557)); // Largest 64-bit prime
+        assert!(!is_prime(u64::MAX));
+    }
+
+    #[test]
+    fn test_factor() {
+        assert_eq!(factor(1), []);
+        assert_eq!(factor(360), [2, 2, 2, 3, 3, 5]);
+        assert_eq!(factor(600_851_475_143), [71, 839, 1471, 6857]);
+        assert_eq!(factor(u64::MAX), [3, 5, 17, 257, 641, 65537, 6_700_417]);
+        assert_eq!(
+            factor(1_000_000_007 * 998_244_353),
+            [998_244_353, 1_000_000_007]
+        );
+    }
+
+    #[test]
+    fn test_arithmetic() {
+        assert_eq!(gcd(12, 18), 6);
+        assert_eq!(gcd(0, 7), 7);
+        assert_eq!(lcm(4, 6), Some(12));
+        assert_eq!(lcm(u64::MAX, 2), None);
+        assert_eq!(modpow(2, 10, 1000), 24);
+        assert_eq!(modpow(5, 0, 1), 0);
+        assert_eq!(modpow(3, 1_000_000_006, 1_000_000_007), 1); // Fermat's little theorem
+        assert_eq!(modinv(3, 11), Some(4));
+        assert_eq!(modinv(4, 8), None);
+        assert_eq!(totient(1), 1);
+        assert_eq!(totient(36), 12);
+        assert_eq!(totient(97), 96);
+    }
 }
@@ -9,14 +9,34 @@
  *
  * https://en.wikipedia.org/wiki/Tower_of_Hanoi
  *
- * We first make a type of pegs: there are three possible pegs, called A, B, C
+ * We first make a type of pegs. The classic puzzle has three pegs, called A, B, C, but the
+ * generalized puzzle can have any number of them, so a peg is just a number with the first three
+ * given their traditional names.
  */
 
-#[derive(Clone, Copy, Debug, Eq, PartialEq)]
-pub enum Peg {
-    A,
-    B,
-    C,
+use std::fmt;
+use std::io::{self, Write};
+use std::thread;
+use std::time::Duration;
+
+#[derive(Clone, Copy, Eq, PartialEq)]
+pub struct Peg(pub u8);
+
+impl Peg {
+    pub const A: Peg = Peg(0);
+    pub const B: Peg = Peg(1);
+    pub const C: Peg = Peg(2);
+}
+
+// Pegs print as letters, continuing past C for the extra pegs
+impl fmt::Debug for Peg {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.0 < 26 {
+            write!(f, "{}", (b'A' + self.0) as char)
+        } else {
+            write!(f, "P{}", self.0)
+        }
+    }
 }
 
 /*
@@ -34,8 +54,321 @@ pub type Move = (Peg, Peg);
  * You may assume that `src`, `aux`, and `dst` are all different.
  * You may want to write some helper functions to avoid repeating lots of code.
  */
-pub fn hanoi(_num_discs: u32, _src: Peg, _aux: Peg, _dst: Peg) -> Vec<Move> {
-    todo!()
+pub fn hanoi(num_discs: u32, src: Peg, aux: Peg, dst: Peg) -> Vec<Move> {
+    let pegs = [src, aux, dst];
+    let mut stacks = [(1..=num_discs).rev().collect::<Vec<u32>>(), vec![], vec![]];
+    let mut moves = Vec::new();
+
+    // The smallest disc cycles through the pegs, forwards or backwards depending on parity
+    let cycle = if num_discs.is_multiple_of(2) {
+        [0, 1, 2]
+    } else {
+        [0, 2, 1]
+    };
+    let mut smallest_at = 0;
+
+    while stacks[2].len() < num_discs as usize {
+        // Odd steps: move the smallest disc one peg along its cycle
+        let from = cycle[smallest_at];
+        smallest_at = (smallest_at + 1) % 3;
+        let to = cycle[smallest_at];
+        move_disc(&mut stacks, from, to);
+        moves.push((pegs[from], pegs[to]));
+
+        if stacks[2].len() == num_discs as usize {
+            break;
+        }
+
+        // Even steps: the only legal move between the other two pegs
+        let (x, y) = match to {
+            0 => (1, 2),
+            1 => (0, 2),
+            _ => (0, 1),
+        };
+        let (from, to) = match (stacks[x].last(), stacks[y].last()) {
+            (Some(top_x), Some(top_y)) if top_x > top_y => (y, x),
+            (None, _) => (y, x),
+            _ => (x, y),
+        };
+        move_disc(&mut stacks, from, to);
+        moves.push((pegs[from], pegs[to]));
+    }
+
+    moves
+}
+
+fn move_disc(stacks: &mut [Vec<u32>; 3], from: usize, to: usize) {
+    if let Some(disc) = stacks[from].pop() {
+        stacks[to].push(disc);
+    }
+}
+
+/*
+ * With more than three pegs, the Frame-Stewart algorithm is the best known strategy: park the top
+ * `m` discs on a spare peg using every peg, move the remaining discs to the destination without
+ * touching the parking peg, then bring the parked discs over on top. We pick `m` by dynamic
+ * programming over the move counts.
+ *
+ * https://en.wikipedia.org/wiki/Tower_of_Hanoi#Frame%E2%80%93Stewart_algorithm
+ */
+
+/// Move counts and best split points for the Frame-Stewart recursion.
+///
+/// `cost[k][n]` is the number of moves needed for `n` discs with `k` pegs, and `split[k][n]` is
+/// how many discs to park on the first spare peg. Rows for fewer than three pegs are unused.
+struct Plan {
+    cost: Vec<Vec<u128>>,
+    split: Vec<Vec<u32>>,
+}
+
+impl Plan {
+    fn new(num_discs: u32, num_pegs: usize) -> Self {
+        let n = num_discs as usize;
+        let rows = std::cmp::max(num_pegs, 3) + 1;
+        let mut cost = vec![vec![0u128; n + 1]; rows];
+        let mut split = vec![vec![0u32; n + 1]; rows];
+
+        for discs in 1..=n {
+            cost[3][discs] = cost[3][discs - 1].saturating_mul(2).saturating_add(1);
+        }
+
+        for pegs in 4..=num_pegs {
+            for discs in 1..=n {
+                let mut best = (u128::MAX, 0);
+                for parked in 0..discs {
+                    let total = cost[pegs][parked]
+                        .saturating_mul(2)
+                        .saturating_add(cost[pegs - 1][discs - parked]);
+                    if total < best.0 {
+                        best = (total, parked);
+                    }
+                }
+                cost[pegs][discs] = best.0;
+                split[pegs][discs] = best.1 as u32;
+            }
+        }
+
+        Plan { cost, split }
+    }
+
+    fn solve(&self, num_discs: u32, src: Peg, dst: Peg, aux: &[Peg], moves: &mut Vec<Move>) {
+        match (num_discs, aux) {
+            (0, _) => (),
+            (1, _) => moves.push((src, dst)),
+            (_, [only]) => moves.extend(hanoi(num_discs, src, *only, dst)),
+            (_, [parking, rest @ ..]) => {
+                let parked = self.split[aux.len() + 2][num_discs as usize];
+
+                let mut spares = vec![dst];
+                spares.extend_from_slice(rest);
+                self.solve(parked, src, *parking, &spares, moves);
+
+                self.solve(num_discs - parked, src, dst, rest, moves);
+
+                spares[0] = src;
+                self.solve(parked, *parking, dst, &spares, moves);
+            }
+            (_, []) => unreachable!("checked by frame_stewart"),
+        }
+    }
+}
+
+/// The most moves `frame_stewart` will list.
+pub const MAX_MOVES: u128 = 1 << 16;
+
+/// Move `num_discs` discs from `src` to `dst`, using all of the `aux` pegs as spares.
+///
+/// Returns `None` if the puzzle can't be solved, which happens when there are at least two discs
+/// but no spare pegs at all, or if it takes more than `MAX_MOVES` moves.
+pub fn frame_stewart(num_discs: u32, src: Peg, dst: Peg, aux: &[Peg]) -> Option<Vec<Move>> {
+    if num_discs > 1 && aux.is_empty() {
+        return None;
+    }
+
+    // Every disc but the largest moves at least twice, so there is no need to plan a puzzle with
+    // more discs than this
+    if 2 * u128::from(num_discs) > MAX_MOVES + 1 {
+        return None;
+    }
+    let plan = Plan::new(num_discs, aux.len() + 2);
+    if plan.cost[aux.len() + 2][num_discs as usize] > MAX_MOVES {
+        return None;
+    }
+
+    let mut moves = Vec::new();
+    plan.solve(num_discs, src, dst, aux, &mut moves);
+    Some(moves)
+}
+
+/// Number of moves the Frame-Stewart algorithm makes with `num_pegs` pegs, saturating at
+/// `u128::MAX`.
+pub fn frame_stewart_count(num_discs: u32, num_pegs: usize) -> Option<u128> {
+    match (num_discs, num_pegs) {
+        (0, _) => Some(0),
+        (1, _) if num_pegs >= 2 => Some(1),
+        (_, pegs) if pegs < 3 => None,
+        _ => Some(Plan::new(num_discs, num_pegs).cost[num_pegs][num_discs as usize]),
+    }
+}
+
+/*
+ * To check a sequence of moves, we simulate it on a set of towers. Discs are numbered by size
+ * from 1 (the smallest) up to `num_discs`, and each tower lists its discs from the bottom up.
+ */
+
+/// State of the pegs partway through a puzzle.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Towers {
+    num_discs: u32,
+    pegs: Vec<Vec<u32>>,
+}
+
+/// Ways that a move can break the rules.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Violation {
+    NoSuchPeg(Peg),                           // Peg isn't part of the puzzle
+    SamePeg,                                  // Moving a disc onto its own peg
+    EmptySource,                              // No disc to move
+    LargerOnSmaller { disc: u32, onto: u32 }, // Disc would sit on a smaller disc
+}
+
+/// The first move in a sequence that breaks the rules, and its (zero-based) position.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct IllegalMove {
+    pub index: usize,
+    pub mv: Move,
+    pub violation: Violation,
+}
+
+impl Towers {
+    /// Set up `num_pegs` pegs with all `num_discs` discs stacked on `src`.
+    pub fn new(num_discs: u32, num_pegs: usize, src: Peg) -> Self {
+        let mut pegs = vec![Vec::new(); num_pegs];
+        if let Some(start) = pegs.get_mut(src.0 as usize) {
+            start.extend((1..=num_discs).rev());
+        }
+        Towers { num_discs, pegs }
+    }
+
+    /// Discs on a peg, from the bottom up.
+    pub fn discs(&self, peg: Peg) -> Option<&[u32]> {
+        self.pegs.get(peg.0 as usize).map(|discs| discs.as_slice())
+    }
+
+    /// Check if every disc has made it to `dst`.
+    pub fn solved(&self, dst: Peg) -> bool {
+        self.discs(dst)
+            .is_some_and(|discs| discs.len() == self.num_discs as usize)
+    }
+
+    /// Carry out one move, leaving the towers unchanged if it is illegal.
+    pub fn apply(&mut self, (from, to): Move) -> Result<(), Violation> {
+        for peg in [from, to].iter() {
+            if peg.0 as usize >= self.pegs.len() {
+                return Err(Violation::NoSuchPeg(*peg));
+            }
+        }
+        if from == to {
+            return Err(Violation::SamePeg);
+        }
+
+        let disc = *self.pegs[from.0 as usize]
+            .last()
+            .ok_or(Violation::EmptySource)?;
+        if let Some(&onto) = self.pegs[to.0 as usize].last() {
+            if onto < disc {
+                return Err(Violation::LargerOnSmaller { disc, onto });
+            }
+        }
+
+        self.pegs[from.0 as usize].pop();
+        self.pegs[to.0 as usize].push(disc);
+        Ok(())
+    }
+
+    /// Draw the towers as ASCII art, one row per disc height plus a labelled base.
+    pub fn render(&self) -> String {
+        let radius = self.num_discs as usize;
+        let mut out = String::new();
+
+        for level in (0..=radius).rev() {
+            for discs in &self.pegs {
+                let size = discs.get(level).map_or(0, |disc| *disc as usize);
+                let pad = " ".repeat(radius - size + 1);
+                let disc = "=".repeat(size);
+                out.push_str(&format!("{}{}|{}{}", pad, disc, disc, pad));
+            }
+            out.push('\n');
+        }
+
+        for idx in 0..self.pegs.len() {
+            let dashes = "-".repeat(radius + 1);
+            out.push_str(&format!("{}{:?}{}", dashes, Peg(idx as u8), dashes));
+        }
+        out.push('\n');
+
+        out
+    }
+}
+
+/// Play a sequence of moves from the starting position, stopping at the first illegal move.
+pub fn simulate(
+    num_discs: u32,
+    num_pegs: usize,
+    src: Peg,
+    moves: &[Move],
+) -> Result<Towers, IllegalMove> {
+    let mut towers = Towers::new(num_discs, num_pegs, src);
+    for (index, mv) in moves.iter().enumerate() {
+        towers.apply(*mv).map_err(|violation| IllegalMove {
+            index,
+            mv: *mv,
+            violation,
+        })?;
+    }
+    Ok(towers)
+}
+
+/// Animate a solution on the terminal, moving every disc from the first peg to the last.
+pub fn animate(num_discs: u32, num_pegs: usize, delay: Duration) -> io::Result<()> {
+    let pegs: Vec<Peg> = (0..num_pegs).map(|idx| Peg(idx as u8)).collect();
+    let (src, dst, aux) = match pegs.as_slice() {
+        [src, aux @ .., dst] => (*src, *dst, aux),
+        _ => return Err(io::Error::new(io::ErrorKind::InvalidInput, "need two pegs")),
+    };
+    let moves = frame_stewart(num_discs, src, dst, aux).ok_or_else(|| {
+        let msg = match aux {
+            [] => "not enough pegs to move the discs",
+            _ => "too many moves to show",
+        };
+        io::Error::new(io::ErrorKind::InvalidInput, msg)
+    })?;
+
+    let mut towers = Towers::new(num_discs, num_pegs, src);
+    let mut out = io::stdout();
+    let clear = "\x1B[2J\x1B[H";
+
+    write!(out, "{}Start\n{}", clear, towers.render())?;
+    for (idx, mv) in moves.iter().enumerate() {
+        out.flush()?;
+        thread::sleep(delay);
+
+        towers
+            .apply(*mv)
+            .map_err(|err| io::Error::other(format!("{:?}", err)))?;
+        write!(
+            out,
+            "{}Move {}/{}: {:?} -> {:?}\n{}",
+            clear,
+            idx + 1,
+            moves.len(),
+            mv.0,
+            mv.1,
+            towers.render()
+        )?;
+    }
+
+    out.flush()
 }
 
 /// You can put more tests here.
@@ -57,4 +390,95 @@ mod tests {
 
         assert_eq!(hanoi(3, Peg::A, Peg::B, Peg::C), moves);
     }
+
+    #[test]
+    fn test_hanoi_valid() {
+        for discs in 0..12 {
+            let moves = hanoi(discs, Peg::B, Peg::C, Peg::A);
+            assert_eq!(moves.len(), (1 << discs) - 1);
+
+            let towers = simulate(discs, 3, Peg::B, &moves).unwrap();
+            assert!(towers.solved(Peg::A));
+        }
+    }
+
+    #[test]
+    fn test_frame_stewart() {
+        // Known Frame-Stewart move counts for four pegs
+        let four_pegs = [0, 1, 3, 5, 9, 13, 17, 25, 33, 41, 49, 65];
+        let aux = [Peg::B, Peg::C];
+        for (discs, count) in four_pegs.iter().enumerate() {
+            let moves = frame_stewart(discs as u32, Peg::A, Peg(3), &aux).unwrap();
+            assert_eq!(moves.len(), *count);
+            assert_eq!(frame_stewart_count(discs as u32, 4), Some(*count as u128));
+
+            let towers = simulate(discs as u32, 4, Peg::A, &moves).unwrap();
+            assert!(towers.solved(Peg(3)));
+        }
+
+        let moves = frame_stewart(3, Peg::A, Peg::C, &[Peg::B]).unwrap();
+        assert_eq!(moves, hanoi(3, Peg::A, Peg::B, Peg::C));
+
+        let aux: Vec<Peg> = (1..6).map(Peg).collect();
+        let moves = frame_stewart(20, Peg::A, Peg(6), &aux).unwrap();
+        assert!(simulate(20, 7, Peg::A, &moves).unwrap().solved(Peg(6)));
+
+        assert_eq!(
+            frame_stewart(1, Peg::A, Peg::B, &[]),
+            Some(vec![(Peg::A, Peg::B)])
+        );
+        assert_eq!(frame_stewart(2, Peg::A, Peg::B, &[]), None);
+
+        // Puzzles that take too many moves are refused, without planning a huge one first
+        assert_eq!(
+            frame_stewart(16, Peg::A, Peg::C, &[Peg::B]).unwrap().len(),
+            65535
+        );
+        assert_eq!(frame_stewart(17, Peg::A, Peg::C, &[Peg::B]), None);
+        assert_eq!(frame_stewart(64, Peg::A, Peg::C, &[Peg::B]), None);
+        assert_eq!(frame_stewart(u32::MAX, Peg::A, Peg(6), &aux), None);
+    }
+
+    #[test]
+    fn test_simulate_illegal() {
+        let moves = [(Peg::A, Peg::B), (Peg::A, Peg::B)];
+        assert_eq!(
+            simulate(3, 3, Peg::A, &moves),
+            Err(IllegalMove {
+                index: 1,
+                mv: (Peg::A, Peg::B),
+                violation: Violation::LargerOnSmaller { disc: 2, onto: 1 },
+            })
+        );
+
+        let moves = [(Peg::A, Peg::C), (Peg::B, Peg::C)];
+        assert_eq!(
+            simulate(2, 3, Peg::A, &moves).unwrap_err().violation,
+            Violation::EmptySource
+        );
+        assert_eq!(
+            simulate(2, 3, Peg::A, &[(Peg::A, Peg(3))])
+                .unwrap_err()
+                .violation,
+            Violation::NoSuchPeg(Peg(3))
+        );
+        assert_eq!(
+            simulate(2, 3, Peg::A, &[(Peg::A, Peg::A)])
+                .unwrap_err()
+                .violation,
+            Violation::SamePeg
+        );
+    }
+
+    #[test]
+    fn test_render() {
+        let towers = simulate(2, 3, Peg::A, &[(Peg::A, Peg::C)]).unwrap();
+        let expected = concat!(
+            "   |      |      |   \n",
+            "   |      |      |   \n",
+            " ==|==    |     =|=  \n",
+            "---A------B------C---\n",
+        );
+        assert_eq!(towers.render(), expected);
+    }
 }
@@ -0,0 +1,387 @@
+/*
+ * Reverse Polish Notation: fuzz.rs
+ * See `rpn.md` for the overview.
+ *
+ * Randomized tests for the evaluator. Random programs must never panic, and arithmetic has to obey
+ * a few algebraic laws. A failing program is shrunk to a minimal list of tokens before it is
+ * reported; once the bug is fixed, the minimal program goes into `REGRESSIONS`.
+ *
+ * The runs are reproducible: set `RPN_FUZZ_SEED` to replay a reported seed, and `RPN_FUZZ_CASES`
+ * to try more (or fewer) programs.
+ */
+
+use std::env;
+use std::panic::{self, AssertUnwindSafe};
+
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
+
+use super::ops::Registry;
+use super::parser;
+use super::session::{Budget, Session};
+
+const DEFAULT_SEED: u64 = 0x5eed_2026;
+const DEFAULT_CASES: usize = 3000;
+
+// Operations left out of random programs: `today` is, because its answer changes
+const SKIPPED: &[&str] = &["today"];
+
+// Literals of every kind of item, with a bias towards edge cases. Unit names aren't operations, so
+// they are here too: after a number they give it a unit, and anywhere else they are unknowns
+#[rustfmt::skip]
+const LITERALS: &[&str] = &[
+    "0", "1", "-1", "2", "3", "7", "12", "-5", "2147483647", "-2147483648", "46341", "65536",
+    "true", "false", "2.5", "-0.5", "0.0", "1e300", "3+4i", "0i", "-1i",
+    "[[1 2] [3 4]]", "[[5]]", "[[1 2 3]]",
+    "2026-10-17", "0001-01-01", "9999-12-31", "12:30", "23:59:59", "P1M", "-P3D", "PT90M",
+    "x", "y", "m", "s", "kW",
+];
+
+// Minimal programs that once broke an invariant, with the stack (or error kind) they should give
+const REGRESSIONS: &[(&str, &str)] = &[
+    ("0 #", "Error: Domain"),
+    ("-3 #", "Error: Domain"),
+    ("1 #", "0"),
+    ("2147483647 1 +", "Error: Overflow"),
+    ("-2147483648 -1 +", "Error: Overflow"),
+    ("0 1 <->", "1 0"),
+    ("-2147483648 abs", "Error: Overflow"),
+    ("-2147483648 -1 /", "Error: Overflow"),
+    ("1e290 2147483647 * 2147483647 1e290 * =", "true"),
+    ("1e300 0i + ->z z z * ->w w w =", "true"),
+    ("1e300 1e300 *", "Error: Overflow"),
+    ("1e308 -1e308 -", "Error: Overflow"),
+    ("2.0 2 =", "true"),
+    ("2147483647 ident", "Error: Domain"),
+    ("2147483647 primes", "Error: Limit"),
+    ("2147483647 primepi", "Error: Limit"),
+];
+
+fn env_or<T: std::str::FromStr>(name: &str, default: T) -> T {
+    env::var(name)
+        .ok()
+        .and_then(|val| val.parse().ok())
+        .unwrap_or(default)
+}
+
+fn seed() -> u64 {
+    env_or("RPN_FUZZ_SEED", DEFAULT_SEED)
+}
+
+fn cases() -> usize {
+    env_or("RPN_FUZZ_CASES", DEFAULT_CASES)
+}
+
+// A session with tight limits, so that random loops and recursion end quickly. There's no time
+// limit, which would make the outcome depend on how fast the machine is
+fn session() -> Session {
+    let mut session = Session::new();
+    session.set_budget(Budget {
+        max_steps: Some(10_000),
+        max_stack: Some(1000),
+        max_calls: 64,
+        max_item: 10_000,
+        max_depth: 64,
+        timeout: None,
+    });
+    session
+}
+
+/// What became of a program: `None` if it panicked, and otherwise the stack it left or the kind of
+/// error it failed with, written out.
+fn outcome(tokens: &[String]) -> Option<String> {
+    let line = tokens.join(" ");
+    let res = panic::catch_unwind(AssertUnwindSafe(|| {
+        let mut session = session();
+        match parser::evaluate_line(&mut session, &line) {
+            Ok(()) => session.stack().to_string(),
+            Err(err) => format!("Error: {}", err.kind()),
+        }
+    }));
+    res.ok()
+}
+
+fn tokens(line: &str) -> Vec<String> {
+    parser::tokenize(line).unwrap()
+}
+
+/// Shrink a failing program: keep removing runs of tokens, long ones first, as long as what's left
+/// still fails.
+fn shrink(mut tokens: Vec<String>, fails: impl Fn(&[String]) -> bool) -> Vec<String> {
+    let mut len = tokens.len() / 2;
+    while len > 0 {
+        let mut start = 0;
+        let mut shrunk = false;
+        while start + len <= tokens.len() {
+            let mut candidate = tokens.clone();
+            candidate.drain(start..start + len);
+            if fails(&candidate) {
+                tokens = candidate;
+                shrunk = true;
+            } else {
+                start += 1;
+            }
+        }
+        if !shrunk {
+            len /= 2;
+        }
+    }
+    tokens
+}
+
+/// Generates random programs from the names of the operations and a few literals of each kind.
+struct Generator {
+    rng: StdRng,
+    ops: Vec<String>,
+}
+
+impl Generator {
+    fn new(seed: u64) -> Self {
+        let ops = Registry::builtins()
+            .iter()
+            .map(|op| op.name.to_string())
+            .filter(|name| !SKIPPED.contains(&name.as_str()))
+            .collect();
+        Generator {
+            rng: StdRng::seed_from_u64(seed),
+            ops,
+        }
+    }
+
+    fn literal(&mut self) -> String {
+        match self.rng.gen_range(0, 3) {
+            0 => self.rng.gen_range(-20, 20).to_string(),
+            _ => LITERALS.choose(&mut self.rng).unwrap().to_string(),
+        }
+    }
+
+    // Push the tokens of a random program of about `len` tokens
+    fn program(&mut self, len: usize, depth: usize, out: &mut Vec<String>) {
+        for _ in 0..len {
+            match self.rng.gen_range(0, 21) {
+                0..=7 => out.push(self.literal()),
+                8..=15 => out.push(self.ops.choose(&mut self.rng).unwrap().clone()),
+                16 if depth < 2 => {
+                    out.push(String::from("["));
+                    let len = self.rng.gen_range(0, 5);
+                    self.program(len, depth + 1, out);
+                    out.push(String::from("]"));
+                }
+                17 => out.push(String::from(
+                    ["->a", "a", "->b", "b"][self.rng.gen_range(0, 4)],
+                )),
+                18 if depth == 0 => {
+                    out.extend([":", "w"].iter().map(|tok| tok.to_string()));
+                    let len = self.rng.gen_range(0, 4);
+                    self.program(len, depth + 1, out);
+                    out.push(String::from(";"));
+                    out.push(String::from("w"));
+                }
+                // Loops nested deep inside each other, to run into the limits
+                19 if depth == 0 => {
+                    let levels = self.rng.gen_range(2, 9);
+                    for _ in 0..levels {
+                        out.push(String::from("["));
+                        let len = self.rng.gen_range(0, 3);
+                        self.program(len, 2, out);
+                    }
+                    for _ in 0..levels {
+                        out.push(String::from("]"));
+                        out.push(self.rng.gen_range(0, 1000).to_string());
+                        out.push(String::from("times"));
+                    }
+                }
+                _ => out.push(String::from(
+                    ["<->", "call", ">s", "main"][self.rng.gen_range(0, 4)],
+                )),
+            }
+        }
+    }
+
+    fn tokens(&mut self) -> Vec<String> {
+        let mut out = Vec::new();
+        let len = self.rng.gen_range(1, 12);
+        self.program(len, 0, &mut out);
+        out
+    }
+}
+
+#[test]
+fn test_regressions() {
+    for (line, expected) in REGRESSIONS {
+        assert_eq!(
+            outcome(&tokens(line)).as_deref(),
+            Some(*expected),
+            "{}",
+            line
+        );
+    }
+}
+
+#[test]
+fn test_no_panics() {
+    let seed = seed();
+    let mut gen = Generator::new(seed);
+    for _ in 0..cases() {
+        let tokens = gen.tokens();
+        if outcome(&tokens).is_none() {
+            let minimal = shrink(tokens.clone(), |tokens| outcome(tokens).is_none());
+            panic!(
+                "`{}` panics (seed {}); minimal program: `{}`",
+                tokens.join(" "),
+                seed,
+                minimal.join(" ")
+            );
+        }
+    }
+}
+
+// Check that `a b op` and `b a op` agree, for operands written as tokens: either both fail the
+// same way, or both work and the results are equal by `=`
+fn commutes(a: &str, b: &str, op: &str) -> bool {
+    let lhs = outcome(&tokens(&format!("{} {} {}", a, b, op)));
+    let rhs = outcome(&tokens(&format!("{} {} {}", b, a, op)));
+    match (&lhs, &rhs) {
+        (Some(lhs), Some(rhs)) if lhs.starts_with("Error") || rhs.starts_with("Error") => {
+            lhs == rhs
+        }
+        (Some(_), Some(_)) => {
+            let same = format!("{} {} {} {} {} {} =", a, b, op, b, a, op);
+            outcome(&tokens(&same)).as_deref() == Some("true")
+        }
+        _ => false,
+    }
+}
+
+#[test]
+fn test_commutative() {
+    // Operands that can be added or multiplied in either order; matrices only commute under `+`
+    const SCALARS: &[&str] = &[
+        "0",
+        "1",
+        "-7",
+        "2147483647",
+        "-2147483648",
+        "65536",
+        "2.5",
+        "-0.5",
+        "1e300",
+        "3+4i",
+        "-1i",
+        "x",
+        "y",
+        "2 m",
+        "3 km",
+        "1 s",
+        "P3D",
+        "PT90M",
+        "2026-10-17",
+        "12:30",
+        "true",
+    ];
+    const MATRICES: &[&str] = &["[[1 2] [3 4]]", "[[-1 0] [2147483647 1]]", "[[5]]"];
+
+    let seed = seed();
+    let mut rng = StdRng::seed_from_u64(seed);
+    for _ in 0..cases() / 10 {
+        let (a, b) = (
+            SCALARS.choose(&mut rng).unwrap(),
+            SCALARS.choose(&mut rng).unwrap(),
+        );
+        for op in &["+", "*"] {
+            assert!(commutes(a, b, op), "`{} {} {}` (seed {})", a, b, op, seed);
+        }
+        let (a, b) = (
+            MATRICES.choose(&mut rng).unwrap(),
+            MATRICES.choose(&mut rng).unwrap(),
+        );
+        assert!(commutes(a, b, "+"), "`{} {} +` (seed {})", a, b, seed);
+    }
+}
+
+// Check that `a b =` and `b a =` give the same answer. Unlike `commutes`, this compares operands
+// of different kinds, which `=` has to convert the same way whichever comes first. The operands go
+// through variables, so that a unit name like `m` stays an unknown instead of becoming the unit of
+// a number before it
+fn compares_both_ways(a: &str, b: &str) -> bool {
+    let compare = |a, b| outcome(&tokens(&format!("{} ->p {} ->q p q =", a, b)));
+    let forward = compare(a, b);
+    forward.is_some() && forward == compare(b, a)
+}
+
+#[test]
+fn test_eq_symmetric() {
+    // Every literal against every other, and against a few items only operations make
+    const MADE: &[&str] = &["2 m", "3 km", "1 s", "x y +", "[ 1 ]", "[ true + ] [ ] try"];
+    let items: Vec<&str> = LITERALS.iter().chain(MADE).copied().collect();
+    for (idx, a) in items.iter().enumerate() {
+        for b in &items[idx..] {
+            assert!(
+                compares_both_ways(a, b),
+                "`{} {} =` and `{} {} =` differ",
+                a,
+                b,
+                b,
+                a
+            );
+        }
+    }
+}
+
+// Whether swapping the top two items of what a program leaves behaves wrongly: swapping twice has
+// to change nothing, and swapping once has to do the same as popping both into variables and
+// pushing them back the other way round
+fn swaps_wrong(tokens: &[String]) -> bool {
+    // Random numbers would make the runs differ
+    if tokens.iter().any(|tok| tok == "#") {
+        return false;
+    }
+    let base = match outcome(tokens) {
+        Some(stack) if !stack.starts_with("Error") => stack,
+        _ => return false,
+    };
+    let with = |suffix: &str| {
+        let mut tokens = tokens.to_vec();
+        tokens.extend(suffix.split_whitespace().map(String::from));
+        outcome(&tokens)
+    };
+
+    let once = with("<->");
+    let twice = with("<-> <->");
+    let by_hand = with("->p ->q p q");
+    let failed = once
+        .as_deref()
+        .is_some_and(|stack| stack.starts_with("Error"));
+    once != by_hand || !failed && twice != Some(base)
+}
+
+#[test]
+fn test_swap() {
+    let seed = seed();
+    let mut gen = Generator::new(seed);
+    for _ in 0..cases() / 3 {
+        let tokens = gen.tokens();
+        if swaps_wrong(&tokens) {
+            let minimal = shrink(tokens.clone(), swaps_wrong);
+            panic!(
+                "swapping after `{}` misbehaves (seed {}); minimal program: `{}`",
+                tokens.join(" "),
+                seed,
+                minimal.join(" ")
+            );
+        }
+    }
+}
+
+#[test]
+fn test_shrink() {
+    // The smallest program containing both a 3 and a 5, in that order
+    let fails = |tokens: &[String]| {
+        let three = tokens.iter().position(|tok| tok == "3");
+        let five = tokens.iter().rposition(|tok| tok == "5");
+        matches!((three, five), (Some(i), Some(j)) if i < j)
+    };
+    assert_eq!(shrink(tokens("1 3 2 4 5 6 5 7"), fails), ["3", "5"]);
+    assert_eq!(shrink(tokens("3 5"), fails), ["3", "5"]);
+}
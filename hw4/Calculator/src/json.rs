@@ -0,0 +1,376 @@
+/*
+ * Reverse Polish Notation: json.rs
+ * See `rpn.md` for the overview.
+ */
+
+use std::fmt;
+
+use super::rpn;
+
+/// Just enough of JSON to report results to other programs, and to read back what we wrote.
+/// Numbers are always integers.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Int(i64),
+    Str(String),
+    Array(Vec<Value>),
+    Object(Vec<(String, Value)>),
+}
+
+impl Value {
+    /// Build an object from its fields, in order.
+    pub fn object(fields: Vec<(&str, Value)>) -> Self {
+        Value::Object(
+            fields
+                .into_iter()
+                .map(|(key, val)| (key.to_string(), val))
+                .collect(),
+        )
+    }
+
+    /// Read a JSON document. Numbers with a fraction or an exponent aren't supported, and arrays
+    /// and objects can nest at most `MAX_DEPTH` deep.
+    pub fn parse(text: &str) -> Result<Value, ParseError> {
+        let mut parser = Parser {
+            text,
+            pos: 0,
+            depth: 0,
+        };
+        let val = parser.value()?;
+        parser.skip_space();
+        match parser.pos {
+            pos if pos == text.len() => Ok(val),
+            pos => Err(ParseError(pos)),
+        }
+    }
+
+    /// Look up a field of an object.
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        match self {
+            Value::Object(fields) => fields.iter().find(|(name, _)| name == key).map(|f| &f.1),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::Str(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[Value]> {
+        match self {
+            Value::Array(vals) => Some(vals),
+            _ => None,
+        }
+    }
+}
+
+/// How deeply arrays and objects may nest. The reader recurses into them, and a document is read
+/// from untrusted files and clients, so it can't be allowed to run off the end of the stack.
+pub const MAX_DEPTH: usize = 256;
+
+/// Where a JSON document stopped making sense, as a byte offset.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ParseError(pub usize);
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid JSON at byte {}", self.0)
+    }
+}
+
+// A recursive-descent reader over the text of a document
+struct Parser<'a> {
+    text: &'a str,
+    pos: usize,
+    depth: usize, // Arrays and objects we are inside of
+}
+
+impl Parser<'_> {
+    fn rest(&self) -> &str {
+        &self.text[self.pos..]
+    }
+
+    fn error<T>(&self) -> Result<T, ParseError> {
+        Err(ParseError(self.pos))
+    }
+
+    fn skip_space(&mut self) {
+        let rest = self.rest();
+        self.pos += rest.len() - rest.trim_start_matches([' ', '\t', '\n', '\r']).len();
+    }
+
+    // Consume `token` if the text continues with it
+    fn eat(&mut self, token: &str) -> bool {
+        self.skip_space();
+        if self.rest().starts_with(token) {
+            self.pos += token.len();
+            return true;
+        }
+        false
+    }
+
+    fn value(&mut self) -> Result<Value, ParseError> {
+        self.skip_space();
+        let rest = self.rest();
+        match rest.chars().next() {
+            Some('{') | Some('[') if self.depth >= MAX_DEPTH => self.error(),
+            Some('{') => self.nested(Parser::object),
+            Some('[') => self.nested(Parser::array),
+            Some('"') => self.string().map(Value::Str),
+            Some('-') | Some('0'..='9') => {
+                let len = 1 + rest[1..]
+                    .find(|c: char| !c.is_ascii_digit())
+                    .unwrap_or(rest.len() - 1);
+                match rest[..len].parse() {
+                    Ok(val) => {
+                        self.pos += len;
+                        Ok(Value::Int(val))
+                    }
+                    Err(_) => self.error(),
+                }
+            }
+            _ if self.eat("null") => Ok(Value::Null),
+            _ if self.eat("true") => Ok(Value::Bool(true)),
+            _ if self.eat("false") => Ok(Value::Bool(false)),
+            _ => self.error(),
+        }
+    }
+
+    // Read an array or object, one level deeper
+    fn nested(
+        &mut self,
+        read: fn(&mut Self) -> Result<Value, ParseError>,
+    ) -> Result<Value, ParseError> {
+        self.depth += 1;
+        let val = read(self);
+        self.depth -= 1;
+        val
+    }
+
+    fn array(&mut self) -> Result<Value, ParseError> {
+        self.eat("[");
+        let mut vals = Vec::new();
+        if self.eat("]") {
+            return Ok(Value::Array(vals));
+        }
+        loop {
+            vals.push(self.value()?);
+            if self.eat("]") {
+                return Ok(Value::Array(vals));
+            }
+            if !self.eat(",") {
+                return self.error();
+            }
+        }
+    }
+
+    fn object(&mut self) -> Result<Value, ParseError> {
+        self.eat("{");
+        let mut fields = Vec::new();
+        if self.eat("}") {
+            return Ok(Value::Object(fields));
+        }
+        loop {
+            self.skip_space();
+            let key = self.string()?;
+            if !self.eat(":") {
+                return self.error();
+            }
+            fields.push((key, self.value()?));
+            if self.eat("}") {
+                return Ok(Value::Object(fields));
+            }
+            if !self.eat(",") {
+                return self.error();
+            }
+        }
+    }
+
+    // A string literal, undoing the escapes `write_str` makes (and the few others JSON has)
+    fn string(&mut self) -> Result<String, ParseError> {
+        if !self.rest().starts_with('"') {
+            return self.error();
+        }
+        self.pos += 1;
+
+        let mut s = String::new();
+        let mut chars = self.rest().char_indices();
+        while let Some((idx, c)) = chars.next() {
+            let c = match c {
+                '"' => {
+                    self.pos += idx + 1;
+                    return Ok(s);
+                }
+                '\\' => match chars.next().map(|(_, c)| c) {
+                    Some('"') => '"',
+                    Some('\\') => '\\',
+                    Some('/') => '/',
+                    Some('n') => '\n',
+                    Some('r') => '\r',
+                    Some('t') => '\t',
+                    Some('b') => '\u{8}',
+                    Some('f') => '\u{c}',
+                    Some('u') => {
+                        let hex: String = chars.by_ref().take(4).map(|(_, c)| c).collect();
+                        let code = u32::from_str_radix(&hex, 16).ok();
+                        match code.and_then(char::from_u32) {
+                            Some(c) if hex.len() == 4 => c,
+                            _ => return Err(ParseError(self.pos + idx)),
+                        }
+                    }
+                    _ => return Err(ParseError(self.pos + idx)),
+                },
+                c if c.is_control() => return Err(ParseError(self.pos + idx)),
+                c => c,
+            };
+            s.push(c);
+        }
+        Err(ParseError(self.text.len()))
+    }
+}
+
+impl From<&rpn::Item> for Value {
+    /// Integers and booleans map to JSON numbers and booleans; everything else is written out the
+    /// way the calculator displays it.
+    fn from(item: &rpn::Item) -> Self {
+        match item {
+            rpn::Item::Int(val) => Value::Int(i64::from(*val)),
+            rpn::Item::Bool(val) => Value::Bool(*val),
+            other => Value::Str(other.to_string()),
+        }
+    }
+}
+
+impl From<&rpn::Error> for Value {
+    fn from(err: &rpn::Error) -> Self {
+        Value::object(vec![
+            ("kind", Value::Str(err.kind().to_string())),
+            ("message", Value::Str(err.to_string())),
+        ])
+    }
+}
+
+// Write a string literal, escaping quotes, backslashes and control characters
+fn write_str(f: &mut fmt::Formatter, s: &str) -> fmt::Result {
+    write!(f, "\"")?;
+    for c in s.chars() {
+        match c {
+            '"' => write!(f, "\\\"")?,
+            '\\' => write!(f, "\\\\")?,
+            '\n' => write!(f, "\\n")?,
+            '\r' => write!(f, "\\r")?,
+            '\t' => write!(f, "\\t")?,
+            c if c.is_control() => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{}", c)?,
+        }
+    }
+    write!(f, "\"")
+}
+
+impl fmt::Display for Value {
+    /// Compact JSON on a single line.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::Null => write!(f, "null"),
+            Value::Bool(val) => write!(f, "{}", val),
+            Value::Int(val) => write!(f, "{}", val),
+            Value::Str(s) => write_str(f, s),
+            Value::Array(vals) => {
+                write!(f, "[")?;
+                for (idx, val) in vals.iter().enumerate() {
+                    if idx > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}", val)?;
+                }
+                write!(f, "]")
+            }
+            Value::Object(fields) => {
+                write!(f, "{{")?;
+                for (idx, (key, val)) in fields.iter().enumerate() {
+                    if idx > 0 {
+                        write!(f, ",")?;
+                    }
+                    write_str(f, key)?;
+                    write!(f, ":{}", val)?;
+                }
+                write!(f, "}}")
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_display() {
+        let val = Value::object(vec![
+            ("ok", Value::Bool(true)),
+            (
+                "stack",
+                Value::Array(vec![
+                    Value::from(&rpn::Item::Int(-3)),
+                    Value::from(&rpn::Item::Quote(vec![String::from("1")])),
+                ]),
+            ),
+            ("note", Value::Str(String::from("say \"hi\"\n\\"))),
+            ("none", Value::Null),
+        ]);
+        assert_eq!(
+            val.to_string(),
+            r#"{"ok":true,"stack":[-3,"[ 1 ]"],"note":"say \"hi\"\n\\","none":null}"#
+        );
+        assert_eq!(Value::Str(String::from("\u{1}")).to_string(), r#""\u0001""#);
+    }
+
+    #[test]
+    fn test_parse() {
+        let text = r#" {"ok" : true, "stack":[-3, "[ 1 ]", [], {}],
+                        "note":"say \"hi\"\n\\ \u00e9\/", "none":null} "#;
+        let val = Value::parse(text).unwrap();
+        assert_eq!(
+            val.to_string(),
+            r#"{"ok":true,"stack":[-3,"[ 1 ]",[],{}],"note":"say \"hi\"\n\\ é/","none":null}"#
+        );
+        assert_eq!(
+            val.get("note").and_then(Value::as_str),
+            Some("say \"hi\"\n\\ é/")
+        );
+        assert_eq!(
+            val.get("stack").and_then(Value::as_array).map(<[_]>::len),
+            Some(4)
+        );
+        assert_eq!(val.get("nothing"), None);
+
+        // Whatever we write, we can read back
+        assert_eq!(Value::parse(&val.to_string()), Ok(val));
+
+        assert_eq!(Value::parse("[1, 2"), Err(ParseError(5)));
+        assert_eq!(Value::parse("[1 2]"), Err(ParseError(3)));
+        assert_eq!(Value::parse("1.5"), Err(ParseError(1)));
+        assert_eq!(Value::parse("\"\\x\""), Err(ParseError(1)));
+        assert_eq!(Value::parse("{\"a\" 1}"), Err(ParseError(5)));
+        assert_eq!(Value::parse("tru"), Err(ParseError(0)));
+        assert!(Value::parse("99999999999999999999").is_err());
+        assert!(Value::parse("\"\\ud800\"").is_err());
+
+        // Nesting stops at the limit, however deep the document goes
+        let nested = |depth| "[".repeat(depth) + &"]".repeat(depth);
+        assert!(Value::parse(&nested(MAX_DEPTH)).is_ok());
+        assert_eq!(
+            Value::parse(&nested(MAX_DEPTH + 1)),
+            Err(ParseError(MAX_DEPTH))
+        );
+        assert_eq!(
+            Value::parse(&"[{\"a\":".repeat(200_000)),
+            Err(ParseError(6 * (MAX_DEPTH / 2)))
+        );
+    }
+}
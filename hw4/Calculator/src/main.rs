@@ -1,16 +1,104 @@
 #![allow(dead_code)]
 #![forbid(unsafe_code)]
 
+use std::env;
+use std::fs;
+use std::io;
+use std::process;
+use std::time::Duration;
+
+mod complex;
+mod dates;
 mod exercise1;
 mod exercise2;
 mod exercise3;
 mod exercise4;
+#[cfg(test)]
+mod fuzz;
 
+mod json;
+mod ops;
 mod parser;
+mod repl;
 mod rpn;
+mod server;
+mod session;
+mod symbolic;
+mod threadpool;
+mod transcript;
+mod units;
+
+const HANOI_FRAME_DELAY: Duration = Duration::from_millis(300);
+const MAX_HANOI_DISCS: u32 = 20;
 
 fn main() {
-    if let Err(err) = parser::rpn_repl() {
-        println!("Error: {:?}", err);
+    let mut args = env::args().skip(1);
+
+    match args.next().as_deref() {
+        Some("--hanoi") => {
+            let discs = args.next().and_then(|n| n.parse::<u32>().ok());
+            let pegs = args.next().map_or(Some(3), |n| n.parse::<usize>().ok());
+            match (discs, pegs) {
+                (Some(discs), Some(pegs))
+                    if discs <= MAX_HANOI_DISCS && (2..=26).contains(&pegs) =>
+                {
+                    if let Err(err) = exercise4::animate(discs, pegs, HANOI_FRAME_DELAY) {
+                        println!("Error: {:?}", err);
+                    }
+                }
+                _ => println!(
+                    "Usage: hw4 --hanoi DISCS [PEGS]  (DISCS up to {}, PEGS between 2 and 26)",
+                    MAX_HANOI_DISCS
+                ),
+            }
+        }
+        Some("--serve") => match args.next() {
+            Some(addr) => {
+                if let Err(err) = serve(&addr) {
+                    println!("Error: {:?}", err);
+                }
+            }
+            None => println!("Usage: hw4 --serve ADDR  (HOST:PORT or unix:PATH)"),
+        },
+        Some("--replay") => match args.next() {
+            Some(path) => {
+                if !replay(&path) {
+                    process::exit(1);
+                }
+            }
+            None => println!("Usage: hw4 --replay FILE  (a transcript saved with :export)"),
+        },
+        _ => {
+            if let Err(err) = repl::rpn_repl() {
+                println!("Error: {:?}", err);
+            }
+        }
+    }
+}
+
+fn serve(addr: &str) -> io::Result<()> {
+    let mut server = server::Server::bind(addr, server::Config::default())?;
+    println!("Listening on {}", server.local_addr()?);
+    server.run()
+}
+
+// Replay a JSON transcript, reporting whether every line gave the same result as before
+fn replay(path: &str) -> bool {
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(err) => {
+            println!("Error: could not read {}: {}", path, err);
+            return false;
+        }
+    };
+    match transcript::replay(&text) {
+        Ok(lines) => {
+            println!("Replayed {} lines; every result matches", lines);
+            true
+        }
+        Err(err) => {
+            println!("Replay failed: {}", err);
+            false
+        }
     }
 }
@@ -0,0 +1,579 @@
+/*
+ * Reverse Polish Notation: ops.rs
+ * See `rpn.md` for the overview.
+ *
+ * Operations are looked up by name in a Registry. Each entry says how many items the operation
+ * takes off the stack, what types it works on, a line of help text, and the code to run. The
+ * built-in operations are registered like any other, so applications embedding the calculator can
+ * add their own operations (or replace ours) at runtime.
+ */
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::Arc;
+
+use super::exercise3;
+use super::rpn::{self, Limit};
+use super::session::Session;
+use super::units;
+
+/// Code for an operation. It gets the whole session, though most operations only need the stack.
+pub type OpFn = Arc<dyn Fn(&mut Session) -> rpn::Result<()> + Send + Sync>;
+
+/// A registered operation.
+#[derive(Clone)]
+pub struct OpDef {
+    pub name: String,
+    pub arity: usize,      // Number of items the operation needs on the stack
+    pub signature: String, // Types consumed and produced, like `int int -> int`
+    pub help: String,
+    pub run: OpFn,
+}
+
+impl OpDef {
+    pub fn new<F>(name: &str, arity: usize, signature: &str, help: &str, run: F) -> Self
+    where
+        F: Fn(&mut Session) -> rpn::Result<()> + Send + Sync + 'static,
+    {
+        OpDef {
+            name: name.to_string(),
+            arity,
+            signature: signature.to_string(),
+            help: help.to_string(),
+            run: Arc::new(run),
+        }
+    }
+
+    /// Make an operation that only touches the stack.
+    pub fn on_stack(
+        name: &str,
+        arity: usize,
+        signature: &str,
+        help: &str,
+        run: fn(&mut rpn::Stack) -> rpn::Result<()>,
+    ) -> Self {
+        OpDef::new(name, arity, signature, help, move |session| {
+            run(session.stack_mut())
+        })
+    }
+
+    /// Make an operation on the stack that calls back to check the session's budget while it
+    /// works. Each check counts as a step.
+    pub fn metered(
+        name: &str,
+        arity: usize,
+        signature: &str,
+        help: &str,
+        run: fn(&mut rpn::Stack, &mut dyn FnMut() -> rpn::Result<()>) -> rpn::Result<()>,
+    ) -> Self {
+        OpDef::new(name, arity, signature, help, move |session| {
+            // Take the stack out of the session, so that the checks can borrow the session
+            let mut stack = std::mem::replace(session.stack_mut(), rpn::Stack::new());
+            let res = run(&mut stack, &mut || session.step());
+            *session.stack_mut() = stack;
+            res
+        })
+    }
+
+    /// Help text for `help <op>`.
+    pub fn describe(&self) -> String {
+        format!(
+            "{} (arity {}): {}\n    {}",
+            self.name, self.arity, self.signature, self.help
+        )
+    }
+}
+
+impl fmt::Debug for OpDef {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("OpDef")
+            .field("name", &self.name)
+            .field("arity", &self.arity)
+            .field("signature", &self.signature)
+            .finish()
+    }
+}
+
+/// Operations known to a calculator session, keyed by name.
+#[derive(Clone, Debug, Default)]
+pub struct Registry {
+    ops: BTreeMap<String, OpDef>,
+}
+
+impl Registry {
+    /// Make an empty registry.
+    pub fn new() -> Self {
+        Registry {
+            ops: BTreeMap::new(),
+        }
+    }
+
+    /// Make a registry with all of the built-in operations.
+    pub fn builtins() -> Self {
+        let mut registry = Registry::new();
+        for (name, arity, signature, help, run) in BUILTINS {
+            registry.register(OpDef::on_stack(name, *arity, signature, help, *run));
+        }
+        for (name, arity, signature, help, run) in METERED {
+            registry.register(OpDef::metered(name, *arity, signature, help, *run));
+        }
+        for (name, arity, signature, help, run) in CONTROL {
+            registry.register(OpDef::new(name, *arity, signature, help, *run));
+        }
+        registry
+    }
+
+    /// Add an operation, returning the operation it replaces if the name was already taken.
+    pub fn register(&mut self, op: OpDef) -> Option<OpDef> {
+        self.ops.insert(op.name.clone(), op)
+    }
+
+    /// Remove an operation by name.
+    pub fn unregister(&mut self, name: &str) -> Option<OpDef> {
+        self.ops.remove(name)
+    }
+
+    /// Look up an operation by name.
+    pub fn get(&self, name: &str) -> Option<&OpDef> {
+        self.ops.get(name)
+    }
+
+    /// Iterate over the operations in order of name.
+    pub fn iter(&self) -> impl Iterator<Item = &OpDef> {
+        self.ops.values()
+    }
+
+    /// Help text: a list of all operations, or the description of one of them.
+    pub fn help(&self, name: Option<&str>) -> String {
+        match name {
+            Some(name) => match (self.get(name), units::lookup(name)) {
+                (Some(op), _) => op.describe(),
+                (None, Some(def)) => format!(
+                    "{} is a unit ({}): write it right after a number, like `5 {}`.",
+                    def.name, def.help, def.name
+                ),
+                (None, None) => format!("No operation named `{}`", name),
+            },
+            None => {
+                let names: Vec<&str> = self.ops.keys().map(String::as_str).collect();
+                let units: Vec<&str> = units::UNITS.iter().map(|def| def.name).collect();
+                format!(
+                    "Operations: {}\nUnits: {}\nType `help <op>` for details.",
+                    names.join(" "),
+                    units.join(" ")
+                )
+            }
+        }
+    }
+}
+
+type Builtin = (
+    &'static str,
+    usize,
+    &'static str,
+    &'static str,
+    fn(&mut rpn::Stack) -> rpn::Result<()>,
+);
+
+// Name, arity, signature, help text, and implementation of the built-in operations
+const BUILTINS: &[Builtin] = &[
+    (
+        "+",
+        2,
+        "int int -> int; mat mat -> mat; qty qty -> qty; cplx cplx -> cplx; date dur -> date; \
+         time dur -> time; dur dur -> dur; expr expr -> expr",
+        "Add two integers, two matrices of the same shape, two quantities of the same \
+         dimension, two complex numbers, a duration to a date or time, or two expressions.",
+        rpn::add,
+    ),
+    (
+        "-",
+        2,
+        "int int -> int; qty qty -> qty; cplx cplx -> cplx; date date -> dur; date dur -> date; \
+         time time -> dur; time dur -> time; dur dur -> dur; expr expr -> expr",
+        "Subtract the top item from the one below it.",
+        rpn::sub,
+    ),
+    (
+        "=",
+        2,
+        "int int -> bool; bool bool -> bool; mat mat -> bool; qty qty -> bool; \
+         cplx cplx -> bool; date date -> bool; expr expr -> bool",
+        "Check if two items are equal; expressions are equal if they simplify to the same thing.",
+        rpn::eq,
+    ),
+    ("~", 1, "bool -> bool", "Negate a boolean.", rpn::neg),
+    ("<->", 2, "a b -> b a", "Swap the top two items.", rpn::swap),
+    (
+        "#",
+        1,
+        "int -> int",
+        "Random integer from 0 up to, but not including, the top item.",
+        rpn::random,
+    ),
+    (
+        "?",
+        3,
+        "bool a b -> a or b",
+        "If-then-else: keep a if the condition is true, and b otherwise.",
+        rpn::cond,
+    ),
+    ("quit", 0, "->", "Quit the calculator.", rpn::quit),
+    (
+        "/",
+        2,
+        "int int -> int; qty qty -> qty; cplx cplx -> cplx",
+        "Divide integers, rounding towards zero, quantities, combining their units, or complex \
+         numbers.",
+        rpn::div,
+    ),
+    (
+        "to",
+        2,
+        "qty quote -> qty",
+        "`q [ km / h ] to` converts a quantity to another unit of the same dimension.",
+        rpn::to,
+    ),
+    ("conj", 1, "cplx -> cplx", "Complex conjugate.", rpn::conj),
+    (
+        "abs",
+        1,
+        "cplx -> num; int -> int; qty -> qty",
+        "Magnitude of a complex number, or absolute value of an integer or quantity.",
+        rpn::abs,
+    ),
+    (
+        "arg",
+        1,
+        "cplx -> num",
+        "Argument of a complex number, in radians between -pi and pi.",
+        rpn::arg,
+    ),
+    (
+        "polar",
+        1,
+        "cplx -> num num",
+        "`z polar` leaves the magnitude and the argument of z.",
+        rpn::polar,
+    ),
+    (
+        "rect",
+        2,
+        "num num -> cplx",
+        "`r theta rect` makes the complex number with magnitude r and argument theta.",
+        rpn::rect,
+    ),
+    ("today", 0, "-> date", "Today's date (in UTC).", rpn::today),
+    (
+        "weekday",
+        1,
+        "date -> int",
+        "Day of the week of a date, from 1 for Monday to 7 for Sunday.",
+        rpn::weekday,
+    ),
+    (
+        "week",
+        1,
+        "date -> int",
+        "ISO week number of a date; week 1 has the year's first Thursday in it.",
+        rpn::week,
+    ),
+    (
+        "addbdays",
+        2,
+        "date int -> date",
+        "`d n addbdays` moves a date n business days on (or back), skipping weekends.",
+        rpn::add_business_days,
+    ),
+    (
+        "bdays",
+        2,
+        "date date -> int",
+        "`d1 d2 bdays` counts the business days from d1 up to, but not including, d2.",
+        rpn::business_days,
+    ),
+    (
+        "transpose",
+        1,
+        "mat -> mat",
+        "Flip a matrix over its diagonal.",
+        rpn::transpose,
+    ),
+    (
+        "ident",
+        1,
+        "int -> mat",
+        "Identity matrix of the given size.",
+        rpn::ident,
+    ),
+    (
+        "prime?",
+        1,
+        "int -> bool",
+        "Check if an integer is prime.",
+        rpn::is_prime,
+    ),
+    (
+        "factor",
+        1,
+        "int -> mat",
+        "Prime factors of a positive integer with multiplicity, as a single-row matrix.",
+        rpn::factor,
+    ),
+    (
+        "gcd",
+        2,
+        "int int -> int",
+        "Greatest common divisor.",
+        rpn::gcd,
+    ),
+    (
+        "lcm",
+        2,
+        "int int -> int",
+        "Least common multiple.",
+        rpn::lcm,
+    ),
+    (
+        "modpow",
+        3,
+        "int int int -> int",
+        "`b e m modpow` computes b^e mod m.",
+        rpn::mod_pow,
+    ),
+    (
+        "modinv",
+        2,
+        "int int -> int",
+        "`a m modinv` computes the inverse of a mod m.",
+        rpn::mod_inv,
+    ),
+    (
+        "totient",
+        1,
+        "int -> int",
+        "Euler's totient function.",
+        rpn::totient,
+    ),
+    (
+        "simplify",
+        1,
+        "expr -> expr",
+        "Fold constants, combine like terms and drop zeros and ones.",
+        rpn::simplify,
+    ),
+    (
+        "subst",
+        3,
+        "expr var expr -> expr",
+        "`e x v subst` replaces the unknown x in e with v, and simplifies.",
+        rpn::subst,
+    ),
+    (
+        "d/dx",
+        2,
+        "expr var -> expr",
+        "`e x d/dx` differentiates e with respect to the unknown x, and simplifies.",
+        rpn::derive,
+    ),
+    (
+        "throw",
+        1,
+        "a ->",
+        "Raise an error; the message is the text of a quotation, or the item itself.",
+        rpn::throw,
+    ),
+];
+
+type Metered = (
+    &'static str,
+    usize,
+    &'static str,
+    &'static str,
+    fn(&mut rpn::Stack, &mut dyn FnMut() -> rpn::Result<()>) -> rpn::Result<()>,
+);
+
+// Operations on the stack that can do a lot of work in one token, on big matrices. They check the
+// budget as they go, so a single product can't run past the time limit or ignore Ctrl-C
+const METERED: &[Metered] = &[
+    (
+        "*",
+        2,
+        "int int -> int; int mat -> mat; mat int -> mat; mat mat -> mat; qty qty -> qty; \
+         cplx cplx -> cplx; dur int -> dur; expr expr -> expr",
+        "Multiply integers, matrices, quantities, complex numbers and expressions, or a \
+         duration by an integer.",
+        rpn::mul,
+    ),
+    (
+        "pow",
+        2,
+        "int int -> int; mat int -> mat; qty int -> qty; expr int -> expr; cplx cplx -> cplx",
+        "Raise an integer, a square matrix, a quantity or an expression to a non-negative \
+         power, or a complex number to a complex power (the principal value).",
+        rpn::pow,
+    ),
+    (
+        "det",
+        1,
+        "mat -> int",
+        "Determinant of a square matrix.",
+        rpn::det,
+    ),
+];
+
+type Control = (
+    &'static str,
+    usize,
+    &'static str,
+    &'static str,
+    fn(&mut Session) -> rpn::Result<()>,
+);
+
+// Operations that need the whole session rather than just the stack: those that run quotations, and
+// those that can run for a long time on a single number, which take a step for every prime they
+// find so that they stay within the budget
+const CONTROL: &[Control] = &[
+    ("call", 1, "quote -> ...", "Run a quotation.", call),
+    (
+        "try",
+        2,
+        "quote quote -> ...",
+        "`[ body ] [ handler ] try` runs the body; if it fails, the stacks are restored and the \
+         handler runs with the error pushed.",
+        try_catch,
+    ),
+    (
+        "times",
+        2,
+        "quote int -> ...",
+        "`[ body ] n times` runs the body n times.",
+        times,
+    ),
+    (
+        "primes",
+        1,
+        "int -> mat",
+        "All primes up to n, as a single-row matrix.",
+        primes,
+    ),
+    (
+        "primepi",
+        1,
+        "int -> int",
+        "Number of primes up to n.",
+        prime_pi,
+    ),
+];
+
+fn call(session: &mut Session) -> rpn::Result<()> {
+    let body = session.stack_mut().pop_quote()?;
+    session.call(&body)
+}
+
+fn times(session: &mut Session) -> rpn::Result<()> {
+    let count = session.stack_mut().pop_nat()?;
+    let body = session.stack_mut().pop_quote()?;
+    for _ in 0..count {
+        session.call(&body)?;
+    }
+    Ok(())
+}
+
+// Primes (`primes`): all primes up to n, as a single-row matrix
+fn primes(session: &mut Session) -> rpn::Result<()> {
+    let n = session.stack_mut().pop_nat()?;
+    let max_item = session.budget().max_item;
+    let mut row = Vec::new();
+    for prime in exercise3::primes(n as u32) {
+        session.step()?;
+        if row.len() >= max_item {
+            return Err(rpn::Error::Limit(Limit::Item));
+        }
+        row.push(prime as i32);
+    }
+    session.stack_mut().push(rpn::Item::Matrix(vec![row]))
+}
+
+// PrimePi (`primepi`): number of primes up to n
+fn prime_pi(session: &mut Session) -> rpn::Result<()> {
+    let n = session.stack_mut().pop_nat()?;
+    let mut count = 0;
+    for _ in exercise3::primes(n as u32) {
+        session.step()?;
+        count += 1;
+    }
+    session.stack_mut().push_nat(count)
+}
+
+fn try_catch(session: &mut Session) -> rpn::Result<()> {
+    let handler = session.stack_mut().pop_quote()?;
+    let body = session.stack_mut().pop_quote()?;
+
+    let saved = session.stacks().clone();
+    match session.call(&body) {
+        Err(err) if err.is_catchable() => {
+            *session.stacks_mut() = saved;
+            session
+                .stack_mut()
+                .push(rpn::Item::Error(err.to_string()))?;
+            session.call(&handler)
+        }
+        res => res,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_builtins_registered() {
+        let registry = Registry::builtins();
+        for name in &["+", "=", "~", "<->", "#", "?", "quit", "*", "det", "modpow"] {
+            assert!(registry.get(name).is_some(), "missing {}", name);
+        }
+        assert_eq!(registry.get("modpow").unwrap().arity, 3);
+        assert!(registry.get("frobnicate").is_none());
+    }
+
+    #[test]
+    fn test_register_custom() {
+        let mut session = Session::new();
+        let double = OpDef::new("double", 1, "int -> int", "Double an integer.", |session| {
+            let val = session.stack_mut().pop_int()?;
+            session.stack_mut().push(rpn::Item::Int(2 * val))
+        });
+        assert!(session.registry_mut().register(double).is_none());
+
+        session.stack_mut().push(rpn::Item::Int(21)).unwrap();
+        session.apply("double").unwrap();
+        assert_eq!(session.stack_mut().pop().unwrap(), rpn::Item::Int(42));
+
+        assert!(matches!(session.apply("double"), Err(rpn::Error::Empty)));
+        assert!(matches!(session.apply("@triple"), Err(rpn::Error::Syntax)));
+    }
+
+    #[test]
+    fn test_arity_checked_first() {
+        let mut session = Session::new();
+        session.stack_mut().push(rpn::Item::Int(1)).unwrap();
+        assert!(matches!(session.apply("+"), Err(rpn::Error::Empty)));
+        assert_eq!(session.stack().len(), 1);
+    }
+
+    #[test]
+    fn test_help() {
+        let registry = Registry::builtins();
+        assert_eq!(
+            registry.help(Some("~")),
+            "~ (arity 1): bool -> bool\n    Negate a boolean."
+        );
+        assert!(registry.help(None).contains("modinv"));
+        assert_eq!(registry.help(Some("nope")), "No operation named `nope`");
+        assert_eq!(
+            registry.help(Some("h")),
+            "h is a unit (hour): write it right after a number, like `5 h`."
+        );
+    }
+}
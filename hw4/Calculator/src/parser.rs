@@ -5,40 +5,172 @@
 
 extern crate rand;
 
-use std::io::{self, Write};
-
+use super::complex::Complex;
+use super::dates::{Date, Duration, Time};
+use super::exercise2::{self, Matrix};
 use super::rpn;
+use super::session::Session;
+use super::units::{self, Quantity, Unit};
 
-pub fn rpn_repl() -> rpn::Result<()> {
-    let mut stack = rpn::Stack::new();
-    let mut input = String::new();
+/// Split a line into tokens.
+///
+/// Tokens are separated by whitespace, except that a matrix literal such as `[[1 2] [3 4]]` is
+/// kept together as a single token: everything from the opening `[[` up to the matching close
+/// bracket belongs to it.
+pub fn tokenize(buf: &str) -> rpn::Result<Vec<String>> {
+    let mut tokens = Vec::new();
+    let mut rest = buf.trim_start();
 
-    // Read-eval-print loop
-    loop {
-        // Clear the input buffer
-        input.clear();
+    while !rest.is_empty() {
+        let end = if rest.starts_with("[[") {
+            let mut depth = 0;
+            let mut close = None;
+            for (idx, c) in rest.char_indices() {
+                match c {
+                    '[' => depth += 1,
+                    ']' => depth -= 1,
+                    _ => (),
+                }
+                if depth == 0 {
+                    close = Some(idx + 1);
+                    break;
+                }
+            }
+            close.ok_or(rpn::Error::Syntax)?
+        } else {
+            rest.find(char::is_whitespace).unwrap_or(rest.len())
+        };
 
-        // Prompt the user
-        print!("> ");
-        io::stdout().flush().map_err(rpn::Error::IO)?;
+        tokens.push(rest[..end].to_string());
+        rest = rest[end..].trim_start();
+    }
 
-        // Read a line and evaluate it
-        io::stdin().read_line(&mut input).map_err(rpn::Error::IO)?;
-        evaluate_line(&mut stack, &input)?;
+    Ok(tokens)
+}
 
-        // A successful run should end with a stack with a exactly one item: the result
-        let res = stack.pop()?;
-        if stack.empty() {
-            println!("Reply> {:?}", res);
-        } else {
-            return Err(rpn::Error::Extra);
+/// Parse a matrix literal like `[[1 2] [3 4]]`, where each inner bracket holds one row.
+fn parse_matrix(tok: &str) -> rpn::Result<Matrix> {
+    let inner = tok
+        .strip_prefix('[')
+        .and_then(|t| t.strip_suffix(']'))
+        .ok_or(rpn::Error::Syntax)?;
+
+    let mut mat = Vec::new();
+    let mut rest = inner.trim_start();
+    while !rest.is_empty() {
+        let body = rest.strip_prefix('[').ok_or(rpn::Error::Syntax)?;
+        let close = body.find(']').ok_or(rpn::Error::Syntax)?;
+
+        let mut row = Vec::new();
+        for num in body[..close].split_whitespace() {
+            row.push(num.parse::<i32>().map_err(|_| rpn::Error::Syntax)?);
+        }
+        mat.push(row);
+
+        rest = body[close + 1..].trim_start();
+    }
+
+    exercise2::shape(&mat)?;
+    Ok(mat)
+}
+
+/// Whether a token is a decimal number like `2.5`, `-.5` or `6.02e23`, which makes a quantity
+/// without a unit.
+fn is_decimal(tok: &str) -> bool {
+    let digits = tok.trim_start_matches('-');
+    digits.starts_with(|c: char| c.is_ascii_digit() || c == '.')
+        && tok.contains(['.', 'e', 'E'])
+        && tok.parse::<f64>().is_ok()
+}
+
+/// Read a literal for one of the newer kinds of item: a decimal number, a complex number, or a
+/// date, time of day or duration.
+fn literal(tok: &str) -> Option<rpn::Item> {
+    if is_decimal(tok) {
+        // Too big to be anything but infinity, like `1e400`
+        let val = tok.parse::<f64>().ok().filter(|val| val.is_finite())?;
+        return Some(rpn::Item::Quantity(Quantity::new(val, Unit::none())));
+    }
+    let complex = Complex::parse(tok).map(rpn::Item::Complex);
+    complex
+        .or_else(|| Date::parse(tok).map(rpn::Item::Date))
+        .or_else(|| Time::parse(tok).map(rpn::Item::Time))
+        .or_else(|| Duration::parse(tok).map(rpn::Item::Duration))
+}
+
+/// Check that a word or variable name doesn't shadow a literal or the punctuation of the language.
+pub fn check_name(name: &str) -> rpn::Result<()> {
+    if name.is_empty()
+        || name.parse::<bool>().is_ok()
+        || name.parse::<i32>().is_ok()
+        || literal(name).is_some()
+        || name.starts_with('[')
+        || name.starts_with("->")
+        || [":", ";", "]", ">s", "s>"].contains(&name)
+    {
+        return Err(rpn::Error::Syntax);
+    }
+    Ok(())
+}
+
+/// Collect the name and body of a word definition `: name body ;`, after the `:` has been
+/// consumed. The body runs up to the first `;` outside of any quotation.
+fn collect_definition<'a, I>(tokens: &mut I) -> rpn::Result<(String, Vec<String>)>
+where
+    I: Iterator<Item = &'a String>,
+{
+    let name = tokens.next().ok_or(rpn::Error::Syntax)?;
+    check_name(name)?;
+
+    let mut body = Vec::new();
+    let mut depth = 0;
+    for tok in tokens {
+        match tok.as_str() {
+            "[" => depth += 1,
+            "]" => depth -= 1,
+            ";" if depth == 0 => return Ok((name.clone(), body)),
+            _ => (),
         }
+        body.push(tok.clone());
     }
+
+    Err(rpn::Error::Syntax)
 }
 
-fn evaluate_line(stack: &mut rpn::Stack, buf: &str) -> rpn::Result<()> {
-    // Trim whitespace and split; this gives an iterator of tokens.
-    let tokens = buf.trim().split_whitespace();
+/// Collect the tokens of a quotation, up to the `]` matching an already-consumed `[`.
+fn collect_quote<'a, I>(tokens: &mut I) -> rpn::Result<Vec<String>>
+where
+    I: Iterator<Item = &'a String>,
+{
+    let mut quote = Vec::new();
+    let mut depth = 0;
+    for tok in tokens {
+        match tok.as_str() {
+            "[" => depth += 1,
+            "]" if depth == 0 => return Ok(quote),
+            "]" => depth -= 1,
+            _ => (),
+        }
+        quote.push(tok.clone());
+    }
+
+    Err(rpn::Error::Syntax)
+}
+
+pub fn evaluate_line(session: &mut Session, buf: &str) -> rpn::Result<()> {
+    // Split the line into whitespace-separated tokens, keeping matrix literals whole.
+    let tokens = tokenize(buf)?;
+    session.begin();
+    eval_tokens(session, &tokens)
+}
+
+/// Evaluate a sequence of tokens. Quotations run through here too, when they are called.
+pub fn eval_tokens(session: &mut Session, tokens: &[String]) -> rpn::Result<()> {
+    let mut tokens = tokens.iter();
+
+    // Whether the last token was a number, or a unit written after one. Only there does a unit
+    // name like `m` or `h` give the number a unit; anywhere else it is a name like any other.
+    let mut after_number = false;
 
     /*
      * Write the main loop processing the tokens. The `parse` method for Strings will be useful for
@@ -46,38 +178,82 @@ fn evaluate_line(stack: &mut rpn::Stack, buf: &str) -> rpn::Result<()> {
      *
      * https://doc.rust-lang.org/std/primitive.str.html#method.parse
      */
-    for tok in tokens {
+    while let Some(tok) = tokens.next() {
+        session.step()?;
+        session.check_stack()?;
+
+        // Words and variables still take priority over units
+        let unit = std::mem::replace(&mut after_number, false)
+            && units::lookup(tok).is_some()
+            && session.word(tok).is_none()
+            && session.var(tok).is_none();
+        if unit {
+            rpn::apply_unit(session.stack_mut(), tok)?;
+            after_number = true;
+            continue;
+        }
+
+        if tok.starts_with("[[") {
+            session
+                .stack_mut()
+                .push(rpn::Item::Matrix(parse_matrix(tok)?))?;
+            continue;
+        }
+
+        // A lone `[` starts a quotation, which is pushed without being evaluated
+        if tok == "[" {
+            let quote = collect_quote(&mut tokens)?;
+            session.stack_mut().push(rpn::Item::Quote(quote))?;
+            continue;
+        }
+
+        // `: name body ;` defines a new word, which runs its body when called
+        if tok == ":" {
+            let (name, body) = collect_definition(&mut tokens)?;
+            session.define(&name, body);
+            continue;
+        }
+
+        // `>s name` moves the top item onto another stack, and `s> name` moves one back
+        if tok == ">s" || tok == "s>" {
+            let name = tokens.next().ok_or(rpn::Error::Syntax)?;
+            let stacks = session.stacks_mut();
+            match tok.as_str() {
+                ">s" => stacks.move_to(name)?,
+                _ => stacks.move_from(name)?,
+            }
+            continue;
+        }
+
+        // `->name` pops the top item into a variable; `name` pushes it back
+        if let Some(name) = tok.strip_prefix("->") {
+            check_name(name)?;
+            let item = session.stack_mut().pop()?;
+            session.set_var(name, item);
+            continue;
+        }
+
         let bool_res = tok.parse::<bool>();
         match bool_res {
-            Ok(bool_val) => match stack.push(rpn::Item::Bool(bool_val)) {
+            Ok(bool_val) => match session.stack_mut().push(rpn::Item::Bool(bool_val)) {
                 Ok(()) => (),
                 Err(e_1) => return Err(e_1),
             },
             Err(_) => {
                 let int_res = tok.parse::<i32>();
                 match int_res {
-                    Ok(int_val) => match stack.push(rpn::Item::Int(int_val)) {
-                        Ok(()) => (),
+                    Ok(int_val) => match session.stack_mut().push(rpn::Item::Int(int_val)) {
+                        Ok(()) => after_number = true,
                         Err(e_2) => return Err(e_2),
                     },
+                    Err(_) if literal(tok).is_some() => {
+                        let item = literal(tok).ok_or(rpn::Error::Syntax)?;
+                        after_number = matches!(item, rpn::Item::Quantity(_));
+                        session.stack_mut().push(item)?;
+                    }
                     Err(_) => {
-                        let res = if tok == "+" {
-                            stack.eval(rpn::Op::Add)
-                        } else if tok == "=" {
-                            stack.eval(rpn::Op::Eq)
-                        } else if tok == "~" {
-                            stack.eval(rpn::Op::Neg)
-                        } else if tok == "<->" {
-                            stack.eval(rpn::Op::Swap)
-                        } else if tok == "#" {
-                            stack.eval(rpn::Op::Rand)
-                        } else if tok == "?" {
-                            stack.eval(rpn::Op::Cond)
-                        } else if tok == "quit" {
-                            stack.eval(rpn::Op::Quit)
-                        } else {
-                            Err(rpn::Error::Syntax)
-                        };
+                        // Anything else should name a registered operation
+                        let res = session.apply(tok);
 
                         match res {
                             Ok(()) => (),
@@ -89,5 +265,346 @@ fn evaluate_line(stack: &mut rpn::Stack, buf: &str) -> rpn::Result<()> {
         }
     }
 
-    Ok(())
+    session.check_stack()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(line: &str) -> rpn::Result<rpn::Item> {
+        let mut session = Session::new();
+        evaluate_line(&mut session, line)?;
+        session.stack_mut().pop()
+    }
+
+    #[test]
+    fn test_tokenize() {
+        assert_eq!(
+            tokenize(" 1 [[1 2] [3 4]]\t[[5]] * ").unwrap(),
+            ["1", "[[1 2] [3 4]]", "[[5]]", "*"]
+        );
+        assert!(tokenize("[[1 2]").is_err());
+    }
+
+    #[test]
+    fn test_matrix_literal() {
+        assert_eq!(
+            parse_matrix("[[1 2][3 4]]").unwrap(),
+            vec![vec![1, 2], vec![3, 4]]
+        );
+        assert_eq!(parse_matrix("[[ -1 ]]").unwrap(), vec![vec![-1]]);
+        assert!(parse_matrix("[[1 2][3]]").is_err());
+        assert!(parse_matrix("[[1 x]]").is_err());
+    }
+
+    #[test]
+    fn test_matrix_ops() {
+        let path_counts = rpn::Item::Matrix(vec![vec![3, 2], vec![2, 1]]);
+        assert_eq!(run("[[1 1][1 0]] 3 pow").unwrap(), path_counts);
+        assert_eq!(run("[[1 2][3 4]] det").unwrap(), rpn::Item::Int(-2));
+        assert_eq!(
+            run("[[1 2][3 4]] transpose 2 ident +").unwrap(),
+            rpn::Item::Matrix(vec![vec![2, 3], vec![2, 5]])
+        );
+        assert_eq!(
+            run("[[1 2]] [[3][4]] *").unwrap(),
+            rpn::Item::Matrix(vec![vec![11]])
+        );
+        assert!(run("[[1 2]] [[3 4]] *").is_err());
+        assert!(run("3 ident det 1 +").is_ok());
+        assert!(run("1000 ident").is_ok());
+        assert!(matches!(run("1001 ident"), Err(rpn::Error::Domain)));
+        assert!(matches!(run("2147483647 ident"), Err(rpn::Error::Domain)));
+    }
+
+    #[test]
+    fn test_number_theory_words() {
+        assert_eq!(run("97 prime?").unwrap(), rpn::Item::Bool(true));
+        assert_eq!(run("-7 prime?").unwrap(), rpn::Item::Bool(false));
+        assert_eq!(
+            run("360 factor").unwrap(),
+            rpn::Item::Matrix(vec![vec![2, 2, 2, 3, 3, 5]])
+        );
+        assert_eq!(
+            run("20 primes").unwrap(),
+            rpn::Item::Matrix(vec![vec![2, 3, 5, 7, 11, 13, 17, 19]])
+        );
+        assert_eq!(run("1000000 primepi").unwrap(), rpn::Item::Int(78498));
+        assert_eq!(run("-12 18 gcd").unwrap(), rpn::Item::Int(6));
+        assert_eq!(run("4 6 lcm").unwrap(), rpn::Item::Int(12));
+        assert_eq!(run("-2 10 1000 modpow").unwrap(), rpn::Item::Int(24));
+        assert_eq!(run("3 11 modinv").unwrap(), rpn::Item::Int(4));
+        assert_eq!(run("36 totient").unwrap(), rpn::Item::Int(12));
+
+        assert!(run("4 8 modinv").is_err());
+        assert!(run("2 10 0 modpow").is_err());
+        assert!(run("65536 65537 lcm").is_err());
+        assert!(run("0 factor").is_err());
+    }
+
+    #[test]
+    fn test_quotations() {
+        assert_eq!(
+            run("[ 1 [ 2 ] + ]").unwrap(),
+            rpn::Item::Quote(
+                vec!["1", "[", "2", "]", "+"]
+                    .into_iter()
+                    .map(String::from)
+                    .collect()
+            )
+        );
+        assert_eq!(run("[ 1 2 + ] call").unwrap(), rpn::Item::Int(3));
+        assert!(matches!(run("[ 1 2"), Err(rpn::Error::Syntax)));
+        assert!(matches!(run("1 ]"), Err(rpn::Error::Syntax)));
+    }
+
+    #[test]
+    fn test_try_catch() {
+        // The handler sees the stack as it was before the body ran, plus the error
+        let mut session = Session::new();
+        evaluate_line(&mut session, "10 [ true + ] [ ] try").unwrap();
+        assert_eq!(
+            session.stack_mut().pop().unwrap(),
+            rpn::Item::Error(String::from("type mismatch"))
+        );
+        assert_eq!(session.stack_mut().pop().unwrap(), rpn::Item::Int(10));
+        assert!(session.stack().empty());
+
+        assert_eq!(run("[ 1 2 + ] [ 0 ] try").unwrap(), rpn::Item::Int(3));
+        assert_eq!(
+            run("[ [ too big ] throw ] [ ] try").unwrap(),
+            rpn::Item::Error(String::from("too big"))
+        );
+
+        // Handlers can recover with a default value, or rethrow the error
+        let mut session = Session::new();
+        evaluate_line(&mut session, "[ + ] [ 0 ] try").unwrap();
+        assert_eq!(session.stack_mut().pop().unwrap(), rpn::Item::Int(0));
+        assert!(matches!(
+            session.stack_mut().pop().unwrap(),
+            rpn::Item::Error(_)
+        ));
+        assert!(matches!(
+            run("[ 42 throw ] [ throw ] try"),
+            Err(rpn::Error::Thrown(msg)) if msg == "42"
+        ));
+        assert!(matches!(run("[ quit ] [ 0 ] try"), Err(rpn::Error::Quit)));
+    }
+
+    #[test]
+    fn test_definitions() {
+        let mut session = Session::new();
+        evaluate_line(&mut session, ": neg1 [ 1 ] call ~ ; : two [ [ 2 ] call ] ;").unwrap();
+        assert!(session.stack().empty());
+        assert_eq!(session.word("neg1").unwrap(), ["[", "1", "]", "call", "~"]);
+
+        // Words can call each other, and be redefined
+        evaluate_line(&mut session, ": inc 1 + ; : inc2 inc inc ; 5 inc2").unwrap();
+        assert_eq!(session.stack_mut().pop().unwrap(), rpn::Item::Int(7));
+        evaluate_line(&mut session, ": inc 10 + ; 5 inc2").unwrap();
+        assert_eq!(session.stack_mut().pop().unwrap(), rpn::Item::Int(25));
+
+        assert_eq!(run("0 [ 3 + ] 4 times").unwrap(), rpn::Item::Int(12));
+        assert_eq!(run("7 [ 3 + ] 0 times").unwrap(), rpn::Item::Int(7));
+        assert!(matches!(run("[ ] -1 times"), Err(rpn::Error::Domain)));
+
+        assert!(matches!(run(": 3 4 ;"), Err(rpn::Error::Syntax)));
+        assert!(matches!(run(": f 1 +"), Err(rpn::Error::Syntax)));
+        assert!(matches!(run(":"), Err(rpn::Error::Syntax)));
+    }
+
+    #[test]
+    fn test_symbolic() {
+        let expr = |line| run(line).unwrap().to_string();
+        assert_eq!(expr("x 1 + y *"), "(x + 1)*y");
+        assert_eq!(expr("x x + x - 2 pow simplify"), "x^2");
+        assert_eq!(expr("x 3 * x 2 pow + 2 x * - simplify"), "x^2 + x");
+        assert_eq!(expr("x 2 pow 3 x * + x 5 subst"), "40");
+        assert_eq!(expr("x y * y x subst"), "x^2");
+        assert_eq!(expr("x 3 pow 4 x * + x d/dx"), "3*x^2 + 4");
+        assert_eq!(expr("x y * x y 2 pow + * y d/dx"), "x*(y^2 + x) + 2*x*y^2");
+
+        assert_eq!(run("x 2 * x x + =").unwrap(), rpn::Item::Bool(true));
+        assert_eq!(run("4 x x * x 2 subst =").unwrap(), rpn::Item::Bool(true));
+        assert_eq!(run("x y =").unwrap(), rpn::Item::Bool(false));
+
+        // Variables and words take priority over unknowns
+        assert_eq!(expr("3 ->x x y +"), "3 + y");
+        assert!(matches!(run("x 1 x subst"), Err(rpn::Error::Type(None))));
+        assert!(matches!(run("x true +"), Err(rpn::Error::Type(None))));
+        assert!(matches!(
+            run("x 2147483647 * x + simplify"),
+            Err(rpn::Error::Overflow)
+        ));
+        assert!(matches!(run("x @"), Err(rpn::Error::Syntax)));
+
+        // Expressions that only get deeper, or double with every step, stop at a limit
+        assert!(matches!(
+            run("x [ 1 + ] 300000 times"),
+            Err(rpn::Error::Limit(rpn::Limit::Depth))
+        ));
+        assert!(matches!(
+            run("x [ ->a a a + ] 40 times"),
+            Err(rpn::Error::Limit(rpn::Limit::Item))
+        ));
+    }
+
+    #[test]
+    fn test_units() {
+        let show = |line| run(line).unwrap().to_string();
+        assert_eq!(show("5 m"), "5 m");
+        assert_eq!(show("2.5"), "2.5");
+        assert_eq!(show("1 km 500 m +"), "1.5 km");
+        assert_eq!(show("1 km 1 mi -"), "-0.609344 km");
+        assert_eq!(show("10 m 4 s /"), "2.5 m/s");
+        assert_eq!(show("3 N 2 m *"), "6 N*m");
+        assert_eq!(show("5 kg m"), "5 kg*m");
+        assert_eq!(show("3 m 2 pow"), "9 m^2");
+        assert_eq!(show("5 km [ mi ] to"), "3.10685596119 mi");
+        assert_eq!(show("18000000 J [ kWh ] to"), "5 kWh");
+        assert_eq!(show("100 km 1 h / [ m / s ] to"), "27.7777777778 m/s");
+        assert_eq!(show("2 kW 3 h * [ kWh ] to"), "6 kWh");
+        assert_eq!(show("0.1 0.2 +"), "0.3");
+        assert_eq!(show("7 2 /"), "3");
+
+        assert_eq!(run("1 km 1000 m =").unwrap(), rpn::Item::Bool(true));
+        assert_eq!(run("1 km 1 mi =").unwrap(), rpn::Item::Bool(false));
+        assert_eq!(run("1 m 1 s =").unwrap(), rpn::Item::Bool(false));
+
+        // Comparing doesn't depend on which operand comes first
+        assert_eq!(run("2 2.0 =").unwrap(), rpn::Item::Bool(true));
+        assert_eq!(run("2.0 2 =").unwrap(), rpn::Item::Bool(true));
+        assert_eq!(run("2 m 2 =").unwrap(), rpn::Item::Bool(false));
+        assert_eq!(run("2 2 m =").unwrap(), rpn::Item::Bool(false));
+        assert_eq!(run("true 1 =").unwrap(), rpn::Item::Bool(false));
+        assert_eq!(run("1 true =").unwrap(), rpn::Item::Bool(false));
+
+        // A result too big for a float overflows rather than becoming infinity
+        assert_eq!(run("1e308 10 *").unwrap_err().kind(), "Overflow");
+        assert_eq!(run("1e200 2 pow").unwrap_err().kind(), "Overflow");
+        assert_eq!(run("1 mi 1e308 * [ m ] to").unwrap_err().kind(), "Overflow");
+        assert_eq!(run("1e400").unwrap_err().kind(), "Syntax");
+
+        // Mixing dimensions is a type error that says what went wrong
+        let err = run("1 m 1 s +").unwrap_err();
+        assert_eq!(err.kind(), "Type");
+        assert_eq!(err.to_string(), "type mismatch: dimensions m and s differ");
+        assert_eq!(
+            run("1 J [ kg ] to").unwrap_err().to_string(),
+            "type mismatch: dimensions kg*m^2/s^2 and kg differ"
+        );
+        assert_eq!(
+            run("5 m 1 +").unwrap_err().to_string(),
+            "type mismatch: dimensions m and 1 differ"
+        );
+        assert!(matches!(run("1 m [ furlong ] to"), Err(rpn::Error::Syntax)));
+        assert!(matches!(run("1 m 0 s /"), Err(rpn::Error::Domain)));
+        assert!(matches!(run("1 0 /"), Err(rpn::Error::Domain)));
+        assert!(matches!(run("1 m [ m m ] +"), Err(rpn::Error::Type(None))));
+    }
+
+    #[test]
+    fn test_unit_names_as_unknowns() {
+        // A unit name only gives a number a unit right after the number; anywhere else it is an
+        // unknown (or a word or variable) like any other name
+        let expr = |line| run(line).unwrap().to_string();
+        assert_eq!(expr("t 2 pow t d/dx"), "2*t");
+        assert_eq!(expr("s s * 3 +"), "s*s + 3");
+        assert_eq!(expr("h 2 * h 5 subst"), "10");
+        assert_eq!(expr("g m *"), "g*m");
+        assert_eq!(expr("true m"), "m");
+        assert_eq!(expr("5 ->m 2 m *"), "10");
+        assert_eq!(expr("2 h"), "2 h");
+        assert_eq!(expr("2 kW h"), "2 kW*h");
+        assert_eq!(expr("2 km x"), "x");
+    }
+
+    #[test]
+    fn test_complex() {
+        let show = |line| run(line).unwrap().to_string();
+        assert_eq!(show("3+4i"), "3+4i");
+        assert_eq!(show("3+4i 1-2i +"), "4+2i");
+        assert_eq!(show("3+4i 1 -"), "2+4i");
+        assert_eq!(show("3+4i 2.5 *"), "7.5+10i");
+        assert_eq!(show("3+4i 1-2i *"), "11-2i");
+        assert_eq!(show("3+4i 1-2i /"), "-1+2i");
+        assert_eq!(show("3+4i conj"), "3-4i");
+        assert_eq!(show("3+4i abs"), "5");
+        assert_eq!(show("-7 abs"), "7");
+        assert_eq!(show("-1+0i arg"), "3.14159265359");
+        assert_eq!(show("1i 1i pow"), "0.207879576351+0i");
+        assert_eq!(show("-1 0.5 0i + pow"), "1i");
+        assert_eq!(show("2 1i pow"), "0.769238901364+0.638961276314i");
+        assert_eq!(show("1+1i polar rect"), "1+1i");
+        assert_eq!(show("2 -1.0 rect"), "1.08060461174-1.68294196962i");
+
+        let stack = |line| {
+            let mut session = Session::new();
+            evaluate_line(&mut session, line).unwrap();
+            session.stack().to_string()
+        };
+        assert_eq!(stack("3+4i polar"), "5 0.927295218002");
+
+        assert_eq!(run("1i 1i * -1 =").unwrap(), rpn::Item::Bool(true));
+        assert_eq!(run("1+2i 1+2.0000001i =").unwrap(), rpn::Item::Bool(false));
+        assert_eq!(run("1i 2.0 =").unwrap(), rpn::Item::Bool(false));
+        assert_eq!(run("2.0 1i =").unwrap(), rpn::Item::Bool(false));
+        assert_eq!(run("2+0i 2.0 =").unwrap(), rpn::Item::Bool(true));
+        assert_eq!(run("1i 2 m =").unwrap(), rpn::Item::Bool(false));
+        assert_eq!(run("2 m 1i =").unwrap(), rpn::Item::Bool(false));
+        assert!(matches!(run("1i 0 /"), Err(rpn::Error::Domain)));
+        assert!(matches!(run("0i -1 pow"), Err(rpn::Error::Domain)));
+        assert!(matches!(run("1i true +"), Err(rpn::Error::Type(None))));
+        assert!(matches!(run("1i 2 m +"), Err(rpn::Error::Type(Some(_)))));
+        assert!(matches!(run("1i 1i rect"), Err(rpn::Error::Type(None))));
+        assert!(matches!(run(": 1i 2 ;"), Err(rpn::Error::Syntax)));
+    }
+
+    #[test]
+    fn test_dates() {
+        let show = |line| run(line).unwrap().to_string();
+        assert_eq!(show("2026-10-17"), "2026-10-17");
+        assert_eq!(show("2026-10-17 P3D +"), "2026-10-20");
+        assert_eq!(show("P2W 2026-10-17 +"), "2026-10-31");
+        assert_eq!(show("2026-10-17 P1M -"), "2026-09-17");
+        assert_eq!(show("2026-12-25 2026-10-17 -"), "P69D");
+        assert_eq!(show("12:30 PT45M +"), "13:15");
+        assert_eq!(show("17:00 08:30:15 -"), "PT8H29M45S");
+        assert_eq!(show("P1D PT12H +"), "P1DT12H");
+        assert_eq!(show("PT90M 3 *"), "PT4H30M");
+        assert_eq!(show("2026-10-17 weekday"), "6");
+        assert_eq!(show("2026-10-17 week"), "42");
+        assert_eq!(show("2026-10-16 3 addbdays"), "2026-10-21");
+        assert_eq!(show("2026-10-01 2026-11-01 bdays"), "22");
+
+        // Release planning: ten business days after the freeze, and the week it falls in
+        assert_eq!(show("2026-10-23 10 addbdays ->r r r week"), "45");
+
+        assert_eq!(run("P1W P7D =").unwrap(), rpn::Item::Bool(true));
+        assert_eq!(run("P1M P30D =").unwrap(), rpn::Item::Bool(false));
+        for other in &["x", "1", "true", "2.5", "1i", "[[1]]", "P1D", "12:30"] {
+            let line = format!("2026-01-01 {} =", other);
+            assert_eq!(run(&line).unwrap(), rpn::Item::Bool(false), "{}", line);
+            let line = format!("{} 2026-01-01 =", other);
+            assert_eq!(run(&line).unwrap(), rpn::Item::Bool(false), "{}", line);
+        }
+        assert!(matches!(run("2026-10-17 PT1H +"), Err(rpn::Error::Domain)));
+        assert!(matches!(run("12:30 P1M +"), Err(rpn::Error::Domain)));
+        assert!(matches!(
+            run("2026-10-17 12:30 +"),
+            Err(rpn::Error::Type(None))
+        ));
+        assert!(matches!(
+            run("2026-10-17 2026-10-17 +"),
+            Err(rpn::Error::Type(None))
+        ));
+        assert!(matches!(run("12:30 weekday"), Err(rpn::Error::Type(None))));
+        assert!(matches!(run("2026-02-30"), Err(rpn::Error::Syntax)));
+        assert!(matches!(run(": P3D 1 ;"), Err(rpn::Error::Syntax)));
+        assert!(matches!(
+            run("9999-12-31 today -"),
+            Ok(rpn::Item::Duration(_))
+        ));
+    }
 }
@@ -0,0 +1,653 @@
+/*
+ * Reverse Polish Notation: repl.rs
+ * See `rpn.md` for the overview.
+ */
+
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+use super::parser;
+use super::rpn;
+use super::session::Session;
+use super::symbolic;
+use super::transcript::{self, Entry};
+
+/// The most significant digits `:precision` will show; an f64 doesn't have any more.
+const MAX_PRECISION: usize = 17;
+
+/// The most times `:play` will replay a macro in one go.
+const MAX_PLAYS: u32 = 10_000;
+
+/// A macro being recorded: its name, and the lines entered so far.
+struct Recording {
+    name: String,
+    lines: Vec<String>,
+}
+
+/// The interactive calculator: a session, plus the settings and macros of the person typing.
+pub struct Repl {
+    session: Session,
+    trace: bool,
+    strict: bool,
+    precision: usize,
+    recording: Option<Recording>,
+    macros: BTreeMap<String, Vec<String>>,
+    transcript: Vec<Entry>,
+}
+
+pub fn rpn_repl() -> rpn::Result<()> {
+    let mut repl = Repl::new();
+    let mut input = String::new();
+
+    // Ctrl-C stops the program being evaluated, not the whole calculator
+    let interrupt = repl.session.interrupt_handle();
+    ctrlc::set_handler(move || interrupt.store(true, Ordering::SeqCst))
+        .map_err(|err| rpn::Error::IO(io::Error::other(err)))?;
+
+    // Read-eval-print loop
+    loop {
+        // Clear the input buffer
+        input.clear();
+
+        // Prompt the user
+        print!("{}", repl.prompt());
+        io::stdout().flush().map_err(rpn::Error::IO)?;
+
+        // Read a line and evaluate it; end of input leaves the calculator
+        if io::stdin().read_line(&mut input).map_err(rpn::Error::IO)? == 0 {
+            return Ok(());
+        }
+
+        repl.handle(&input, &mut io::stdout())?;
+    }
+}
+
+impl Repl {
+    pub fn new() -> Self {
+        Repl {
+            session: Session::new(),
+            trace: false,
+            strict: false,
+            precision: rpn::DEFAULT_PRECISION,
+            recording: None,
+            macros: BTreeMap::new(),
+            transcript: Vec::new(),
+        }
+    }
+
+    pub fn session(&self) -> &Session {
+        &self.session
+    }
+
+    /// Every line handled so far, except blank lines and `:export`s, with what came of it.
+    pub fn transcript(&self) -> &[Entry] {
+        &self.transcript
+    }
+
+    /// The prompt shows the active stack and how many items are on it, like `main(2)> `.
+    pub fn prompt(&self) -> String {
+        let stack = format!(
+            "{}({})> ",
+            self.session.stacks().active(),
+            self.session.stack().len()
+        );
+        match self.recording {
+            Some(_) => format!("rec {}", stack),
+            None => stack,
+        }
+    }
+
+    /// Handle one line of input, writing any replies to `out`, and add it to the transcript.
+    ///
+    /// Only quitting and IO errors are returned. Any other error is reported, and abandons the
+    /// line: the stack goes back to how it was before the line.
+    pub fn handle<W: Write>(&mut self, line: &str, out: &mut W) -> rpn::Result<()> {
+        let mut buf = Vec::new();
+        let res = self.dispatch(line, &mut buf);
+        let error = match &res {
+            Err(rpn::Error::Quit) | Err(rpn::Error::IO(_)) | Ok(()) => None,
+            Err(err) => {
+                writeln!(buf, "Error: {}", err).map_err(rpn::Error::IO)?;
+                Some((err.kind().to_string(), err.to_string()))
+            }
+        };
+        out.write_all(&buf).map_err(rpn::Error::IO)?;
+
+        // Quitting, blank lines and exporting the transcript don't make it into the transcript
+        let input = line.trim();
+        let quit = matches!(res, Err(rpn::Error::Quit) | Err(rpn::Error::IO(_)));
+        if !quit && !input.is_empty() && !input.starts_with(":export") {
+            self.transcript.push(Entry {
+                input: input.to_string(),
+                output: String::from_utf8_lossy(&buf)
+                    .lines()
+                    .map(String::from)
+                    .collect(),
+                error,
+                stack: self
+                    .session
+                    .stack()
+                    .items()
+                    .iter()
+                    .map(From::from)
+                    .collect(),
+            });
+        }
+
+        match res {
+            Err(err @ rpn::Error::Quit) | Err(err @ rpn::Error::IO(_)) => Err(err),
+            _ => Ok(()),
+        }
+    }
+
+    // Run a line: `help`, a REPL command, or something to evaluate
+    fn dispatch<W: Write>(&mut self, line: &str, out: &mut W) -> rpn::Result<()> {
+        let mut words = line.split_whitespace();
+        match words.next() {
+            // `help` and `help <op>` describe operations instead of evaluating anything
+            Some("help") => writeln!(out, "{}", self.session.registry().help(words.next()))
+                .map_err(rpn::Error::IO),
+            Some(cmd) if cmd.len() > 1 && cmd.starts_with(':') => {
+                self.command(&cmd[1..], &words.collect::<Vec<_>>(), out)
+            }
+            _ => self.eval(line, out),
+        }
+    }
+
+    fn eval<W: Write>(&mut self, line: &str, out: &mut W) -> rpn::Result<()> {
+        let saved = self.session.stacks().clone();
+        let res = parser::evaluate_line(&mut self.session, line).and_then(|()| {
+            // In strict mode a line has to leave exactly one item: its result
+            match self.session.stack().len() {
+                _ if !self.strict => Ok(()),
+                0 => Err(rpn::Error::Empty),
+                1 => Ok(()),
+                _ => Err(rpn::Error::Extra),
+            }
+        });
+        if let Err(err) = res {
+            *self.session.stacks_mut() = saved;
+            return Err(err);
+        }
+
+        // Only lines that worked make it into a recording
+        if let Some(rec) = &mut self.recording {
+            if !line.trim().is_empty() {
+                rec.lines.push(line.trim().to_string());
+            }
+        }
+
+        self.reply(out)?;
+        if self.strict {
+            self.session.stack_mut().pop()?;
+        }
+        Ok(())
+    }
+
+    // Show the top of the stack, if there is anything on it
+    fn reply<W: Write>(&self, out: &mut W) -> rpn::Result<()> {
+        match self.session.stack().items().last() {
+            Some(top) => writeln!(out, "Reply> {:.*}", self.precision, top).map_err(rpn::Error::IO),
+            None => Ok(()),
+        }
+    }
+
+    /// Run a REPL command, written `:name args...`.
+    fn command<W: Write>(&mut self, name: &str, args: &[&str], out: &mut W) -> rpn::Result<()> {
+        let msg = match (name, args) {
+            ("limit", _) => {
+                set_limit(
+                    &mut self.session,
+                    args.first().copied(),
+                    args.get(1).copied(),
+                )?;
+                format!("Limits: {}", self.session.budget())
+            }
+            ("precision", []) => format!("Precision {} digits", self.precision),
+            ("precision", [digits]) => {
+                self.precision = match digits.parse().map_err(|_| rpn::Error::Syntax)? {
+                    digits @ 1..=MAX_PRECISION => digits,
+                    _ => return Err(rpn::Error::Domain),
+                };
+                format!("Precision {} digits", self.precision)
+            }
+            ("export", [file]) => match transcript::export(Path::new(file), &self.transcript) {
+                Ok(()) => format!("Exported {} lines to {}", self.transcript.len(), file),
+                Err(err) => format!("Could not export to {}: {}", file, err),
+            },
+            ("stack", []) => {
+                let depths = self.session.stacks().depths();
+                let active = self.session.stacks().active();
+                let list: Vec<_> = depths
+                    .iter()
+                    .map(|(name, depth)| {
+                        let mark = if *name == active { "*" } else { "" };
+                        format!("{}{} ({})", mark, name, depth)
+                    })
+                    .collect();
+                format!("Stacks: {}", list.join(", "))
+            }
+            ("stack", ["new", stack_name]) => {
+                self.session.stacks_mut().create(stack_name)?;
+                format!("Created stack `{}`", stack_name)
+            }
+            ("stack", ["use", stack_name]) => {
+                self.session.stacks_mut().switch(stack_name)?;
+                format!("Using stack `{}`", stack_name)
+            }
+            ("trace", ["on"]) | ("trace", ["off"]) => {
+                self.trace = args[0] == "on";
+                format!("Trace {}", args[0])
+            }
+            ("strict", ["on"]) | ("strict", ["off"]) => {
+                self.strict = args[0] == "on";
+                format!("Strict {}", args[0])
+            }
+            ("record", [macro_name]) if self.recording.is_none() => {
+                self.recording = Some(Recording {
+                    name: macro_name.to_string(),
+                    lines: Vec::new(),
+                });
+                format!("Recording `{}`; `:end` stops", macro_name)
+            }
+            ("end", []) => {
+                let rec = self.recording.take().ok_or(rpn::Error::Syntax)?;
+                let msg = format!("Recorded `{}` ({} lines)", rec.name, rec.lines.len());
+                self.macros.insert(rec.name, rec.lines);
+                msg
+            }
+            ("play", [macro_name]) => return self.play(macro_name, 1, out),
+            ("play", [macro_name, count]) => {
+                let count = count.parse().map_err(|_| rpn::Error::Syntax)?;
+                return self.play(macro_name, count, out);
+            }
+            ("save", [macro_name]) => {
+                let lines = self.macros.get(*macro_name).ok_or(rpn::Error::Syntax)?;
+                parser::check_name(macro_name)?;
+                let body = parser::tokenize(&lines.join(" "))?;
+                self.session.define(macro_name, body);
+                format!("Saved `{}` as a word", macro_name)
+            }
+            _ => return Err(rpn::Error::Syntax),
+        };
+
+        writeln!(out, "{}", msg).map_err(rpn::Error::IO)
+    }
+
+    /// Replay a macro `count` times against the current stack. If any line fails, the whole
+    /// replay is abandoned. The replay is metered as a single evaluation, so the limits and Ctrl-C
+    /// apply to all of it rather than to each line on its own.
+    fn play<W: Write>(&mut self, name: &str, count: u32, out: &mut W) -> rpn::Result<()> {
+        // Replaying while recording would copy the macro into itself, doubling it every time
+        if self.recording.is_some() {
+            return Err(rpn::Error::Syntax);
+        }
+        if count > MAX_PLAYS {
+            return Err(rpn::Error::Domain);
+        }
+        let lines = self.macros.get(name).ok_or(rpn::Error::Syntax)?;
+        let lines = lines
+            .iter()
+            .map(|line| Ok((line.clone(), parser::tokenize(line)?)))
+            .collect::<rpn::Result<Vec<_>>>()?;
+
+        let saved = self.session.stacks().clone();
+        self.session.begin();
+        if let Err(err) = self.replay(name, &lines, count, out) {
+            *self.session.stacks_mut() = saved;
+            return Err(err);
+        }
+
+        self.reply(out)
+    }
+
+    // Run the rounds of a replay, tracing each line if asked to
+    fn replay<W: Write>(
+        &mut self,
+        name: &str,
+        lines: &[(String, Vec<String>)],
+        count: u32,
+        out: &mut W,
+    ) -> rpn::Result<()> {
+        for round in 1..=count {
+            // Every round takes a step, so even a macro with no lines can be stopped
+            self.session.step()?;
+            for (idx, (line, tokens)) in lines.iter().enumerate() {
+                parser::eval_tokens(&mut self.session, tokens)?;
+                if self.trace {
+                    writeln!(
+                        out,
+                        "  {} {}.{}> {}  -- {:.*}",
+                        name,
+                        round,
+                        idx + 1,
+                        line,
+                        self.precision,
+                        self.session.stack()
+                    )
+                    .map_err(rpn::Error::IO)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Change one of the session's limits: `:limit steps|stack|calls|item|depth|time VALUE`, where the
+/// value is a count (seconds for `time`), or `off` to lift the limit. With no arguments nothing
+/// changes.
+fn set_limit(session: &mut Session, which: Option<&str>, value: Option<&str>) -> rpn::Result<()> {
+    fn parse<T: std::str::FromStr>(value: Option<&str>) -> rpn::Result<Option<T>> {
+        match value {
+            Some("off") => Ok(None),
+            Some(num) => num.parse().map(Some).map_err(|_| rpn::Error::Syntax),
+            None => Err(rpn::Error::Syntax),
+        }
+    }
+
+    let mut budget = session.budget();
+    match which {
+        None => (),
+        Some("steps") => budget.max_steps = parse(value)?,
+        Some("stack") => budget.max_stack = parse(value)?,
+        Some("calls") => budget.max_calls = parse(value)?.ok_or(rpn::Error::Domain)?,
+        Some("item") => budget.max_item = parse(value)?.ok_or(rpn::Error::Domain)?,
+        Some("depth") => {
+            budget.max_depth = match parse(value)? {
+                Some(depth) if depth <= symbolic::MAX_DEPTH => depth,
+                _ => return Err(rpn::Error::Domain),
+            }
+        }
+        Some("time") => {
+            budget.timeout = match parse::<f64>(value)? {
+                Some(secs) if secs.is_finite() && secs >= 0.0 => {
+                    Some(Duration::from_secs_f64(secs))
+                }
+                Some(_) => return Err(rpn::Error::Domain),
+                None => None,
+            }
+        }
+        Some(_) => return Err(rpn::Error::Syntax),
+    }
+
+    session.set_budget(budget);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Feed lines to the REPL, returning everything it printed
+    fn feed(repl: &mut Repl, lines: &[&str]) -> String {
+        let mut out = Vec::new();
+        for line in lines {
+            repl.handle(line, &mut out).unwrap();
+        }
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn test_stack_persists() {
+        let mut repl = Repl::new();
+        assert_eq!(
+            feed(&mut repl, &["1 2", "+", "true +", "10 +"]),
+            "Reply> 2\nReply> 3\nError: type mismatch\nReply> 13\n"
+        );
+        assert_eq!(repl.session().stack().to_string(), "13");
+        assert!(matches!(
+            repl.handle("quit", &mut Vec::new()),
+            Err(rpn::Error::Quit)
+        ));
+    }
+
+    #[test]
+    fn test_strict() {
+        // Strict mode works like the calculator used to: every line computes one result
+        let mut repl = Repl::new();
+        assert_eq!(
+            feed(&mut repl, &[":strict on", "1 2 +", "1 2", "+", "2 3 *"]),
+            "Strict on\nReply> 3\nError: extra items left on the stack\n\
+             Error: not enough items on the stack\nReply> 6\n"
+        );
+        assert!(repl.session().stack().empty());
+
+        assert_eq!(
+            feed(&mut repl, &[":strict off", "1", "2"]),
+            "Strict off\nReply> 1\nReply> 2\n"
+        );
+        assert_eq!(repl.session().stack().to_string(), "1 2");
+    }
+
+    #[test]
+    fn test_record_play() {
+        let mut repl = Repl::new();
+        assert_eq!(
+            feed(&mut repl, &["1", ":record step", "2 *", "1 +", "@", ":end"]),
+            "Reply> 1\nRecording `step`; `:end` stops\nReply> 2\nReply> 3\n\
+             Error: syntax error\nRecorded `step` (2 lines)\n"
+        );
+        assert_eq!(repl.prompt(), "main(1)> ");
+
+        assert_eq!(feed(&mut repl, &[":play step 3"]), "Reply> 31\n");
+        assert_eq!(feed(&mut repl, &[":play step"]), "Reply> 63\n");
+
+        // A failing replay leaves the stack alone
+        assert_eq!(
+            feed(&mut repl, &["true", ":play step"]),
+            "Reply> true\nError: type mismatch\n"
+        );
+        assert_eq!(repl.session().stack().to_string(), "63 true");
+
+        assert_eq!(
+            feed(&mut repl, &[":play nothing", ":play step x", ":end"]),
+            "Error: syntax error\n".repeat(3)
+        );
+    }
+
+    #[test]
+    fn test_play_limits() {
+        let mut repl = Repl::new();
+        feed(&mut repl, &["0", ":record inc", "1 +", ":end"]);
+
+        // The limits apply to the replay as a whole: each round here takes three steps
+        assert_eq!(
+            feed(
+                &mut repl,
+                &[":limit steps 10", ":play inc 3", ":play inc 4"]
+            ),
+            "Limits: steps 10, stack 1048576, calls 256, item 1048576, depth 256, time off\n\
+             Reply> 4\nError: step limit exceeded\n"
+        );
+        assert_eq!(repl.session().stack().to_string(), "4");
+
+        // Neither a huge count nor replaying into a recording can grow without bound
+        feed(&mut repl, &[":limit steps off"]);
+        assert_eq!(
+            feed(&mut repl, &[":play inc 4000000000"]),
+            "Error: argument out of range\n"
+        );
+        assert_eq!(
+            feed(&mut repl, &[":record inc", ":play inc", ":end"]),
+            "Recording `inc`; `:end` stops\nError: syntax error\nRecorded `inc` (0 lines)\n"
+        );
+    }
+
+    #[test]
+    fn test_trace() {
+        let mut repl = Repl::new();
+        feed(
+            &mut repl,
+            &["3", ":record dup2", "->a a a", "->b b b +", ":end"],
+        );
+        assert_eq!(
+            feed(&mut repl, &[":trace on", ":play dup2 2"]),
+            "Trace on\n  dup2 1.1> ->a a a  -- 3 6 6\n  dup2 1.2> ->b b b +  -- 3 6 12\n  \
+             dup2 2.1> ->a a a  -- 3 6 12 12\n  dup2 2.2> ->b b b +  -- 3 6 12 24\nReply> 24\n"
+        );
+    }
+
+    #[test]
+    fn test_save_as_word() {
+        let mut repl = Repl::new();
+        feed(
+            &mut repl,
+            &["5", ":record sq", "->n n n *", ":end", ":save sq"],
+        );
+        assert_eq!(feed(&mut repl, &["[ sq ] 2 times"]), "Reply> 390625\n");
+        assert_eq!(
+            feed(
+                &mut repl,
+                &[":save nothing", ":record 3", ":end", ":save 3"]
+            ),
+            "Error: syntax error\nRecording `3`; `:end` stops\nRecorded `3` (0 lines)\n\
+             Error: syntax error\n"
+        );
+    }
+
+    #[test]
+    fn test_set_limit() {
+        let mut repl = Repl::new();
+        assert_eq!(
+            feed(
+                &mut repl,
+                &[
+                    ":limit steps 10",
+                    ":limit time 0.5",
+                    ":limit stack off",
+                    ":limit item 100",
+                    ":limit depth 8"
+                ]
+            )
+            .lines()
+            .last(),
+            Some("Limits: steps 10, stack off, calls 256, item 100, depth 8, time 0.5s")
+        );
+
+        let session = &mut repl.session;
+        assert!(set_limit(session, Some("calls"), Some("off")).is_err());
+        assert!(set_limit(session, Some("depth"), Some("off")).is_err());
+        assert!(set_limit(session, Some("depth"), Some("1001")).is_err());
+        assert!(set_limit(session, Some("item"), Some("off")).is_err());
+        assert!(set_limit(session, Some("time"), Some("-1")).is_err());
+        assert!(set_limit(session, Some("speed"), Some("1")).is_err());
+        assert!(set_limit(session, Some("steps"), None).is_err());
+
+        // Running past a limit abandons the line, but the session carries on
+        assert_eq!(
+            feed(&mut repl, &["1 2 3 4 5 6 7 8 9 10 11", "1 2 +"]),
+            "Error: step limit exceeded\nReply> 3\n"
+        );
+    }
+
+    #[test]
+    fn test_precision() {
+        let mut repl = Repl::new();
+        assert_eq!(
+            feed(&mut repl, &["1 3 /", "1.0 3 /", "3+4i 1-2i /"]),
+            "Reply> 0\nReply> 0.333333333333\nReply> -1+2i\n"
+        );
+        assert_eq!(
+            feed(
+                &mut repl,
+                &[
+                    ":precision 4",
+                    "2 5 pow 1.0 7 / +",
+                    "1+1i 3 pow",
+                    "1+1i arg"
+                ]
+            ),
+            "Precision 4 digits\nReply> 32.14\nReply> -2+2i\nReply> 0.7854\n"
+        );
+        assert_eq!(
+            feed(
+                &mut repl,
+                &[
+                    ":precision",
+                    ":precision 0",
+                    ":precision 18",
+                    ":precision x"
+                ]
+            ),
+            "Precision 4 digits\nError: argument out of range\nError: argument out of range\n\
+             Error: syntax error\n"
+        );
+    }
+
+    #[test]
+    fn test_named_stacks() {
+        let mut repl = Repl::new();
+        assert_eq!(repl.prompt(), "main(0)> ");
+        assert_eq!(
+            feed(
+                &mut repl,
+                &[
+                    "10 20 30",
+                    ":stack new scratch",
+                    ">s scratch >s scratch",
+                    ":stack use scratch"
+                ]
+            ),
+            "Reply> 30\nCreated stack `scratch`\nReply> 10\nUsing stack `scratch`\n"
+        );
+        assert_eq!(repl.prompt(), "scratch(2)> ");
+        assert_eq!(
+            feed(&mut repl, &["*", ":stack"]),
+            "Reply> 600\nStacks: main (1), *scratch (1)\n"
+        );
+
+        // A failing line puts back what it moved between stacks
+        assert_eq!(
+            feed(&mut repl, &["s> main true +", ":stack"]),
+            "Error: type mismatch\nStacks: main (1), *scratch (1)\n"
+        );
+
+        feed(&mut repl, &[":record r"]);
+        assert_eq!(repl.prompt(), "rec scratch(1)> ");
+        assert_eq!(
+            feed(
+                &mut repl,
+                &[
+                    ":end",
+                    ":stack use nowhere",
+                    ":stack new scratch",
+                    ":stack new 1"
+                ]
+            ),
+            format!(
+                "Recorded `r` (0 lines)\n{}",
+                "Error: syntax error\n".repeat(3)
+            )
+        );
+    }
+
+    #[test]
+    fn test_export() {
+        let dir = std::env::temp_dir().join(format!("rpn-export-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (md, json) = (dir.join("log.md"), dir.join("log.json"));
+
+        let mut repl = Repl::new();
+        let export = |file: &Path| format!(":export {}", file.display());
+        let out = feed(
+            &mut repl,
+            &["1 2 +", "", "true +", &export(&md), &export(&json)],
+        );
+        assert!(out.ends_with(&format!("Exported 2 lines to {}\n", json.display())));
+
+        let text = std::fs::read_to_string(&md).unwrap();
+        assert!(text.contains("**[2]** `true +`\n\n    Error: type mismatch\n"));
+        let text = std::fs::read_to_string(&json).unwrap();
+        assert_eq!(transcript::replay(&text).unwrap(), 2);
+
+        let out = feed(&mut repl, &[":export log.txt", ":export"]);
+        assert!(out.starts_with("Could not export to log.txt: the file name should end in"));
+        assert!(out.ends_with("Error: syntax error\n"));
+        assert_eq!(repl.transcript().len(), 2);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -3,45 +3,234 @@
  * See `rpn.md` for the overview.
  */
 
+use std::convert::TryFrom;
+use std::fmt;
 use std::io;
 
-// Stacks will work with Items, which either either integers or booleans
-#[derive(Eq, PartialEq, Ord, PartialOrd, Debug)]
+use rand::Rng;
+
+use super::complex::Complex;
+use super::dates::{Date, Duration, Time};
+use super::exercise2::{self, MatError, Matrix};
+use super::exercise3;
+use super::symbolic::{self, Expr};
+use super::units::{DimensionMismatch, Quantity, Unit};
+
+// Stacks will work with Items, which are integers, booleans, or integer matrices. Quotations are
+// unevaluated lists of tokens, errors caught by `try` are pushed as Error items, and unknowns (and
+// arithmetic on them) make symbolic expressions. Quantities are decimal numbers with a unit.
+// Complex numbers have no unit. Dates, times of day and durations are for calendar arithmetic.
+#[derive(Clone, PartialEq, Debug)]
 pub enum Item {
     Int(i32),
     Bool(bool),
+    Matrix(Matrix),
+    Quote(Vec<String>),
+    Error(String),
+    Expr(Expr),
+    Quantity(Quantity),
+    Complex(Complex),
+    Date(Date),
+    Time(Time),
+    Duration(Duration),
+}
+
+impl fmt::Display for Item {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let digits = f.precision().unwrap_or(DEFAULT_PRECISION);
+        match self {
+            Item::Int(val) => write!(f, "{}", val),
+            Item::Bool(val) => write!(f, "{}", val),
+            Item::Matrix(mat) => write!(f, "{}", exercise2::pretty(mat)),
+            Item::Quote(toks) if toks.is_empty() => write!(f, "[ ]"),
+            Item::Quote(toks) => write!(f, "[ {} ]", toks.join(" ")),
+            Item::Error(msg) => write!(f, "<error: {}>", msg),
+            Item::Expr(expr) => write!(f, "{}", expr),
+            Item::Quantity(qty) if qty.unit.is_none() => {
+                write!(f, "{}", fmt_float(qty.value, digits))
+            }
+            Item::Quantity(qty) => write!(f, "{} {}", fmt_float(qty.value, digits), qty.unit),
+            Item::Complex(z) => write!(f, "{:.*}", digits, z),
+            Item::Date(date) => write!(f, "{}", date),
+            Item::Time(time) => write!(f, "{}", time),
+            Item::Duration(dur) => write!(f, "{}", dur),
+        }
+    }
+}
+
+impl Item {
+    // How big the item is, and how deeply it nests: matrices count their entries, quotations their
+    // tokens and expressions their nodes; everything else is a single flat value
+    pub fn measure(&self) -> (usize, usize) {
+        match self {
+            Item::Matrix(mat) => (mat.iter().map(Vec::len).sum(), 1),
+            Item::Quote(toks) => (toks.len(), 1),
+            Item::Expr(expr) => expr.measure(),
+            _ => (1, 1),
+        }
+    }
 }
 
 // List of possible errors
 #[derive(Debug)]
 pub enum Error {
-    Empty,         // Tried to pop empty stack
-    Extra,         // Stack ended with extra elements
-    Type,          // Type mismatch
-    Syntax,        // Syntax error, didn't recognize op
-    IO(io::Error), // Some kind of IO error
-    Quit,          // User quitting
+    Empty,            // Tried to pop empty stack
+    Extra,            // Stack ended with extra elements
+    Syntax,           // Syntax error, didn't recognize op
+    IO(io::Error),    // Some kind of IO error
+    Quit,             // User quitting
+    Overflow,         // Integer result out of range
+    Domain,           // Argument outside of what the op accepts
+    Matrix(MatError), // Matrix shapes don't fit the op
+    Thrown(String),   // Raised by the program with `throw`
+    Limit(Limit),     // Program ran past its budget, or was interrupted
+
+    // Type mismatch; between quantities of different physical dimensions, it says which
+    Type(Option<DimensionMismatch>),
 }
 
-// Base operations supported by calculator, see rpn.md
-#[derive(Debug)]
-pub enum Op {
-    Add,
-    Eq,
-    Neg,
-    Swap,
-    Rand,
-    Cond,
-    Quit,
+// The resource limits a program can run into
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Limit {
+    Steps,       // Evaluated too many tokens
+    Stack,       // Stack grew too deep
+    Calls,       // Quotations or words nested too deeply
+    Item,        // An item grew too big
+    Depth,       // An expression nested too deeply
+    Timeout,     // Ran for too long
+    Interrupted, // User pressed Ctrl-C
+}
+
+impl Error {
+    // Whether `try` may catch this error; quitting, IO problems and hitting a limit always go
+    // through, so a program can't swallow its own cancellation
+    pub fn is_catchable(&self) -> bool {
+        !matches!(self, Error::Quit | Error::IO(_) | Error::Limit(_))
+    }
+
+    // A short name for the kind of error, for reporting errors to other programs
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Error::Empty => "Empty",
+            Error::Extra => "Extra",
+            Error::Type(_) => "Type",
+            Error::Syntax => "Syntax",
+            Error::IO(_) => "IO",
+            Error::Quit => "Quit",
+            Error::Overflow => "Overflow",
+            Error::Domain => "Domain",
+            Error::Matrix(_) => "Matrix",
+            Error::Thrown(_) => "Thrown",
+            Error::Limit(_) => "Limit",
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Empty => write!(f, "not enough items on the stack"),
+            Error::Extra => write!(f, "extra items left on the stack"),
+            Error::Type(None) => write!(f, "type mismatch"),
+            Error::Syntax => write!(f, "syntax error"),
+            Error::IO(err) => write!(f, "IO error: {}", err),
+            Error::Quit => write!(f, "quit"),
+            Error::Overflow => write!(f, "integer overflow"),
+            Error::Domain => write!(f, "argument out of range"),
+            Error::Matrix(MatError::Ragged) => write!(f, "matrix rows differ in length"),
+            Error::Matrix(MatError::Mismatch(a, b)) => write!(
+                f,
+                "matrix shapes {}x{} and {}x{} don't fit",
+                a.0, a.1, b.0, b.1
+            ),
+            Error::Matrix(MatError::NotSquare(a)) => {
+                write!(f, "matrix is {}x{}, not square", a.0, a.1)
+            }
+            Error::Matrix(MatError::Overflow) => write!(f, "integer overflow"),
+            Error::Thrown(msg) => write!(f, "{}", msg),
+            Error::Limit(Limit::Steps) => write!(f, "step limit exceeded"),
+            Error::Limit(Limit::Stack) => write!(f, "stack depth limit exceeded"),
+            Error::Limit(Limit::Calls) => write!(f, "call depth limit exceeded"),
+            Error::Limit(Limit::Item) => write!(f, "item size limit exceeded"),
+            Error::Limit(Limit::Depth) => write!(f, "expression depth limit exceeded"),
+            Error::Limit(Limit::Timeout) => write!(f, "time limit exceeded"),
+            Error::Limit(Limit::Interrupted) => write!(f, "interrupted"),
+            Error::Type(Some(DimensionMismatch(a, b))) => {
+                let dim = |unit: &Unit| match unit.to_string() {
+                    name if name.is_empty() => String::from("1"),
+                    name => name,
+                };
+                write!(
+                    f,
+                    "type mismatch: dimensions {} and {} differ",
+                    dim(a),
+                    dim(b)
+                )
+            }
+        }
+    }
+}
+
+impl From<MatError> for Error {
+    fn from(err: MatError) -> Self {
+        match err {
+            MatError::Overflow => Error::Overflow,
+            _ => Error::Matrix(err),
+        }
+    }
+}
+
+impl From<DimensionMismatch> for Error {
+    fn from(err: DimensionMismatch) -> Self {
+        Error::Type(Some(err))
+    }
+}
+
+impl From<symbolic::TooBig> for Error {
+    fn from(_: symbolic::TooBig) -> Self {
+        Error::Limit(Limit::Item)
+    }
+}
+
+impl From<symbolic::Overflow> for Error {
+    fn from(_: symbolic::Overflow) -> Self {
+        Error::Overflow
+    }
+}
+
+// Significant digits shown for decimal and complex numbers, unless the formatter asks for more or
+// fewer with a precision, as in `{:.4}`
+pub const DEFAULT_PRECISION: usize = 12;
+
+// Write a decimal number, rounded to `digits` significant digits to hide floating-point noise
+pub fn fmt_float(val: f64, digits: usize) -> String {
+    let rounded = format!("{:.*e}", digits.max(1) - 1, val)
+        .parse::<f64>()
+        .unwrap_or(val);
+    format!("{}", rounded + 0.0)
 }
 
 // We'll define a result type for our calculator: either a valid value, or a calculator Error
 pub type Result<T> = std::result::Result<T, Error>;
 
 // Define a type for Stacks
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct Stack(Vec<Item>);
 
+impl fmt::Display for Stack {
+    // The items from the bottom up, separated by spaces, each shown with the formatter's precision
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let digits = f.precision().unwrap_or(DEFAULT_PRECISION);
+        for (idx, item) in self.0.iter().enumerate() {
+            if idx > 0 {
+                write!(f, " ")?;
+            }
+            write!(f, "{:.*}", digits, item)?;
+        }
+        Ok(())
+    }
+}
+
 // Implement the following functions on Stacks
 impl Stack {
     // Make a new Stack
@@ -55,6 +244,16 @@ impl Stack {
         self.0.is_empty()
     }
 
+    // Number of items on the Stack
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    // The items on the Stack, from the bottom up
+    pub fn items(&self) -> &[Item] {
+        &self.0
+    }
+
     // Push an item onto a stack (should never error)
     pub fn push(&mut self, item: Item) -> Result<()> {
         self.0.push(item);
@@ -70,88 +269,587 @@ impl Stack {
         }
     }
 
-    /*
-     * Main evaluation function: apply an operation to a Stack
-     *
-     * Complete each of the cases.
-     *
-     * Hint: You'll probably want to use the "question-mark" syntax quite a bit; see `rpn.md`.
-     */
-    pub fn eval(&mut self, op: Op) -> Result<()> {
-        match op {
-            Op::Add => {
-                let x = self.pop()?;
-                match x {
-                    Item::Int(val_x) => {
-                        let y = self.pop()?;
-                        match y {
-                            Item::Int(val_y) => self.push(Item::Int(val_x + val_y)),
-                            _ => Err(Error::Type),
-                        }
-                    }
-                    _ => {
-                        Err(Error::Type)},
-                }
-            }
-            Op::Eq => {
-                let x = self.pop()?;
-                match x {
-                    Item::Int(val_x) => {
-                        let y = self.pop()?;
-                        match y {
-                            Item::Int(val_y) => self.push(Item::Bool(val_x == val_y)),
-                            _ => Err(Error::Type),
-                        }
-                    }
-                    Item::Bool(val_x) => {
-                        let y = self.pop()?;
-                        match y {
-                            Item::Bool(val_y) => self.push(Item::Bool(val_x == val_y)),
-                            _ => Err(Error::Type),
-                        }
-                    }
-                }
-            }
-            Op::Neg => {
-                let x = self.pop()?;
-                match x {
-                    Item::Bool(val_x) => self.push(Item::Bool(!val_x)),
-                    _ => Err(Error::Type),
-                }
+    // Pop an integer off the Stack; anything else is a Type error
+    pub fn pop_int(&mut self) -> Result<i32> {
+        match self.pop()? {
+            Item::Int(val) => Ok(val),
+            _ => Err(Error::Type(None)),
+        }
+    }
+
+    // Pop a quotation's tokens off the Stack
+    pub fn pop_quote(&mut self) -> Result<Vec<String>> {
+        match self.pop()? {
+            Item::Quote(toks) => Ok(toks),
+            _ => Err(Error::Type(None)),
+        }
+    }
+
+    // Pop a non-negative integer off the Stack; negative integers are a Domain error
+    pub fn pop_nat(&mut self) -> Result<u64> {
+        let val = self.pop_int()?;
+        u64::try_from(val).map_err(|_| Error::Domain)
+    }
+
+    // Pop a positive modulus off the Stack
+    pub fn pop_modulus(&mut self) -> Result<u64> {
+        match self.pop_nat()? {
+            0 => Err(Error::Domain),
+            modulus => Ok(modulus),
+        }
+    }
+
+    // Push a number-theory result, which may not fit back into an integer Item
+    pub fn push_nat(&mut self, val: u64) -> Result<()> {
+        let val = i32::try_from(val).map_err(|_| Error::Overflow)?;
+        self.push(Item::Int(val))
+    }
+
+    // Push a list of numbers as a single-row matrix
+    pub fn push_row(&mut self, vals: impl Iterator<Item = u64>) -> Result<()> {
+        let mut row = Vec::new();
+        for val in vals {
+            row.push(i32::try_from(val).map_err(|_| Error::Overflow)?);
+        }
+        self.push(Item::Matrix(vec![row]))
+    }
+}
+
+/*
+ * Built-in operations. Each one takes the Stack and applies the operation to it; see `ops.rs` for
+ * how they are registered under their names.
+ *
+ * Hint: You'll probably want to use the "question-mark" syntax quite a bit; see `rpn.md`.
+ */
+
+// Add (`+`): add two integers, or two matrices of the same shape
+pub fn add(stack: &mut Stack) -> Result<()> {
+    let x = stack.pop()?;
+    let y = stack.pop()?;
+    match (y, x) {
+        (Item::Int(val_y), Item::Int(val_x)) => {
+            stack.push(Item::Int(val_y.checked_add(val_x).ok_or(Error::Overflow)?))
+        }
+        (Item::Matrix(mat_y), Item::Matrix(mat_x)) => {
+            stack.push(Item::Matrix(exercise2::mat_add(&mat_y, &mat_x)?))
+        }
+        (Item::Date(date), Item::Duration(dur)) | (Item::Duration(dur), Item::Date(date)) => {
+            stack.push(Item::Date(date.add(&dur).ok_or(Error::Domain)?))
+        }
+        (Item::Time(time), Item::Duration(dur)) | (Item::Duration(dur), Item::Time(time)) => {
+            stack.push(Item::Time(time.add(&dur).ok_or(Error::Domain)?))
+        }
+        (Item::Duration(dur_y), Item::Duration(dur_x)) => {
+            stack.push(Item::Duration(dur_y.add(&dur_x).ok_or(Error::Overflow)?))
+        }
+        (y @ Item::Complex(_), x) | (y, x @ Item::Complex(_)) => {
+            stack.push(Item::Complex(to_complex(&y)?.add(&to_complex(&x)?)))
+        }
+        (y @ Item::Quantity(_), x) | (y, x @ Item::Quantity(_)) => {
+            let sum = to_quantity(&y)?.add(&to_quantity(&x)?)?;
+            push_quantity(stack, sum)
+        }
+        (y, x) => {
+            let (expr_y, expr_x) = symbolic_pair(&y, &x)?;
+            stack.push(Item::Expr(expr_y.add(expr_x)))
+        }
+    }
+}
+
+// Sub (`-`): subtract integers, quantities or expressions
+pub fn sub(stack: &mut Stack) -> Result<()> {
+    let x = stack.pop()?;
+    let y = stack.pop()?;
+    match (y, x) {
+        (Item::Int(val_y), Item::Int(val_x)) => {
+            stack.push(Item::Int(val_y.checked_sub(val_x).ok_or(Error::Overflow)?))
+        }
+        (Item::Date(date_y), Item::Date(date_x)) => {
+            stack.push(Item::Duration(date_y.since(&date_x)))
+        }
+        (Item::Time(time_y), Item::Time(time_x)) => {
+            stack.push(Item::Duration(time_y.since(&time_x)))
+        }
+        (Item::Date(date), Item::Duration(dur)) => {
+            let back = dur.scale(-1).ok_or(Error::Overflow)?;
+            stack.push(Item::Date(date.add(&back).ok_or(Error::Domain)?))
+        }
+        (Item::Time(time), Item::Duration(dur)) => {
+            let back = dur.scale(-1).ok_or(Error::Overflow)?;
+            stack.push(Item::Time(time.add(&back).ok_or(Error::Domain)?))
+        }
+        (Item::Duration(dur_y), Item::Duration(dur_x)) => {
+            stack.push(Item::Duration(dur_y.sub(&dur_x).ok_or(Error::Overflow)?))
+        }
+        (y @ Item::Complex(_), x) | (y, x @ Item::Complex(_)) => {
+            stack.push(Item::Complex(to_complex(&y)?.sub(&to_complex(&x)?)))
+        }
+        (y @ Item::Quantity(_), x) | (y, x @ Item::Quantity(_)) => {
+            let diff = to_quantity(&y)?.sub(&to_quantity(&x)?)?;
+            push_quantity(stack, diff)
+        }
+        (y, x) => {
+            let (expr_y, expr_x) = symbolic_pair(&y, &x)?;
+            stack.push(Item::Expr(expr_y.sub(expr_x)))
+        }
+    }
+}
+
+// Eq (`=`): compare two items. Numbers of different kinds are converted the same way whichever
+// comes first, and items that can't be compared at all are unequal
+pub fn eq(stack: &mut Stack) -> Result<()> {
+    let x = stack.pop()?;
+    let y = stack.pop()?;
+    let same = match (&y, &x) {
+        (Item::Int(val_y), Item::Int(val_x)) => val_y == val_x,
+        // Expressions are equal if they simplify to the same thing
+        (Item::Expr(_), _) | (_, Item::Expr(_)) => match symbolic_pair(&y, &x) {
+            Ok((expr_y, expr_x)) => expr_y.simplify()? == expr_x.simplify()?,
+            Err(_) => false,
+        },
+        (Item::Complex(_), _) | (_, Item::Complex(_)) => match (to_complex(&y), to_complex(&x)) {
+            (Ok(z_y), Ok(z_x)) => z_y.same_as(&z_x),
+            _ => false,
+        },
+        // Quantities are equal if they are the same amount, whatever their units
+        (Item::Quantity(_), _) | (_, Item::Quantity(_)) => {
+            match (to_quantity(&y), to_quantity(&x)) {
+                (Ok(qty_y), Ok(qty_x)) => qty_y.same_as(&qty_x),
+                _ => false,
             }
-            Op::Swap => {
-                let x = self.pop()?;
-                let y = self.pop()?;
-                self.push(y)?;
-                self.push(x)
+        }
+        // Booleans, matrices, dates and the rest are only equal to the same item
+        _ => y == x,
+    };
+    stack.push(Item::Bool(same))
+}
+
+// Neg (`~`): negate a boolean
+pub fn neg(stack: &mut Stack) -> Result<()> {
+    let x = stack.pop()?;
+    match x {
+        Item::Bool(val_x) => stack.push(Item::Bool(!val_x)),
+        _ => Err(Error::Type(None)),
+    }
+}
+
+// Swap (`<->`): swap the top two items
+pub fn swap(stack: &mut Stack) -> Result<()> {
+    let x = stack.pop()?;
+    let y = stack.pop()?;
+    stack.push(x)?;
+    stack.push(y)
+}
+
+// Rand (`#`): random integer from 0 up to (but not including) the top item, which must be positive
+pub fn random(stack: &mut Stack) -> Result<()> {
+    let x = stack.pop()?;
+    match x {
+        Item::Int(val_x) if val_x > 0 => {
+            let number_in_range = rand::thread_rng().gen_range(0, val_x);
+            stack.push(Item::Int(number_in_range))
+        }
+        Item::Int(_) => Err(Error::Domain),
+        _ => Err(Error::Type(None)),
+    }
+}
+
+// Cond (`?`): if-then-else on the top three items
+pub fn cond(stack: &mut Stack) -> Result<()> {
+    let x = stack.pop()?;
+    let y = stack.pop()?;
+    let z = stack.pop()?;
+    match z {
+        Item::Bool(val_z) => {
+            if val_z {
+                stack.push(y)
+            } else {
+                stack.push(x)
             }
-            Op::Rand => {
-                let x = self.pop()?;
-                match x {
-                    Item::Int(val_x) => {
-                        let random_number = rand::random::<i32>().abs();
-                        let number_in_range = random_number % val_x;
-                        self.push(Item::Int(number_in_range))
-                    }
-                    _ => Err(Error::Type),
-                }
+        }
+        _ => Err(Error::Type(None)),
+    }
+}
+
+// Quit (`quit`): quit the calculator
+pub fn quit(_stack: &mut Stack) -> Result<()> {
+    Err(Error::Quit)
+}
+
+// Mul (`*`): multiply integers and matrices; `check` runs before each row of a matrix product
+pub fn mul(stack: &mut Stack, check: &mut dyn FnMut() -> Result<()>) -> Result<()> {
+    let x = stack.pop()?;
+    let y = stack.pop()?;
+    match (y, x) {
+        (Item::Int(val_y), Item::Int(val_x)) => {
+            let prod = val_y.checked_mul(val_x).ok_or(Error::Overflow)?;
+            stack.push(Item::Int(prod))
+        }
+        (Item::Int(val), Item::Matrix(mat)) | (Item::Matrix(mat), Item::Int(val)) => {
+            stack.push(Item::Matrix(exercise2::mat_scale(val, &mat)?))
+        }
+        (Item::Int(val), Item::Duration(dur)) | (Item::Duration(dur), Item::Int(val)) => {
+            let scaled = dur.scale(i64::from(val)).ok_or(Error::Overflow)?;
+            stack.push(Item::Duration(scaled))
+        }
+        (Item::Matrix(mat_y), Item::Matrix(mat_x)) => stack.push(Item::Matrix(
+            exercise2::mat_mult_checked(&mat_y, &mat_x, check)?,
+        )),
+        (y @ Item::Complex(_), x) | (y, x @ Item::Complex(_)) => {
+            stack.push(Item::Complex(to_complex(&y)?.mul(&to_complex(&x)?)))
+        }
+        (y @ Item::Quantity(_), x) | (y, x @ Item::Quantity(_)) => {
+            let prod = to_quantity(&y)?.mul(&to_quantity(&x)?);
+            push_quantity(stack, prod)
+        }
+        (y, x) => {
+            let (expr_y, expr_x) = symbolic_pair(&y, &x)?;
+            stack.push(Item::Expr(expr_y.mul(expr_x)))
+        }
+    }
+}
+
+// Pow (`pow`): raise an integer or a square matrix to a non-negative power, or a complex number to
+// a complex power; `check` runs before each row of every matrix product
+pub fn pow(stack: &mut Stack, check: &mut dyn FnMut() -> Result<()>) -> Result<()> {
+    let x = stack.pop()?;
+    let y = stack.pop()?;
+    if let (Item::Complex(_), _) | (_, Item::Complex(_)) = (&y, &x) {
+        let power = to_complex(&y)?.pow(&to_complex(&x)?).ok_or(Error::Domain)?;
+        return stack.push(Item::Complex(power));
+    }
+    let exp = match x {
+        Item::Int(val_x) if val_x >= 0 => val_x as u32,
+        Item::Int(_) => return Err(Error::Domain),
+        _ => return Err(Error::Type(None)),
+    };
+    match y {
+        Item::Int(val_y) => {
+            let power = val_y.checked_pow(exp).ok_or(Error::Overflow)?;
+            stack.push(Item::Int(power))
+        }
+        Item::Matrix(mat_y) => stack.push(Item::Matrix(exercise2::mat_pow_checked(
+            &mat_y, exp, check,
+        )?)),
+        Item::Expr(expr_y) => stack.push(Item::Expr(expr_y.pow(exp))),
+        Item::Quantity(qty_y) => push_quantity(stack, qty_y.pow(exp as i32)),
+        _ => Err(Error::Type(None)),
+    }
+}
+
+// Transpose (`transpose`): flip a matrix over its diagonal
+pub fn transpose(stack: &mut Stack) -> Result<()> {
+    let x = stack.pop()?;
+    match x {
+        Item::Matrix(mat_x) => stack.push(Item::Matrix(exercise2::transpose(&mat_x)?)),
+        _ => Err(Error::Type(None)),
+    }
+}
+
+// Largest size `ident` builds: a million entries, so a single token can't exhaust the memory
+pub const MAX_IDENT: i32 = 1000;
+
+// Ident (`ident`): identity matrix of the given size
+pub fn ident(stack: &mut Stack) -> Result<()> {
+    let x = stack.pop()?;
+    match x {
+        Item::Int(val_x) if (0..=MAX_IDENT).contains(&val_x) => {
+            stack.push(Item::Matrix(exercise2::identity(val_x as usize)))
+        }
+        Item::Int(_) => Err(Error::Domain),
+        _ => Err(Error::Type(None)),
+    }
+}
+
+// Det (`det`): determinant of a square matrix; `check` runs before each elimination step
+pub fn det(stack: &mut Stack, check: &mut dyn FnMut() -> Result<()>) -> Result<()> {
+    let x = stack.pop()?;
+    match x {
+        Item::Matrix(mat_x) => {
+            stack.push(Item::Int(exercise2::determinant_checked(&mat_x, check)?))
+        }
+        _ => Err(Error::Type(None)),
+    }
+}
+
+// IsPrime (`prime?`): check if an integer is prime
+pub fn is_prime(stack: &mut Stack) -> Result<()> {
+    let n = stack.pop_int()?;
+    let prime = u64::try_from(n).is_ok_and(exercise3::is_prime);
+    stack.push(Item::Bool(prime))
+}
+
+// Factor (`factor`): prime factors of a positive integer, as a single-row matrix
+pub fn factor(stack: &mut Stack) -> Result<()> {
+    let n = stack.pop_int()?;
+    if n <= 0 {
+        return Err(Error::Domain);
+    }
+    stack.push_row(exercise3::factor(n as u64).into_iter())
+}
+
+// Gcd (`gcd`): greatest common divisor
+pub fn gcd(stack: &mut Stack) -> Result<()> {
+    let x = stack.pop_int()?;
+    let y = stack.pop_int()?;
+    let gcd = exercise3::gcd(u64::from(y.unsigned_abs()), u64::from(x.unsigned_abs()));
+    stack.push_nat(gcd)
+}
+
+// Lcm (`lcm`): least common multiple
+pub fn lcm(stack: &mut Stack) -> Result<()> {
+    let x = stack.pop_int()?;
+    let y = stack.pop_int()?;
+    let lcm = exercise3::lcm(u64::from(y.unsigned_abs()), u64::from(x.unsigned_abs()));
+    stack.push_nat(lcm.ok_or(Error::Overflow)?)
+}
+
+// ModPow (`modpow`): `b e m modpow` is b^e mod m
+pub fn mod_pow(stack: &mut Stack) -> Result<()> {
+    let modulus = stack.pop_modulus()?;
+    let exp = stack.pop_nat()?;
+    let base = i64::from(stack.pop_int()?).rem_euclid(modulus as i64) as u64;
+    stack.push_nat(exercise3::modpow(base, exp, modulus))
+}
+
+// ModInv (`modinv`): `a m modinv` is the inverse of a mod m
+pub fn mod_inv(stack: &mut Stack) -> Result<()> {
+    let modulus = stack.pop_modulus()?;
+    let a = i64::from(stack.pop_int()?).rem_euclid(modulus as i64) as u64;
+    let inverse = exercise3::modinv(a, modulus).ok_or(Error::Domain)?;
+    stack.push_nat(inverse)
+}
+
+// Totient (`totient`): Euler's totient function
+pub fn totient(stack: &mut Stack) -> Result<()> {
+    let n = stack.pop_nat()?;
+    stack.push_nat(exercise3::totient(n))
+}
+
+// Throw (`throw`): raise an error; a quotation's tokens or the item itself become the message
+pub fn throw(stack: &mut Stack) -> Result<()> {
+    let msg = match stack.pop()? {
+        Item::Quote(toks) => toks.join(" "),
+        Item::Error(msg) => msg,
+        item => item.to_string(),
+    };
+    Err(Error::Thrown(msg))
+}
+
+// View an integer or expression item as an expression
+fn to_expr(item: &Item) -> Result<Expr> {
+    match item {
+        Item::Int(val) => Ok(Expr::Num(*val)),
+        Item::Expr(expr) => Ok(expr.clone()),
+        _ => Err(Error::Type(None)),
+    }
+}
+
+// Both operands of an arithmetic op as expressions, if at least one of them is symbolic
+fn symbolic_pair(y: &Item, x: &Item) -> Result<(Expr, Expr)> {
+    match (y, x) {
+        (Item::Expr(_), _) | (_, Item::Expr(_)) => Ok((to_expr(y)?, to_expr(x)?)),
+        _ => Err(Error::Type(None)),
+    }
+}
+
+// Push a simplified expression, which is just an integer if no unknowns are left
+fn push_simplified(stack: &mut Stack, expr: Expr) -> Result<()> {
+    match expr.simplify()? {
+        Expr::Num(val) => stack.push(Item::Int(val)),
+        expr => stack.push(Item::Expr(expr)),
+    }
+}
+
+// Pop an unknown, like `x`, off the Stack
+fn pop_var(stack: &mut Stack) -> Result<String> {
+    match stack.pop()? {
+        Item::Expr(Expr::Var(name)) => Ok(name),
+        _ => Err(Error::Type(None)),
+    }
+}
+
+// Simplify (`simplify`): bring an expression into normal form
+pub fn simplify(stack: &mut Stack) -> Result<()> {
+    let expr = to_expr(&stack.pop()?)?;
+    push_simplified(stack, expr)
+}
+
+// Subst (`subst`): `e x v subst` replaces the unknown x in e with v
+pub fn subst(stack: &mut Stack) -> Result<()> {
+    let val = to_expr(&stack.pop()?)?;
+    let name = pop_var(stack)?;
+    let expr = to_expr(&stack.pop()?)?;
+    push_simplified(stack, expr.subst(&name, &val)?)
+}
+
+// Derive (`d/dx`): `e x d/dx` differentiates e with respect to the unknown x
+pub fn derive(stack: &mut Stack) -> Result<()> {
+    let name = pop_var(stack)?;
+    let expr = to_expr(&stack.pop()?)?;
+    push_simplified(stack, expr.derive(&name)?)
+}
+
+// View an integer or quantity item as a quantity; integers have no unit
+fn to_quantity(item: &Item) -> Result<Quantity> {
+    match item {
+        Item::Int(val) => Ok(Quantity::new(f64::from(*val), Unit::none())),
+        Item::Quantity(qty) => Ok(qty.clone()),
+        _ => Err(Error::Type(None)),
+    }
+}
+
+// Div (`/`): divide integers (rounding towards zero) or quantities
+pub fn div(stack: &mut Stack) -> Result<()> {
+    let x = stack.pop()?;
+    let y = stack.pop()?;
+    match (y, x) {
+        (Item::Int(_), Item::Int(0)) => Err(Error::Domain),
+        (Item::Int(val_y), Item::Int(val_x)) => {
+            stack.push(Item::Int(val_y.checked_div(val_x).ok_or(Error::Overflow)?))
+        }
+        (y @ Item::Complex(_), x) | (y, x @ Item::Complex(_)) => {
+            let (z_y, z_x) = (to_complex(&y)?, to_complex(&x)?);
+            if z_x.is_zero() {
+                return Err(Error::Domain);
             }
-            Op::Cond => {
-                let x = self.pop()?;
-                let y = self.pop()?;
-                let z = self.pop()?;
-                match z {
-                    Item::Bool(val_z) => {
-                        if val_z {
-                            self.push(y)
-                        } else {
-                            self.push(x)
-                        }
-                    }
-                    _ => Err(Error::Type),
-                }
+            stack.push(Item::Complex(z_y.div(&z_x)))
+        }
+        (y, x) => {
+            let (qty_y, qty_x) = (to_quantity(&y)?, to_quantity(&x)?);
+            if qty_x.value == 0.0 {
+                return Err(Error::Domain);
             }
-            Op::Quit => Err(Error::Quit),
+            push_quantity(stack, qty_y.div(&qty_x))
         }
     }
 }
+
+// Unit (`m`, `kg`, `kWh`, ...): attach a unit to a number, or multiply a quantity's unit by it
+pub fn apply_unit(stack: &mut Stack, name: &str) -> Result<()> {
+    let unit = Unit::named(name).ok_or(Error::Syntax)?;
+    let qty = to_quantity(&stack.pop()?)?;
+    push_quantity(stack, Quantity::new(qty.value, qty.unit.mul(&unit)))
+}
+
+// To (`to`): `q [ unit ] to` converts a quantity to another unit of the same dimension
+pub fn to(stack: &mut Stack) -> Result<()> {
+    let unit = Unit::parse(&stack.pop_quote()?).ok_or(Error::Syntax)?;
+    let qty = to_quantity(&stack.pop()?)?;
+    push_quantity(stack, qty.to(&unit)?)
+}
+
+// View a number as a complex number. Quantities have to be unitless: a complex number has no unit
+fn to_complex(item: &Item) -> Result<Complex> {
+    match item {
+        Item::Complex(z) => Ok(*z),
+        Item::Int(val) => Ok(Complex::new(f64::from(*val), 0.0)),
+        Item::Quantity(qty) if qty.unit.is_none() => Ok(Complex::new(qty.value, 0.0)),
+        Item::Quantity(qty) => Err(Error::from(DimensionMismatch(
+            Unit::base(&qty.unit.dims()),
+            Unit::none(),
+        ))),
+        _ => Err(Error::Type(None)),
+    }
+}
+
+// Push a real number as a quantity without a unit
+fn push_real(stack: &mut Stack, val: f64) -> Result<()> {
+    push_quantity(stack, Quantity::new(val, Unit::none()))
+}
+
+// Push a quantity, unless its value overflowed to infinity or isn't a number at all, like the
+// difference of two infinities
+fn push_quantity(stack: &mut Stack, qty: Quantity) -> Result<()> {
+    if qty.value.is_nan() {
+        return Err(Error::Domain);
+    }
+    if qty.value.is_infinite() {
+        return Err(Error::Overflow);
+    }
+    stack.push(Item::Quantity(qty))
+}
+
+// Conj (`conj`): complex conjugate
+pub fn conj(stack: &mut Stack) -> Result<()> {
+    let z = to_complex(&stack.pop()?)?;
+    stack.push(Item::Complex(z.conj()))
+}
+
+// Abs (`abs`): magnitude of a complex number, or absolute value of an integer or quantity
+pub fn abs(stack: &mut Stack) -> Result<()> {
+    match stack.pop()? {
+        Item::Int(val) => stack.push(Item::Int(val.checked_abs().ok_or(Error::Overflow)?)),
+        Item::Quantity(qty) => stack.push(Item::Quantity(Quantity::new(qty.value.abs(), qty.unit))),
+        x => push_real(stack, to_complex(&x)?.abs()),
+    }
+}
+
+// Arg (`arg`): argument of a complex number, in radians
+pub fn arg(stack: &mut Stack) -> Result<()> {
+    let z = to_complex(&stack.pop()?)?;
+    push_real(stack, z.arg())
+}
+
+// Polar (`polar`): `z polar` leaves the magnitude and the argument of z
+pub fn polar(stack: &mut Stack) -> Result<()> {
+    let z = to_complex(&stack.pop()?)?;
+    push_real(stack, z.abs())?;
+    push_real(stack, z.arg())
+}
+
+// Rect (`rect`): `r theta rect` makes the complex number with magnitude r and argument theta
+pub fn rect(stack: &mut Stack) -> Result<()> {
+    let theta = to_complex(&stack.pop()?)?;
+    let r = to_complex(&stack.pop()?)?;
+    if theta.im != 0.0 || r.im != 0.0 {
+        return Err(Error::Type(None));
+    }
+    stack.push(Item::Complex(Complex::from_polar(r.re, theta.re)))
+}
+
+// Pop a date off the Stack
+fn pop_date(stack: &mut Stack) -> Result<Date> {
+    match stack.pop()? {
+        Item::Date(date) => Ok(date),
+        _ => Err(Error::Type(None)),
+    }
+}
+
+// Push a count from the calendar; they are all small enough for an integer Item
+fn push_count(stack: &mut Stack, count: i64) -> Result<()> {
+    let count = i32::try_from(count).map_err(|_| Error::Overflow)?;
+    stack.push(Item::Int(count))
+}
+
+// Today (`today`): today's date
+pub fn today(stack: &mut Stack) -> Result<()> {
+    stack.push(Item::Date(Date::today()))
+}
+
+// Weekday (`weekday`): day of the week of a date, from 1 for Monday to 7 for Sunday
+pub fn weekday(stack: &mut Stack) -> Result<()> {
+    let date = pop_date(stack)?;
+    push_count(stack, date.weekday())
+}
+
+// Week (`week`): ISO week number of a date
+pub fn week(stack: &mut Stack) -> Result<()> {
+    let date = pop_date(stack)?;
+    push_count(stack, date.week())
+}
+
+// AddBusinessDays (`addbdays`): `d n addbdays` moves a date n business days on, skipping weekends
+pub fn add_business_days(stack: &mut Stack) -> Result<()> {
+    let count = stack.pop_int()?;
+    let date = pop_date(stack)?;
+    let later = date.add_business_days(i64::from(count));
+    stack.push(Item::Date(later.ok_or(Error::Domain)?))
+}
+
+// BusinessDays (`bdays`): `d1 d2 bdays` counts the business days from d1 up to d2
+pub fn business_days(stack: &mut Stack) -> Result<()> {
+    let to = pop_date(stack)?;
+    let from = pop_date(stack)?;
+    push_count(stack, to.business_days_since(&from))
+}
@@ -0,0 +1,537 @@
+/*
+ * Reverse Polish Notation: server.rs
+ * See `rpn.md` for the overview.
+ */
+
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::json::Value;
+use super::parser;
+use super::rpn;
+use super::session::{Budget, Session};
+use super::threadpool::ThreadPool;
+
+/// Longest request line we accept, in bytes.
+const MAX_LINE: usize = 64 * 1024;
+
+/// Native stack for each worker, with room for the deepest calls and expressions a budget allows.
+const WORKER_STACK: usize = 64 << 20;
+
+/// How long to wait before accepting again after a failed accept, say because we ran out of file
+/// descriptors.
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+/// How the server treats its clients.
+#[derive(Clone, Copy, Debug)]
+pub struct Config {
+    /// Number of clients served at once; further connections wait for a free worker.
+    pub workers: usize,
+    /// Close connections that take longer than this to send a whole line.
+    pub idle_timeout: Option<Duration>,
+    /// Limits for every line a client sends.
+    pub budget: Budget,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            workers: 8,
+            idle_timeout: Some(Duration::from_secs(300)),
+            budget: Budget {
+                max_steps: Some(10_000_000),
+                timeout: Some(Duration::from_secs(5)),
+                ..Budget::default()
+            },
+        }
+    }
+}
+
+/// A client connection, over TCP or a Unix socket.
+trait Connection: Read + Write + Send + 'static {
+    fn set_idle_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+}
+
+impl Connection for TcpStream {
+    fn set_idle_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.set_read_timeout(timeout)
+    }
+}
+
+#[cfg(unix)]
+impl Connection for UnixStream {
+    fn set_idle_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.set_read_timeout(timeout)
+    }
+}
+
+/// A connection whose reads fail once a deadline has passed, so that a client can't hold on to a
+/// worker by sending one byte at a time.
+struct Deadline<C> {
+    conn: C,
+    timeout: Option<Duration>,
+    deadline: Option<Instant>,
+}
+
+impl<C: Connection> Deadline<C> {
+    fn new(conn: C, timeout: Option<Duration>) -> Self {
+        Deadline {
+            conn,
+            timeout,
+            deadline: None,
+        }
+    }
+
+    /// Start the clock for the next line.
+    fn restart(&mut self) {
+        self.deadline = self.timeout.map(|timeout| Instant::now() + timeout);
+    }
+}
+
+impl<C: Connection> Read for Deadline<C> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if let Some(deadline) = self.deadline {
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                return Err(io::ErrorKind::TimedOut.into());
+            }
+            self.conn.set_idle_timeout(Some(left))?;
+        }
+        self.conn.read(buf)
+    }
+}
+
+impl<C: Connection> Write for Deadline<C> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.conn.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.conn.flush()
+    }
+}
+
+enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener, PathBuf),
+}
+
+impl Drop for Listener {
+    // Unix sockets leave a file behind, which would stop the next server from binding
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        {
+            if let Listener::Unix(_, path) = self {
+                let _ = fs::remove_file(path);
+            }
+        }
+    }
+}
+
+/// A calculator server, which gives each client its own session.
+///
+/// Clients speak a line protocol: each line they send is evaluated like a line at the REPL, and
+/// the server answers with one line of JSON. A successful line gets `{"ok":true,"stack":[...]}`
+/// with the whole stack, bottom first; a failed line leaves the stack as it was and gets
+/// `{"ok":false,"error":{"kind":...,"message":...},"stack":[...]}`.
+pub struct Server {
+    listener: Listener,
+    config: Config,
+    pool: ThreadPool,
+}
+
+impl Server {
+    /// Listen on `addr`, which is either `HOST:PORT` for TCP or `unix:PATH` for a Unix socket.
+    pub fn bind(addr: &str, config: Config) -> io::Result<Self> {
+        let listener = match addr.strip_prefix("unix:") {
+            #[cfg(unix)]
+            Some(path) => Listener::Unix(UnixListener::bind(path)?, PathBuf::from(path)),
+            #[cfg(not(unix))]
+            Some(_) => return Err(io::Error::other("Unix sockets are not supported here")),
+            None => Listener::Tcp(TcpListener::bind(addr)?),
+        };
+
+        Ok(Server {
+            listener,
+            config,
+            pool: ThreadPool::new(config.workers.max(1), WORKER_STACK),
+        })
+    }
+
+    /// The address clients should connect to, in the same form `bind` takes.
+    pub fn local_addr(&self) -> io::Result<String> {
+        match &self.listener {
+            Listener::Tcp(listener) => Ok(listener.local_addr()?.to_string()),
+            #[cfg(unix)]
+            Listener::Unix(_, path) => Ok(format!("unix:{}", path.display())),
+        }
+    }
+
+    /// Accept clients forever, handing each connection to the worker pool. A connection we fail
+    /// to accept is logged and skipped; it doesn't stop the server.
+    pub fn run(&mut self) -> io::Result<()> {
+        loop {
+            let accepted = match &self.listener {
+                Listener::Tcp(listener) => {
+                    listener.accept().map(|(stream, _)| self.dispatch(stream))
+                }
+                #[cfg(unix)]
+                Listener::Unix(listener, _) => {
+                    listener.accept().map(|(stream, _)| self.dispatch(stream))
+                }
+            };
+            if let Err(err) = accepted {
+                eprintln!("Failed to accept a connection: {}", err);
+                thread::sleep(ACCEPT_BACKOFF);
+            }
+        }
+    }
+
+    fn dispatch<C: Connection>(&mut self, conn: C) {
+        let config = self.config;
+        self.pool.execute(move || {
+            // One client going away doesn't concern anyone else
+            let _ = serve_client(conn, config);
+        });
+    }
+}
+
+/// Talk to one client until it hangs up, quits, or goes idle.
+fn serve_client<C: Connection>(conn: C, config: Config) -> io::Result<()> {
+    let mut session = Session::new();
+    session.set_budget(config.budget);
+
+    let mut reader = BufReader::new(Deadline::new(conn, config.idle_timeout));
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        reader.get_mut().restart();
+        match (&mut reader)
+            .take(MAX_LINE as u64)
+            .read_until(b'\n', &mut line)
+        {
+            Ok(0) => return Ok(()),
+            Ok(_) if !line.ends_with(b"\n") && line.len() >= MAX_LINE => {
+                let reply = failure("Syntax", "line too long", &session);
+                return writeln!(reader.get_mut(), "{}", reply);
+            }
+            Ok(_) => (),
+            Err(err)
+                if matches!(
+                    err.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                let reply = failure("Idle", "idle timeout", &session);
+                return writeln!(reader.get_mut(), "{}", reply);
+            }
+            Err(err) => return Err(err),
+        }
+
+        let (reply, done) = match std::str::from_utf8(&line) {
+            Ok(line) => respond(&mut session, line),
+            Err(_) => (failure("Syntax", "invalid UTF-8", &session), false),
+        };
+        writeln!(reader.get_mut(), "{}", reply)?;
+        if done {
+            return Ok(());
+        }
+    }
+}
+
+/// Evaluate one request line, returning the reply and whether the client asked to quit. A failed
+/// line leaves every stack as it was, like in the REPL.
+fn respond(session: &mut Session, line: &str) -> (Value, bool) {
+    let saved = session.stacks().clone();
+    match parser::evaluate_line(session, line) {
+        Ok(()) => (success(session, false), false),
+        Err(rpn::Error::Quit) => (success(session, true), true),
+        Err(err) => {
+            *session.stacks_mut() = saved;
+            let reply = Value::object(vec![
+                ("ok", Value::Bool(false)),
+                ("error", Value::from(&err)),
+                ("stack", stack_value(session)),
+            ]);
+            (reply, false)
+        }
+    }
+}
+
+fn stack_value(session: &Session) -> Value {
+    Value::Array(session.stack().items().iter().map(Value::from).collect())
+}
+
+fn success(session: &Session, closed: bool) -> Value {
+    let mut fields = vec![("ok", Value::Bool(true)), ("stack", stack_value(session))];
+    if closed {
+        fields.push(("closed", Value::Bool(true)));
+    }
+    Value::object(fields)
+}
+
+fn failure(kind: &str, message: &str, session: &Session) -> Value {
+    Value::object(vec![
+        ("ok", Value::Bool(false)),
+        (
+            "error",
+            Value::object(vec![
+                ("kind", Value::Str(kind.to_string())),
+                ("message", Value::Str(message.to_string())),
+            ]),
+        ),
+        ("stack", stack_value(session)),
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    // Start a server on an ephemeral port, returning its address
+    fn start(config: Config) -> String {
+        start_at("127.0.0.1:0", config)
+    }
+
+    fn start_at(addr: &str, config: Config) -> String {
+        let mut server = Server::bind(addr, config).unwrap();
+        let addr = server.local_addr().unwrap();
+        thread::spawn(move || server.run());
+        addr
+    }
+
+    struct Client<C: Connection> {
+        reader: BufReader<C>,
+    }
+
+    impl<C: Connection> Client<C> {
+        fn new(conn: C) -> Self {
+            conn.set_idle_timeout(Some(Duration::from_secs(10)))
+                .unwrap();
+            Client {
+                reader: BufReader::new(conn),
+            }
+        }
+
+        fn ask(&mut self, line: &str) -> String {
+            writeln!(self.reader.get_mut(), "{}", line).unwrap();
+            self.reply()
+        }
+
+        fn reply(&mut self) -> String {
+            let mut reply = String::new();
+            self.reader.read_line(&mut reply).unwrap();
+            reply.trim_end().to_string()
+        }
+    }
+
+    fn connect(addr: &str) -> Client<TcpStream> {
+        Client::new(TcpStream::connect(addr).unwrap())
+    }
+
+    #[test]
+    fn test_protocol() {
+        let addr = start(Config::default());
+        let mut client = connect(&addr);
+
+        assert_eq!(client.ask("1 2 +"), r#"{"ok":true,"stack":[3]}"#);
+        assert_eq!(
+            client.ask("true [ 1 ] [[1 2]]"),
+            r#"{"ok":true,"stack":[3,true,"[ 1 ]","[[1 2]]"]}"#
+        );
+
+        // A failed line reports the error and leaves the stack alone
+        assert_eq!(
+            client.ask("+"),
+            r#"{"ok":false,"error":{"kind":"Type","message":"type mismatch"},"stack":[3,true,"[ 1 ]","[[1 2]]"]}"#
+        );
+        assert_eq!(
+            client.ask("@"),
+            r#"{"ok":false,"error":{"kind":"Syntax","message":"syntax error"},"stack":[3,true,"[ 1 ]","[[1 2]]"]}"#
+        );
+        assert_eq!(
+            client.ask("quit"),
+            r#"{"ok":true,"stack":[3,true,"[ 1 ]","[[1 2]]"],"closed":true}"#
+        );
+        assert_eq!(client.reply(), "");
+    }
+
+    #[test]
+    fn test_failure_restores_every_stack() {
+        let mut session = Session::new();
+        session.stacks_mut().create("other").unwrap();
+        let (reply, _) = respond(&mut session, "1 2 >s other true +");
+        assert_eq!(reply.get("ok"), Some(&Value::Bool(false)));
+        assert_eq!(session.stacks().depths(), vec![("main", 0), ("other", 0)]);
+    }
+
+    #[test]
+    fn test_sessions_isolated() {
+        let addr = start(Config::default());
+        let mut alice = connect(&addr);
+        let mut bob = connect(&addr);
+
+        assert_eq!(
+            alice.ask(": double 2 * ; 21 ->x"),
+            r#"{"ok":true,"stack":[]}"#
+        );
+        assert_eq!(alice.ask("x double"), r#"{"ok":true,"stack":[42]}"#);
+        assert_eq!(bob.ask("1"), r#"{"ok":true,"stack":[1]}"#);
+        // Alice's variable and word are just unknowns to Bob
+        assert_eq!(
+            bob.ask("x 1 double"),
+            r#"{"ok":true,"stack":[1,"x",1,"double"]}"#
+        );
+        assert_eq!(alice.ask("x"), r#"{"ok":true,"stack":[42,21]}"#);
+    }
+
+    #[test]
+    fn test_limits() {
+        let addr = start(Config {
+            budget: Budget {
+                max_steps: Some(1000),
+                ..Budget::default()
+            },
+            ..Config::default()
+        });
+        let mut client = connect(&addr);
+
+        assert_eq!(
+            client.ask(": loop [ ] 10000 times ; 7 loop"),
+            r#"{"ok":false,"error":{"kind":"Limit","message":"step limit exceeded"},"stack":[]}"#
+        );
+        assert_eq!(client.ask("7"), r#"{"ok":true,"stack":[7]}"#);
+
+        // Nothing a client sends can take the server down with it
+        assert_eq!(
+            client.ask("x [ 1 + ] 50000 times"),
+            r#"{"ok":false,"error":{"kind":"Limit","message":"expression depth limit exceeded"},"stack":[7]}"#
+        );
+        assert_eq!(
+            client.ask("2147483647 ident"),
+            r#"{"ok":false,"error":{"kind":"Domain","message":"argument out of range"},"stack":[7]}"#
+        );
+        assert_eq!(client.ask("1 +"), r#"{"ok":true,"stack":[8]}"#);
+    }
+
+    #[test]
+    fn test_slow_token() {
+        // A single token can take much longer than the time limit, unless it checks as it goes
+        let addr = start(Config {
+            budget: Budget {
+                timeout: Some(Duration::from_millis(200)),
+                ..Budget::default()
+            },
+            ..Config::default()
+        });
+        let mut client = connect(&addr);
+
+        let started = Instant::now();
+        assert_eq!(
+            client.ask("1000 ident 2147483647 pow"),
+            r#"{"ok":false,"error":{"kind":"Limit","message":"time limit exceeded"},"stack":[]}"#
+        );
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+
+    #[test]
+    fn test_idle_timeout() {
+        let addr = start(Config {
+            idle_timeout: Some(Duration::from_millis(100)),
+            ..Config::default()
+        });
+        let mut client = connect(&addr);
+
+        assert_eq!(client.ask("5"), r#"{"ok":true,"stack":[5]}"#);
+        thread::sleep(Duration::from_millis(300));
+        assert_eq!(
+            client.reply(),
+            r#"{"ok":false,"error":{"kind":"Idle","message":"idle timeout"},"stack":[5]}"#
+        );
+        assert_eq!(client.reply(), "");
+    }
+
+    #[test]
+    fn test_slow_line() {
+        // The timeout covers the whole line, not each byte of it: a byte every 100ms doesn't keep
+        // the connection open past 300ms
+        let addr = start(Config {
+            idle_timeout: Some(Duration::from_millis(300)),
+            ..Config::default()
+        });
+        let mut client = connect(&addr);
+
+        let started = Instant::now();
+        for _ in 0..3 {
+            write!(client.reader.get_mut(), "1 ").unwrap();
+            thread::sleep(Duration::from_millis(100));
+        }
+        assert_eq!(
+            client.reply(),
+            r#"{"ok":false,"error":{"kind":"Idle","message":"idle timeout"},"stack":[]}"#
+        );
+        assert!(started.elapsed() < Duration::from_millis(450));
+        assert_eq!(client.reply(), "");
+    }
+
+    #[test]
+    fn test_invalid_utf8() {
+        let addr = start(Config::default());
+        let mut client = connect(&addr);
+
+        client.reader.get_mut().write_all(b"1 \xff\n").unwrap();
+        assert_eq!(
+            client.reply(),
+            r#"{"ok":false,"error":{"kind":"Syntax","message":"invalid UTF-8"},"stack":[]}"#
+        );
+        assert_eq!(client.ask("1"), r#"{"ok":true,"stack":[1]}"#);
+    }
+
+    #[test]
+    fn test_worker_pool() {
+        // With two workers, a third client waits until one of the first two leaves
+        let addr = start(Config {
+            workers: 2,
+            ..Config::default()
+        });
+        let mut first = connect(&addr);
+        let mut second = connect(&addr);
+        assert_eq!(first.ask("1"), r#"{"ok":true,"stack":[1]}"#);
+        assert_eq!(second.ask("2"), r#"{"ok":true,"stack":[2]}"#);
+
+        let mut third = connect(&addr);
+        writeln!(third.reader.get_mut(), "3").unwrap();
+        drop(first);
+        assert_eq!(third.reply(), r#"{"ok":true,"stack":[3]}"#);
+    }
+
+    #[test]
+    fn test_long_line() {
+        let addr = start(Config::default());
+        let mut client = connect(&addr);
+        let line = " ".repeat(MAX_LINE);
+        client.reader.get_mut().write_all(line.as_bytes()).unwrap();
+        assert!(client.reply().contains("line too long"));
+        assert_eq!(client.reply(), "");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_unix_socket() {
+        let path = std::env::temp_dir().join(format!("hw4-test-{}.sock", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let addr = start_at(&format!("unix:{}", path.display()), Config::default());
+
+        let mut client = Client::new(UnixStream::connect(&path).unwrap());
+        assert_eq!(client.ask("6 7 *"), r#"{"ok":true,"stack":[42]}"#);
+        assert_eq!(addr, format!("unix:{}", path.display()));
+    }
+}
@@ -0,0 +1,564 @@
+/*
+ * Reverse Polish Notation: session.rs
+ * See `rpn.md` for the overview.
+ */
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use super::ops::Registry;
+use super::parser;
+use super::rpn::{self, Limit};
+use super::symbolic::{self, Expr};
+
+/// Limits on how much work a single line of input may do.
+///
+/// Every token evaluated counts as a step, including the tokens inside quotations and words, and
+/// so does every call of a quotation or word. The size of an item is the number of entries of a
+/// matrix, tokens of a quotation or nodes of an expression.
+/// `None` means no limit. The call depth, item size and expression depth are always bounded:
+/// nested calls and walking nested expressions use up some of the (fixed-size) native stack, and
+/// an expression can double in size with every step. The expression depth can't go past
+/// `symbolic::MAX_DEPTH`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Budget {
+    pub max_steps: Option<u64>,
+    pub max_stack: Option<usize>,
+    pub max_calls: usize,
+    pub max_item: usize,
+    pub max_depth: usize,
+    pub timeout: Option<Duration>,
+}
+
+impl Default for Budget {
+    fn default() -> Self {
+        Budget {
+            max_steps: None,
+            max_stack: Some(1 << 20),
+            max_calls: 256,
+            max_item: 1 << 20,
+            max_depth: 256,
+            timeout: None,
+        }
+    }
+}
+
+impl fmt::Display for Budget {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fn show<T: fmt::Display>(limit: Option<T>) -> String {
+            limit.map_or(String::from("off"), |val| val.to_string())
+        }
+
+        write!(
+            f,
+            "steps {}, stack {}, calls {}, item {}, depth {}, time {}",
+            show(self.max_steps),
+            show(self.max_stack),
+            self.max_calls,
+            self.max_item,
+            self.max_depth,
+            show(self.timeout.map(|t| format!("{}s", t.as_secs_f64())))
+        )
+    }
+}
+
+/// The name of the stack every session starts with.
+pub const MAIN_STACK: &str = "main";
+
+/// The named stacks of a session. One of them is active, and that is the one operations work on;
+/// the others only change when items are moved to or from them.
+#[derive(Clone, Debug)]
+pub struct Stacks {
+    active: String,
+    current: rpn::Stack,
+    others: BTreeMap<String, rpn::Stack>,
+}
+
+impl Stacks {
+    fn new() -> Self {
+        Stacks {
+            active: MAIN_STACK.to_string(),
+            current: rpn::Stack::new(),
+            others: BTreeMap::new(),
+        }
+    }
+
+    /// The name of the active stack.
+    pub fn active(&self) -> &str {
+        &self.active
+    }
+
+    /// The names and depths of all the stacks, in order of name.
+    pub fn depths(&self) -> Vec<(&str, usize)> {
+        let mut depths: Vec<_> = self
+            .others
+            .iter()
+            .map(|(name, stack)| (name.as_str(), stack.len()))
+            .collect();
+        depths.push((&self.active, self.current.len()));
+        depths.sort_unstable();
+        depths
+    }
+
+    /// The number of items on all the stacks together.
+    pub fn total_len(&self) -> usize {
+        self.current.len() + self.others.values().map(rpn::Stack::len).sum::<usize>()
+    }
+
+    /// Make a new, empty stack. The name has to be a valid word name and not already taken.
+    pub fn create(&mut self, name: &str) -> rpn::Result<()> {
+        parser::check_name(name)?;
+        if name == self.active || self.others.contains_key(name) {
+            return Err(rpn::Error::Syntax);
+        }
+        self.others.insert(name.to_string(), rpn::Stack::new());
+        Ok(())
+    }
+
+    /// Make another stack the active one.
+    pub fn switch(&mut self, name: &str) -> rpn::Result<()> {
+        if name == self.active {
+            return Ok(());
+        }
+        let stack = self.others.remove(name).ok_or(rpn::Error::Syntax)?;
+        let old = std::mem::replace(&mut self.current, stack);
+        let old_name = std::mem::replace(&mut self.active, name.to_string());
+        self.others.insert(old_name, old);
+        Ok(())
+    }
+
+    // The stack with the given name, active or not
+    fn get_mut(&mut self, name: &str) -> rpn::Result<&mut rpn::Stack> {
+        if name == self.active {
+            return Ok(&mut self.current);
+        }
+        self.others.get_mut(name).ok_or(rpn::Error::Syntax)
+    }
+
+    /// Move the top item of the active stack onto the named one (`>s name`).
+    pub fn move_to(&mut self, name: &str) -> rpn::Result<()> {
+        self.get_mut(name)?;
+        let item = self.current.pop()?;
+        self.get_mut(name)?.push(item)
+    }
+
+    /// Move the top item of the named stack onto the active one (`s> name`).
+    pub fn move_from(&mut self, name: &str) -> rpn::Result<()> {
+        let item = self.get_mut(name)?.pop()?;
+        self.current.push(item)
+    }
+}
+
+/// Everything one user of the calculator works with: their stacks and variables, the operations
+/// and words they can call on it, and the limits on how long a program may run.
+pub struct Session {
+    stacks: Stacks,
+    registry: Registry,
+    words: BTreeMap<String, Arc<Vec<String>>>,
+    variables: BTreeMap<String, rpn::Item>,
+    budget: Budget,
+
+    // Usage so far in the current evaluation
+    steps: u64,
+    calls: usize,
+    started: Instant,
+    interrupt: Arc<AtomicBool>,
+}
+
+impl Session {
+    /// Make a new session with an empty stack and the built-in operations.
+    pub fn new() -> Self {
+        Session::with_registry(Registry::builtins())
+    }
+
+    /// Make a new session with an empty stack and a custom set of operations.
+    pub fn with_registry(registry: Registry) -> Self {
+        Session {
+            stacks: Stacks::new(),
+            registry,
+            words: BTreeMap::new(),
+            variables: BTreeMap::new(),
+            budget: Budget::default(),
+            steps: 0,
+            calls: 0,
+            started: Instant::now(),
+            interrupt: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// The active stack.
+    pub fn stack(&self) -> &rpn::Stack {
+        &self.stacks.current
+    }
+
+    pub fn stack_mut(&mut self) -> &mut rpn::Stack {
+        &mut self.stacks.current
+    }
+
+    pub fn stacks(&self) -> &Stacks {
+        &self.stacks
+    }
+
+    pub fn stacks_mut(&mut self) -> &mut Stacks {
+        &mut self.stacks
+    }
+
+    pub fn registry(&self) -> &Registry {
+        &self.registry
+    }
+
+    pub fn registry_mut(&mut self) -> &mut Registry {
+        &mut self.registry
+    }
+
+    pub fn budget(&self) -> Budget {
+        self.budget
+    }
+
+    pub fn set_budget(&mut self, budget: Budget) {
+        self.budget = budget;
+    }
+
+    /// A flag that stops the running program when set, for example from a Ctrl-C handler.
+    pub fn interrupt_handle(&self) -> Arc<AtomicBool> {
+        Arc::clone(&self.interrupt)
+    }
+
+    /// Define (or redefine) a word as a sequence of tokens.
+    pub fn define(&mut self, name: &str, body: Vec<String>) {
+        self.words.insert(name.to_string(), Arc::new(body));
+    }
+
+    /// Look up the body of a user-defined word.
+    pub fn word(&self, name: &str) -> Option<&[String]> {
+        self.words.get(name).map(|body| body.as_slice())
+    }
+
+    /// Store an item in a variable, replacing its old value.
+    pub fn set_var(&mut self, name: &str, item: rpn::Item) {
+        self.variables.insert(name.to_string(), item);
+    }
+
+    /// Look up the value of a variable.
+    pub fn var(&self, name: &str) -> Option<&rpn::Item> {
+        self.variables.get(name)
+    }
+
+    /// Start metering a new top-level evaluation.
+    pub fn begin(&mut self) {
+        self.steps = 0;
+        self.calls = 0;
+        self.started = Instant::now();
+        self.interrupt.store(false, Ordering::SeqCst);
+    }
+
+    /// Account for evaluating one more token, failing if the program is over budget or has been
+    /// interrupted.
+    pub fn step(&mut self) -> rpn::Result<()> {
+        if self.interrupt.swap(false, Ordering::SeqCst) {
+            return Err(rpn::Error::Limit(Limit::Interrupted));
+        }
+
+        self.steps += 1;
+        if self.budget.max_steps.is_some_and(|max| self.steps > max) {
+            return Err(rpn::Error::Limit(Limit::Steps));
+        }
+        if let Some(timeout) = self.budget.timeout {
+            if self.started.elapsed() > timeout {
+                return Err(rpn::Error::Limit(Limit::Timeout));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Check that the stacks have not grown past their limit. The limit is on all of them together,
+    /// since items moved off the active stack take up just as much memory.
+    pub fn check_stack(&self) -> rpn::Result<()> {
+        match self.budget.max_stack {
+            Some(max) if self.stacks.total_len() > max => Err(rpn::Error::Limit(Limit::Stack)),
+            _ => Ok(()),
+        }
+    }
+
+    /// Check that none of the items from `start` up on the active stack is too big or nests too
+    /// deeply.
+    pub fn check_items(&self, start: usize) -> rpn::Result<()> {
+        let items = self.stack().items();
+        for item in &items[start.min(items.len())..] {
+            let (size, depth) = item.measure();
+            if size > self.budget.max_item {
+                return Err(rpn::Error::Limit(Limit::Item));
+            }
+            if depth > self.budget.max_depth {
+                return Err(rpn::Error::Limit(Limit::Depth));
+            }
+        }
+        Ok(())
+    }
+
+    /// Run the body of a quotation or word, one call level deeper.
+    pub fn call(&mut self, body: &[String]) -> rpn::Result<()> {
+        self.step()?;
+        if self.calls >= self.budget.max_calls {
+            return Err(rpn::Error::Limit(Limit::Calls));
+        }
+
+        self.calls += 1;
+        let res = parser::eval_tokens(self, body);
+        self.calls -= 1;
+        res
+    }
+
+    /// Apply the word or operation called `name`.
+    ///
+    /// User-defined words take priority over variables, which push their value, and variables over
+    /// registered operations. Any other identifier is an unknown, and pushes itself as a symbolic
+    /// expression; anything else is a Syntax error. If the stack holds fewer items than an
+    /// operation takes, we report an Empty error before running it, so the stack is left untouched.
+    /// The items an operation leaves have to fit the budget.
+    pub fn apply(&mut self, name: &str) -> rpn::Result<()> {
+        if let Some(body) = self.words.get(name) {
+            let body = Arc::clone(body);
+            return self.call(&body);
+        }
+        if let Some(item) = self.variables.get(name) {
+            let item = item.clone();
+            return self.stack_mut().push(item);
+        }
+
+        let op = match self.registry.get(name) {
+            Some(op) => op,
+            None if symbolic::is_identifier(name) => {
+                return self.stack_mut().push(rpn::Item::Expr(Expr::var(name)));
+            }
+            None => return Err(rpn::Error::Syntax),
+        };
+        if self.stack().len() < op.arity {
+            return Err(rpn::Error::Empty);
+        }
+
+        let start = self.stack().len() - op.arity;
+        let run = op.run.clone();
+        run(self)?;
+        self.check_items(start)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rpn::{Error, Item};
+
+    fn limited(budget: Budget) -> Session {
+        let mut session = Session::new();
+        session.set_budget(budget);
+        session
+    }
+
+    #[test]
+    fn test_step_limit() {
+        let mut session = limited(Budget {
+            max_steps: Some(100),
+            ..Budget::default()
+        });
+        assert!(parser::evaluate_line(&mut session, "0 [ 1 + ] 20 times").is_ok());
+        assert!(matches!(
+            parser::evaluate_line(&mut session, "0 [ 1 + ] 50 times"),
+            Err(Error::Limit(Limit::Steps))
+        ));
+
+        // Every row of a matrix product takes a step
+        *session.stack_mut() = rpn::Stack::new();
+        assert!(parser::evaluate_line(&mut session, "10 ident 10 ident *").is_ok());
+        *session.stack_mut() = rpn::Stack::new();
+        assert!(matches!(
+            parser::evaluate_line(&mut session, "100 ident 10 pow"),
+            Err(Error::Limit(Limit::Steps))
+        ));
+        *session.stack_mut() = rpn::Stack::new();
+        assert!(matches!(
+            parser::evaluate_line(&mut session, "100 ident det"),
+            Err(Error::Limit(Limit::Steps))
+        ));
+
+        // The meter starts again for every line
+        *session.stack_mut() = rpn::Stack::new();
+        parser::evaluate_line(&mut session, "0 [ 1 + ] 1 1 + times").unwrap();
+    }
+
+    #[test]
+    fn test_stack_limit() {
+        let mut session = limited(Budget {
+            max_stack: Some(10),
+            ..Budget::default()
+        });
+        assert!(parser::evaluate_line(&mut session, "[ 1 ] 10 times").is_ok());
+        assert!(matches!(
+            parser::evaluate_line(&mut session, "1"),
+            Err(Error::Limit(Limit::Stack))
+        ));
+
+        // Items moved to another stack still count
+        let mut session = limited(Budget {
+            max_stack: Some(10),
+            ..Budget::default()
+        });
+        session.stacks_mut().create("scratch").unwrap();
+        assert!(matches!(
+            parser::evaluate_line(&mut session, "[ 1 >s scratch ] 100 times"),
+            Err(Error::Limit(Limit::Stack))
+        ));
+    }
+
+    #[test]
+    fn test_item_limits() {
+        let mut session = limited(Budget {
+            max_item: 100,
+            max_depth: 20,
+            ..Budget::default()
+        });
+        assert!(parser::evaluate_line(&mut session, "10 ident").is_ok());
+        assert!(matches!(
+            parser::evaluate_line(&mut session, "11 ident"),
+            Err(Error::Limit(Limit::Item))
+        ));
+
+        // Expressions that double in size, or only get deeper, stop at the limit
+        assert!(matches!(
+            parser::evaluate_line(&mut session, "x [ ->a a a + ] 40 times"),
+            Err(Error::Limit(Limit::Item))
+        ));
+        assert!(matches!(
+            parser::evaluate_line(&mut session, "x [ 1 + ] 300000 times"),
+            Err(Error::Limit(Limit::Depth))
+        ));
+    }
+
+    #[test]
+    fn test_call_limit() {
+        // Unbounded recursion stops at the call depth limit instead of overflowing the stack
+        let mut session = Session::new();
+        assert!(matches!(
+            parser::evaluate_line(&mut session, ": forever forever ; forever"),
+            Err(Error::Limit(Limit::Calls))
+        ));
+
+        let mut session = limited(Budget {
+            max_calls: 3,
+            ..Budget::default()
+        });
+        assert!(parser::evaluate_line(&mut session, "[ [ [ 1 ] call ] call ] call").is_ok());
+        assert!(matches!(
+            parser::evaluate_line(&mut session, "[ [ [ [ 1 ] call ] call ] call ] call"),
+            Err(Error::Limit(Limit::Calls))
+        ));
+    }
+
+    #[test]
+    fn test_timeout() {
+        let mut session = limited(Budget {
+            timeout: Some(Duration::from_millis(50)),
+            ..Budget::default()
+        });
+        assert!(matches!(
+            parser::evaluate_line(&mut session, ": spin [ ] 1000000 times spin ; spin"),
+            Err(Error::Limit(Limit::Timeout))
+        ));
+
+        // A single token multiplying big matrices stops at the time limit too
+        let started = Instant::now();
+        assert!(matches!(
+            parser::evaluate_line(&mut session, "600 ident 1000000 pow"),
+            Err(Error::Limit(Limit::Timeout))
+        ));
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_interrupt() {
+        let mut session = Session::new();
+        let interrupt = session.interrupt_handle();
+        let ticker = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(20));
+            interrupt.store(true, Ordering::SeqCst);
+        });
+        assert!(matches!(
+            parser::evaluate_line(&mut session, "[ [ ] 1000000 times ] 1000000 times"),
+            Err(Error::Limit(Limit::Interrupted))
+        ));
+        ticker.join().unwrap();
+
+        // Even in the middle of a single token
+        let interrupt = session.interrupt_handle();
+        let ticker = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(20));
+            interrupt.store(true, Ordering::SeqCst);
+        });
+        *session.stack_mut() = rpn::Stack::new();
+        assert!(matches!(
+            parser::evaluate_line(&mut session, "600 ident 1000000 pow"),
+            Err(Error::Limit(Limit::Interrupted))
+        ));
+        ticker.join().unwrap();
+
+        // A stale interrupt doesn't cancel the next line
+        session.interrupt_handle().store(true, Ordering::SeqCst);
+        *session.stack_mut() = rpn::Stack::new();
+        parser::evaluate_line(&mut session, "1 2 +").unwrap();
+        assert_eq!(session.stack_mut().pop().unwrap(), Item::Int(3));
+    }
+
+    #[test]
+    fn test_limits_uncatchable() {
+        let mut session = limited(Budget {
+            max_steps: Some(50),
+            ..Budget::default()
+        });
+        assert!(matches!(
+            parser::evaluate_line(&mut session, "[ [ 1 ] 100 times ] [ 0 ] try"),
+            Err(Error::Limit(Limit::Steps))
+        ));
+    }
+
+    #[test]
+    fn test_named_stacks() {
+        let mut session = Session::new();
+        let stacks = session.stacks_mut();
+        stacks.create("scratch").unwrap();
+        assert!(matches!(stacks.create("scratch"), Err(Error::Syntax)));
+        assert!(matches!(stacks.create(MAIN_STACK), Err(Error::Syntax)));
+        assert!(matches!(stacks.create("3"), Err(Error::Syntax)));
+        assert!(matches!(stacks.switch("nowhere"), Err(Error::Syntax)));
+
+        parser::evaluate_line(&mut session, "1 2 3 >s scratch >s scratch").unwrap();
+        assert_eq!(session.stack().to_string(), "1");
+        assert_eq!(session.stacks().depths(), vec![("main", 1), ("scratch", 2)]);
+
+        session.stacks_mut().switch("scratch").unwrap();
+        assert_eq!(session.stacks().active(), "scratch");
+        assert_eq!(session.stack().to_string(), "3 2");
+        parser::evaluate_line(&mut session, "s> main +").unwrap();
+        assert_eq!(session.stack().to_string(), "3 3");
+
+        // Moving to or from the active stack itself leaves it alone
+        parser::evaluate_line(&mut session, ">s scratch s> scratch").unwrap();
+        assert_eq!(session.stack().to_string(), "3 3");
+
+        assert!(matches!(
+            parser::evaluate_line(&mut session, "s> main"),
+            Err(Error::Empty)
+        ));
+        assert!(matches!(
+            parser::evaluate_line(&mut session, "1 >s nowhere"),
+            Err(Error::Syntax)
+        ));
+        assert!(matches!(
+            parser::evaluate_line(&mut session, "1 >s"),
+            Err(Error::Syntax)
+        ));
+    }
+}
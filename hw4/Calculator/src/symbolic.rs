@@ -0,0 +1,526 @@
+/*
+ * Reverse Polish Notation: symbolic.rs
+ * See `rpn.md` for the overview.
+ */
+
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// An arithmetic expression over integers and named unknowns.
+///
+/// Sums and products hold any number of operands. Arithmetic on expressions just builds bigger
+/// trees; `simplify` is what folds them back into a tidy normal form. Exponents are kept within
+/// the range of an `i32`, like every other integer in the calculator.
+#[derive(Clone, Eq, PartialEq, Ord, PartialOrd, Debug, Hash)]
+pub enum Expr {
+    Num(i32),
+    Var(String),
+    Sum(Vec<Expr>),
+    Product(Vec<Expr>),
+    Pow(Box<Expr>, u32),
+}
+
+/// Simplifying failed because a constant got too big for an integer.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Overflow;
+
+pub type SymResult<T> = Result<T, Overflow>;
+
+/// Building an expression failed because it would get too big.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct TooBig;
+
+/// The most nodes `subst` and `derive` will build. Without a cap, substituting into an unknown that
+/// occurs many times, or differentiating a product of many factors, could run out of memory
+/// before any budget gets to look at the result.
+pub const MAX_NODES: usize = 1 << 20;
+
+/// How deeply an expression may ever nest. Simplifying, printing and dropping an expression
+/// recurse once per level, so deeper ones could overflow the native stack.
+pub const MAX_DEPTH: usize = 1000;
+
+/// Whether a token can name an unknown: a letter or underscore, then letters, digits and
+/// underscores.
+pub fn is_identifier(tok: &str) -> bool {
+    let mut chars = tok.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => {
+            chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+        }
+        _ => false,
+    }
+}
+
+impl Expr {
+    pub fn var(name: &str) -> Self {
+        Expr::Var(name.to_string())
+    }
+
+    pub fn add(self, other: Expr) -> Self {
+        Expr::Sum(vec![self, other])
+    }
+
+    pub fn sub(self, other: Expr) -> Self {
+        Expr::Sum(vec![self, other.neg()])
+    }
+
+    pub fn mul(self, other: Expr) -> Self {
+        Expr::Product(vec![self, other])
+    }
+
+    pub fn neg(self) -> Self {
+        Expr::Product(vec![Expr::Num(-1), self])
+    }
+
+    pub fn pow(self, exp: u32) -> Self {
+        Expr::Pow(Box::new(self), exp)
+    }
+
+    /// The number of nodes in the expression, and how deeply they nest. We walk the tree with an
+    /// explicit stack, so this is safe to call on expressions too deep to simplify or print.
+    pub fn measure(&self) -> (usize, usize) {
+        let (mut nodes, mut depth) = (0, 0);
+        self.walk(|_, level| {
+            nodes += 1;
+            depth = depth.max(level);
+        });
+        (nodes, depth)
+    }
+
+    // Visit every node with its depth, the root being at depth 1
+    fn walk<'a>(&'a self, mut visit: impl FnMut(&'a Expr, usize)) {
+        let mut todo = vec![(self, 1)];
+        while let Some((expr, level)) = todo.pop() {
+            visit(expr, level);
+            match expr {
+                Expr::Num(_) | Expr::Var(_) => (),
+                Expr::Sum(args) | Expr::Product(args) => {
+                    todo.extend(args.iter().map(|arg| (arg, level + 1)))
+                }
+                Expr::Pow(base, _) => todo.push((base, level + 1)),
+            }
+        }
+    }
+
+    /// Replace every occurrence of the unknown `name` with `val`, unless the result would have
+    /// more than `MAX_NODES` nodes or nest more than `MAX_DEPTH` levels deep.
+    pub fn subst(&self, name: &str, val: &Expr) -> Result<Expr, TooBig> {
+        let (mut nodes, mut depth) = (0, 0);
+        let (val_nodes, val_depth) = val.measure();
+        self.walk(|expr, level| match expr {
+            Expr::Var(var) if var == name => {
+                nodes += val_nodes;
+                depth = depth.max(level - 1 + val_depth);
+            }
+            _ => {
+                nodes += 1;
+                depth = depth.max(level);
+            }
+        });
+        if nodes > MAX_NODES || depth > MAX_DEPTH {
+            return Err(TooBig);
+        }
+
+        Ok(self.subst_all(name, val))
+    }
+
+    fn subst_all(&self, name: &str, val: &Expr) -> Expr {
+        match self {
+            Expr::Var(var) if var == name => val.clone(),
+            Expr::Num(_) | Expr::Var(_) => self.clone(),
+            Expr::Sum(terms) => Expr::Sum(terms.iter().map(|t| t.subst_all(name, val)).collect()),
+            Expr::Product(factors) => {
+                Expr::Product(factors.iter().map(|f| f.subst_all(name, val)).collect())
+            }
+            Expr::Pow(base, exp) => base.subst_all(name, val).pow(*exp),
+        }
+    }
+
+    /// The derivative with respect to the unknown `name`, before simplifying, unless it would
+    /// have more than `MAX_NODES` nodes.
+    pub fn derive(&self, name: &str) -> Result<Expr, TooBig> {
+        let mut left = MAX_NODES;
+        self.derive_within(name, &mut left)
+    }
+
+    // The derivative, taking the nodes it builds out of the allowance `left`
+    fn derive_within(&self, name: &str, left: &mut usize) -> Result<Expr, TooBig> {
+        *left = left.checked_sub(1).ok_or(TooBig)?;
+        Ok(match self {
+            Expr::Num(_) => Expr::Num(0),
+            Expr::Var(var) => Expr::Num(if var == name { 1 } else { 0 }),
+            Expr::Sum(terms) => Expr::Sum(
+                terms
+                    .iter()
+                    .map(|t| t.derive_within(name, left))
+                    .collect::<Result<_, _>>()?,
+            ),
+
+            // Product rule: differentiate one factor at a time, keeping the others
+            Expr::Product(factors) => {
+                let sizes: Vec<usize> = factors.iter().map(|f| f.measure().0).collect();
+                let total: usize = sizes.iter().sum();
+                let mut terms = Vec::with_capacity(factors.len());
+                for (idx, size) in sizes.into_iter().enumerate() {
+                    *left = left.checked_sub(1 + total - size).ok_or(TooBig)?;
+                    let mut factors = factors.clone();
+                    factors[idx] = factors[idx].derive_within(name, left)?;
+                    terms.push(Expr::Product(factors));
+                }
+                Expr::Sum(terms)
+            }
+
+            // Power rule, with the chain rule for the base
+            Expr::Pow(_, 0) => Expr::Num(0),
+            Expr::Pow(base, exp) => {
+                *left = left.checked_sub(3 + base.measure().0).ok_or(TooBig)?;
+                Expr::Product(vec![
+                    Expr::Num(*exp as i32),
+                    (**base).clone().pow(exp - 1),
+                    base.derive_within(name, left)?,
+                ])
+            }
+        })
+    }
+
+    /// The degree of the expression as a polynomial, used to order terms.
+    fn degree(&self) -> u64 {
+        match self {
+            Expr::Num(_) => 0,
+            Expr::Var(_) => 1,
+            Expr::Sum(terms) => terms.iter().map(Expr::degree).max().unwrap_or(0),
+            Expr::Product(factors) => factors.iter().map(Expr::degree).sum(),
+            Expr::Pow(base, exp) => base.degree().saturating_mul(u64::from(*exp)),
+        }
+    }
+
+    /// Split a simplified term into its constant coefficient and the rest of it.
+    fn split_coeff(self) -> (i32, Expr) {
+        match self {
+            Expr::Num(val) => (val, Expr::Num(1)),
+            Expr::Product(mut factors) => match factors.first() {
+                Some(&Expr::Num(coeff)) => {
+                    factors.remove(0);
+                    let rest = if factors.len() == 1 {
+                        factors.pop().unwrap()
+                    } else {
+                        Expr::Product(factors)
+                    };
+                    (coeff, rest)
+                }
+                _ => (1, Expr::Product(factors)),
+            },
+            other => (1, other),
+        }
+    }
+
+    /// Put a coefficient back in front of a term.
+    fn with_coeff(coeff: i32, rest: Expr) -> Expr {
+        match (coeff, rest) {
+            (_, Expr::Num(1)) => Expr::Num(coeff),
+            (1, rest) => rest,
+            (_, Expr::Product(mut factors)) => {
+                factors.insert(0, Expr::Num(coeff));
+                Expr::Product(factors)
+            }
+            (_, rest) => Expr::Product(vec![Expr::Num(coeff), rest]),
+        }
+    }
+
+    /// Bring the expression into normal form: fold constants, flatten nested sums and products,
+    /// combine like terms and like factors, and drop zeros and ones. Sums are not multiplied out.
+    pub fn simplify(&self) -> SymResult<Expr> {
+        match self {
+            Expr::Num(_) | Expr::Var(_) => Ok(self.clone()),
+            Expr::Sum(terms) => simplify_sum(terms),
+            Expr::Product(factors) => simplify_product(factors),
+            Expr::Pow(base, exp) => simplify_pow(base.simplify()?, *exp),
+        }
+    }
+}
+
+fn simplify_sum(terms: &[Expr]) -> SymResult<Expr> {
+    // Add up the coefficients of terms that only differ by a constant factor
+    let mut constant = 0i32;
+    let mut like: BTreeMap<Expr, i32> = BTreeMap::new();
+    let mut add_term = |term: Expr| -> SymResult<()> {
+        let (coeff, rest) = term.split_coeff();
+        let total = match rest {
+            Expr::Num(_) => &mut constant,
+            rest => like.entry(rest).or_insert(0),
+        };
+        *total = total.checked_add(coeff).ok_or(Overflow)?;
+        Ok(())
+    };
+
+    for term in terms {
+        match term.simplify()? {
+            Expr::Sum(inner) => inner.into_iter().try_for_each(&mut add_term)?,
+            term => add_term(term)?,
+        }
+    }
+
+    // Highest degree first, and the constant last
+    let mut out: Vec<Expr> = like
+        .into_iter()
+        .filter(|&(_, coeff)| coeff != 0)
+        .map(|(rest, coeff)| Expr::with_coeff(coeff, rest))
+        .collect();
+    out.sort_by_key(|term| Reverse(term.degree()));
+    if constant != 0 {
+        out.push(Expr::Num(constant));
+    }
+
+    Ok(match out.len() {
+        0 => Expr::Num(0),
+        1 => out.pop().unwrap(),
+        _ => Expr::Sum(out),
+    })
+}
+
+fn simplify_product(factors: &[Expr]) -> SymResult<Expr> {
+    // Multiply out the constants, and add up the exponents of factors with the same base
+    let mut coeff = 1i32;
+    let mut like: BTreeMap<Expr, i32> = BTreeMap::new();
+    let mut mul_factor = |factor: Expr| -> SymResult<()> {
+        let (base, exp) = match factor {
+            Expr::Num(val) => {
+                coeff = coeff.checked_mul(val).ok_or(Overflow)?;
+                return Ok(());
+            }
+            Expr::Pow(base, exp) => (*base, exp as i32),
+            base => (base, 1),
+        };
+        let total = like.entry(base).or_insert(0);
+        *total = total.checked_add(exp).ok_or(Overflow)?;
+        Ok(())
+    };
+
+    for factor in factors {
+        match factor.simplify()? {
+            Expr::Product(inner) => inner.into_iter().try_for_each(&mut mul_factor)?,
+            factor => mul_factor(factor)?,
+        }
+    }
+
+    if coeff == 0 {
+        return Ok(Expr::Num(0));
+    }
+
+    let mut out: Vec<Expr> = like
+        .into_iter()
+        .map(|(base, exp)| if exp == 1 { base } else { base.pow(exp as u32) })
+        .collect();
+
+    Ok(match out.len() {
+        0 => Expr::Num(coeff),
+        1 => Expr::with_coeff(coeff, out.pop().unwrap()),
+        _ => Expr::with_coeff(coeff, Expr::Product(out)),
+    })
+}
+
+fn simplify_pow(base: Expr, exp: u32) -> SymResult<Expr> {
+    match (base, exp) {
+        (_, 0) => Ok(Expr::Num(1)),
+        (base, 1) => Ok(base),
+        (Expr::Num(val), _) => val.checked_pow(exp).map(Expr::Num).ok_or(Overflow),
+        (Expr::Pow(base, inner), _) => match inner.checked_mul(exp) {
+            Some(exp) if exp <= i32::MAX as u32 => simplify_pow(*base, exp),
+            _ => Err(Overflow),
+        },
+
+        // (a*b)^n = a^n * b^n, which lets the constant fold
+        (Expr::Product(factors), _) => {
+            let factors: Vec<Expr> = factors.into_iter().map(|f| f.pow(exp)).collect();
+            simplify_product(&factors)
+        }
+        (base, _) => Ok(base.pow(exp)),
+    }
+}
+
+impl Expr {
+    // How tightly the expression binds when written out: sums, then products, then powers
+    fn precedence(&self) -> u8 {
+        match self {
+            Expr::Sum(terms) if terms.len() > 1 => 1,
+            Expr::Product(factors) if factors.len() > 1 => 2,
+            Expr::Num(val) if *val < 0 => 2,
+            Expr::Pow(..) => 3,
+            _ => 4,
+        }
+    }
+
+    // Write the expression, in parentheses if it binds less tightly than `prec`
+    fn write_at(&self, f: &mut fmt::Formatter, prec: u8) -> fmt::Result {
+        if self.precedence() < prec {
+            write!(f, "(")?;
+            self.write_at(f, 0)?;
+            return write!(f, ")");
+        }
+
+        match self {
+            Expr::Num(val) => write!(f, "{}", val),
+            Expr::Var(name) => write!(f, "{}", name),
+            Expr::Sum(terms) if terms.is_empty() => write!(f, "0"),
+            Expr::Product(factors) if factors.is_empty() => write!(f, "1"),
+            Expr::Sum(terms) => {
+                terms[0].write_at(f, 1)?;
+                for term in &terms[1..] {
+                    // Write `a - b` rather than `a + -1*b`
+                    match term.clone().split_coeff() {
+                        (coeff, rest) if coeff < 0 && coeff != i32::MIN => {
+                            write!(f, " - ")?;
+                            Expr::with_coeff(-coeff, rest).write_at(f, 2)?;
+                        }
+                        _ => {
+                            write!(f, " + ")?;
+                            term.write_at(f, 2)?;
+                        }
+                    }
+                }
+                Ok(())
+            }
+            Expr::Product(factors) => {
+                // Write `-2*x` rather than `(-2)*x`, and `-x` rather than `-1*x`
+                if let Expr::Num(coeff) = factors[0] {
+                    if coeff < 0 && coeff != i32::MIN {
+                        write!(f, "-")?;
+                        let rest = Expr::Product(factors[1..].to_vec());
+                        return Expr::with_coeff(-coeff, rest).write_at(f, 2);
+                    }
+                }
+                for (idx, factor) in factors.iter().enumerate() {
+                    if idx > 0 {
+                        write!(f, "*")?;
+                    }
+                    factor.write_at(f, 3)?;
+                }
+                Ok(())
+            }
+            Expr::Pow(base, exp) => {
+                base.write_at(f, 4)?;
+                write!(f, "^{}", exp)
+            }
+        }
+    }
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.write_at(f, 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn x() -> Expr {
+        Expr::var("x")
+    }
+
+    fn y() -> Expr {
+        Expr::var("y")
+    }
+
+    fn num(val: i32) -> Expr {
+        Expr::Num(val)
+    }
+
+    fn simple(expr: Expr) -> String {
+        expr.simplify().unwrap().to_string()
+    }
+
+    #[test]
+    fn test_display() {
+        assert_eq!(x().add(num(1)).mul(y()).to_string(), "(x + 1)*y");
+        assert_eq!(x().sub(y()).to_string(), "x - y");
+        assert_eq!(x().neg().pow(2).to_string(), "(-x)^2");
+        assert_eq!(num(-2).pow(3).to_string(), "(-2)^3");
+        assert_eq!(x().pow(2).pow(3).to_string(), "(x^2)^3");
+        assert_eq!(x().add(num(-3)).to_string(), "x - 3");
+    }
+
+    #[test]
+    fn test_simplify() {
+        assert_eq!(simple(num(2).add(num(3)).mul(num(4))), "20");
+        assert_eq!(simple(x().add(x()).add(x())), "3*x");
+        assert_eq!(simple(x().sub(x())), "0");
+        assert_eq!(simple(x().mul(num(0)).add(y())), "y");
+        assert_eq!(simple(x().mul(num(1)).add(num(0))), "x");
+        assert_eq!(simple(x().pow(1).mul(x().pow(0))), "x");
+        assert_eq!(simple(x().mul(x()).mul(num(3)).mul(x())), "3*x^3");
+        assert_eq!(simple(num(2).mul(x()).pow(3)), "8*x^3");
+        assert_eq!(
+            simple(num(1).add(x()).add(x().pow(2)).add(num(2).mul(x()))),
+            "x^2 + 3*x + 1"
+        );
+        assert_eq!(simple(x().mul(y()).sub(y().mul(x()))), "0");
+        assert_eq!(simple(y().sub(x().mul(num(2)))), "-2*x + y");
+
+        // Sums are kept as they are, not multiplied out
+        assert_eq!(simple(x().add(num(1)).mul(x().add(num(1)))), "(x + 1)^2");
+        assert_eq!(num(i32::MAX).add(num(1)).simplify(), Err(Overflow));
+    }
+
+    #[test]
+    fn test_subst() {
+        let poly = x().pow(2).add(num(3).mul(x())).add(y());
+        assert_eq!(simple(poly.subst("x", &num(2)).unwrap()), "y + 10");
+        assert_eq!(simple(poly.subst("y", &x()).unwrap()), "x^2 + 4*x");
+        assert_eq!(
+            simple(
+                poly.subst("x", &num(2))
+                    .unwrap()
+                    .subst("y", &num(-10))
+                    .unwrap()
+            ),
+            "0"
+        );
+
+        // A million copies of a big expression, or nesting one deep chain in another, is too much
+        let many = Expr::Product(vec![x(); 2000]);
+        let big = Expr::Sum(vec![y(); 1000]);
+        assert_eq!(many.subst("x", &big), Err(TooBig));
+        let chain = (0..600).fold(x(), |expr, _| expr.add(num(1)));
+        assert_eq!(chain.subst("x", &chain), Err(TooBig));
+        assert!(chain.subst("x", &y()).is_ok());
+    }
+
+    #[test]
+    fn test_derive() {
+        let poly = x().pow(3).add(num(2).mul(x())).add(num(7));
+        assert_eq!(simple(poly.derive("x").unwrap()), "3*x^2 + 2");
+        assert_eq!(simple(poly.derive("y").unwrap()), "0");
+        assert_eq!(simple(x().mul(y()).derive("x").unwrap()), "y");
+        assert_eq!(
+            simple(x().add(num(1)).pow(2).derive("x").unwrap()),
+            "2*(x + 1)"
+        );
+        assert_eq!(
+            simple(x().pow(2).mul(y().pow(2)).derive("y").unwrap()),
+            "2*x^2*y"
+        );
+
+        // The product rule copies every factor but one into each term
+        assert!(Expr::Product(vec![x(); 500]).derive("x").is_ok());
+        assert_eq!(Expr::Product(vec![x(); 2000]).derive("x"), Err(TooBig));
+    }
+
+    #[test]
+    fn test_measure() {
+        assert_eq!(x().measure(), (1, 1));
+        assert_eq!(x().add(num(1)).mul(y()).measure(), (5, 3));
+        assert_eq!(x().pow(2).pow(3).measure(), (3, 3));
+    }
+
+    #[test]
+    fn test_is_identifier() {
+        assert!(is_identifier("x"));
+        assert!(is_identifier("rate_2"));
+        assert!(!is_identifier("2x"));
+        assert!(!is_identifier("d/dx"));
+        assert!(!is_identifier(""));
+    }
+}
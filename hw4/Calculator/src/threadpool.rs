@@ -0,0 +1,88 @@
+/*
+ * Reverse Polish Notation: threadpool.rs
+ * See `rpn.md` for the overview.
+ */
+
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+
+/// Message type to communicate with workers. A JobMsg is either a FnOnce closure or None, which
+/// signals the worker to shut down.
+type JobMsg = Option<Box<dyn FnOnce() + Send + 'static>>;
+
+/// A fixed set of worker threads taking jobs off a shared channel.
+pub struct ThreadPool {
+    sender: mpsc::Sender<JobMsg>,
+    workers: Vec<thread::JoinHandle<()>>,
+}
+
+impl ThreadPool {
+    /// Spin up a thread pool with `num_workers` threads, each with `stack_size` bytes of stack.
+    /// Jobs pushed while every worker is busy wait in the channel until one frees up.
+    pub fn new(num_workers: usize, stack_size: usize) -> Self {
+        let (tx, rx) = mpsc::channel::<JobMsg>();
+        let rx = Arc::new(Mutex::new(rx));
+
+        let workers = (0..num_workers)
+            .map(|_| {
+                let rx = Arc::clone(&rx);
+                thread::Builder::new()
+                    .stack_size(stack_size)
+                    .spawn(move || loop {
+                        // Release the lock before running the job, so other workers can pick up
+                        // work. A job that panics only loses its own work, not the worker.
+                        let received = rx.lock().unwrap().recv();
+                        match received {
+                            Ok(Some(job)) => {
+                                let _ = panic::catch_unwind(AssertUnwindSafe(job));
+                            }
+                            Ok(None) | Err(_) => break,
+                        }
+                    })
+                    .expect("failed to spawn a worker thread")
+            })
+            .collect();
+
+        ThreadPool {
+            sender: tx,
+            workers,
+        }
+    }
+
+    /// Push a new job into the thread pool.
+    pub fn execute<F>(&mut self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.sender.send(Some(Box::new(job))).unwrap();
+    }
+}
+
+impl Drop for ThreadPool {
+    /// Send a kill message (None) to each worker, and wait for them all to finish.
+    fn drop(&mut self) {
+        for _ in 0..self.workers.len() {
+            self.sender.send(None).unwrap();
+        }
+
+        for worker in self.workers.drain(..) {
+            worker.join().unwrap();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_panicking_job() {
+        // A job that panics doesn't take its worker down: the next job still runs
+        let mut pool = ThreadPool::new(1, 1 << 20);
+        let (tx, rx) = mpsc::channel();
+        pool.execute(|| panic!("job failed"));
+        pool.execute(move || tx.send(42).unwrap());
+        assert_eq!(rx.recv().unwrap(), 42);
+    }
+}
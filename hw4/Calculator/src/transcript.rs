@@ -0,0 +1,301 @@
+/*
+ * Reverse Polish Notation: transcript.rs
+ * See `rpn.md` for the overview.
+ *
+ * A transcript is the record of a REPL session: every line typed in, with what the calculator
+ * answered. It can be written out as Markdown for people to read, or as JSON, which can be replayed
+ * later to check that the calculator still gives the same answers.
+ */
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use super::json::{self, Value};
+use super::repl::Repl;
+
+/// One line of input, and what came of it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Entry {
+    pub input: String,
+    /// What the REPL printed, line by line.
+    pub output: Vec<String>,
+    /// The kind and message of the error, if the line failed.
+    pub error: Option<(String, String)>,
+    /// The active stack afterwards.
+    pub stack: Vec<Value>,
+}
+
+impl Entry {
+    pub fn to_json(&self) -> Value {
+        let mut fields = vec![
+            ("input", Value::Str(self.input.clone())),
+            (
+                "output",
+                Value::Array(self.output.iter().cloned().map(Value::Str).collect()),
+            ),
+        ];
+        if let Some((kind, message)) = &self.error {
+            let error = Value::object(vec![
+                ("kind", Value::Str(kind.clone())),
+                ("message", Value::Str(message.clone())),
+            ]);
+            fields.push(("error", error));
+        }
+        fields.push(("stack", Value::Array(self.stack.clone())));
+        Value::object(fields)
+    }
+
+    /// Read an entry back from the JSON `to_json` writes.
+    pub fn from_json(val: &Value) -> Option<Entry> {
+        let strings = |vals: &[Value]| -> Option<Vec<String>> {
+            vals.iter()
+                .map(|val| val.as_str().map(String::from))
+                .collect()
+        };
+        let error = match val.get("error") {
+            Some(error) => Some((
+                error.get("kind")?.as_str()?.to_string(),
+                error.get("message")?.as_str()?.to_string(),
+            )),
+            None => None,
+        };
+        Some(Entry {
+            input: val.get("input")?.as_str()?.to_string(),
+            output: strings(val.get("output")?.as_array()?)?,
+            error,
+            stack: val.get("stack")?.as_array()?.to_vec(),
+        })
+    }
+}
+
+/// Write a transcript to a file, as Markdown if its name ends in `.md` and as JSON if it ends in
+/// `.json`.
+pub fn export(path: &Path, entries: &[Entry]) -> io::Result<()> {
+    let text = match path.extension().and_then(|ext| ext.to_str()) {
+        Some("md") => markdown(entries),
+        Some("json") => json(entries),
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the file name should end in .md or .json",
+            ))
+        }
+    };
+    fs::write(path, text)
+}
+
+/// A notebook-style log: each line typed in, followed by what the calculator answered.
+pub fn markdown(entries: &[Entry]) -> String {
+    let mut text = String::from("# Calculator session\n");
+    for (idx, entry) in entries.iter().enumerate() {
+        text += &format!("\n**[{}]** {}\n", idx + 1, code_span(&entry.input));
+        if !entry.output.is_empty() {
+            text += "\n";
+            for line in &entry.output {
+                text += &format!("    {}\n", line);
+            }
+        }
+    }
+    text
+}
+
+// Inline code showing `text` as it is. The fence has to be a longer run of backticks than any in
+// the text, and a text starting or ending with a backtick needs a space between it and the fence.
+fn code_span(text: &str) -> String {
+    let longest = text.split(|c| c != '`').map(str::len).max().unwrap_or(0);
+    let fence = "`".repeat(longest + 1);
+    let pad = if text.starts_with('`') || text.ends_with('`') {
+        " "
+    } else {
+        ""
+    };
+    format!("{}{}{}{}{}", fence, pad, text, pad, fence)
+}
+
+/// A JSON array with one entry per line, for other programs and for `replay`.
+pub fn json(entries: &[Entry]) -> String {
+    let lines: Vec<_> = entries
+        .iter()
+        .map(|entry| entry.to_json().to_string())
+        .collect();
+    format!("[\n{}\n]\n", lines.join(",\n"))
+}
+
+/// Why a replay didn't go through.
+#[derive(Debug)]
+pub enum ReplayError {
+    Json(json::ParseError), // The file isn't JSON
+    Entry(usize),           // This entry isn't a transcript entry
+    Quit(usize),            // The calculator quit at this entry
+    Random(usize), // This entry uses `#` or `today`, which give a different answer each time
+    Differs {
+        line: usize,
+        expected: Box<Entry>,
+        actual: Box<Entry>,
+    },
+}
+
+impl fmt::Display for ReplayError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ReplayError::Json(err) => write!(f, "not a transcript: {}", err),
+            ReplayError::Entry(line) => write!(f, "line {} is not a transcript entry", line),
+            ReplayError::Quit(line) => write!(f, "the calculator quit at line {}", line),
+            ReplayError::Random(line) => write!(
+                f,
+                "line {} uses `#` or `today`, so it can't replay the same",
+                line
+            ),
+            ReplayError::Differs {
+                line,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "line {} (`{}`) differs\n  expected: {}\n  actual:   {}",
+                line,
+                expected.input,
+                expected.to_json(),
+                actual.to_json()
+            ),
+        }
+    }
+}
+
+/// Run the lines of a JSON transcript through a new REPL, checking that each one prints the same
+/// output, fails with the same error and leaves the same stack as before. Lines are numbered from
+/// 1. On success, returns the number of lines replayed.
+///
+/// A transcript with a line using `#` or `today` is turned down before anything runs, since the
+/// answers it recorded can't be expected to come out again (and a later line may depend on them).
+pub fn replay(text: &str) -> Result<usize, ReplayError> {
+    let entries = Value::parse(text).map_err(ReplayError::Json)?;
+    let entries = entries.as_array().ok_or(ReplayError::Entry(0))?;
+
+    let random = entries.iter().position(|val| {
+        let input = val.get("input").and_then(Value::as_str).unwrap_or("");
+        input
+            .split_whitespace()
+            .any(|tok| tok == "#" || tok == "today")
+    });
+    if let Some(idx) = random {
+        return Err(ReplayError::Random(idx + 1));
+    }
+
+    let mut repl = Repl::new();
+    for (idx, val) in entries.iter().enumerate() {
+        let line = idx + 1;
+        let expected = Entry::from_json(val).ok_or(ReplayError::Entry(line))?;
+        if repl.handle(&expected.input, &mut io::sink()).is_err() {
+            return Err(ReplayError::Quit(line));
+        }
+
+        let actual = repl.transcript().last().cloned();
+        if actual.as_ref() != Some(&expected) {
+            let actual = actual.unwrap_or_else(|| Entry {
+                input: expected.input.clone(),
+                output: Vec::new(),
+                error: None,
+                stack: Vec::new(),
+            });
+            return Err(ReplayError::Differs {
+                line,
+                expected: Box::new(expected),
+                actual: Box::new(actual),
+            });
+        }
+    }
+    Ok(entries.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn session(lines: &[&str]) -> Vec<Entry> {
+        let mut repl = Repl::new();
+        for line in lines {
+            repl.handle(line, &mut io::sink()).unwrap();
+        }
+        repl.transcript().to_vec()
+    }
+
+    #[test]
+    fn test_markdown() {
+        let entries = session(&["1 2", "", "+", "true +", ":precision 3", "1.0 3 /"]);
+        assert_eq!(
+            markdown(&entries),
+            "# Calculator session\n\n\
+             **[1]** `1 2`\n\n    Reply> 2\n\n\
+             **[2]** `+`\n\n    Reply> 3\n\n\
+             **[3]** `true +`\n\n    Error: type mismatch\n\n\
+             **[4]** `:precision 3`\n\n    Precision 3 digits\n\n\
+             **[5]** `1.0 3 /`\n\n    Reply> 0.333\n"
+        );
+
+        // Backticks in the input can't end the code span early
+        assert_eq!(code_span("a `b` c"), "``a `b` c``");
+        assert_eq!(code_span("a ``` b"), "````a ``` b````");
+        assert_eq!(code_span("`x"), "`` `x ``");
+    }
+
+    #[test]
+    fn test_json() {
+        let entries = session(&["1 x", "true +"]);
+        assert_eq!(
+            json(&entries),
+            "[\n\
+             {\"input\":\"1 x\",\"output\":[\"Reply> x\"],\"stack\":[1,\"x\"]},\n\
+             {\"input\":\"true +\",\"output\":[\"Error: type mismatch\"],\
+             \"error\":{\"kind\":\"Type\",\"message\":\"type mismatch\"},\"stack\":[1,\"x\"]}\n\
+             ]\n"
+        );
+        let read: Vec<_> = Value::parse(&json(&entries))
+            .unwrap()
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|val| Entry::from_json(val).unwrap())
+            .collect();
+        assert_eq!(read, entries);
+    }
+
+    #[test]
+    fn test_replay() {
+        let entries = session(&[
+            ": sq ->n n n * ;",
+            "3 sq",
+            ":stack new other",
+            ">s other",
+            "s> other 1i *",
+            "2026-10-17 P3D +",
+            "@",
+        ]);
+        let text = json(&entries);
+        assert_eq!(replay(&text).unwrap(), 7);
+
+        // Change the answer of one line
+        let edited = text.replace("Reply> 9", "Reply> 10");
+        match replay(&edited) {
+            Err(ReplayError::Differs { line, actual, .. }) => {
+                assert_eq!(line, 2);
+                assert_eq!(actual.output, vec![String::from("Reply> 9")]);
+            }
+            res => panic!("{:?}", res),
+        }
+
+        assert!(matches!(replay("[1, 2"), Err(ReplayError::Json(_))));
+        assert!(matches!(replay("{}"), Err(ReplayError::Entry(0))));
+        assert!(matches!(replay("[{}]"), Err(ReplayError::Entry(1))));
+        let quit = r#"[{"input":"quit","output":[],"stack":[]}]"#;
+        assert!(matches!(replay(quit), Err(ReplayError::Quit(1))));
+
+        // Transcripts that can't give the same answers again are turned down
+        let text = json(&session(&["1 2", ": roll 6 # ;", "roll"]));
+        assert!(matches!(replay(&text), Err(ReplayError::Random(2))));
+        let text = json(&session(&["today"]));
+        assert!(matches!(replay(&text), Err(ReplayError::Random(1))));
+    }
+}
@@ -0,0 +1,365 @@
+/*
+ * Reverse Polish Notation: units.rs
+ * See `rpn.md` for the overview.
+ *
+ * Quantities carry a number together with the unit it is measured in. Every unit is some multiple
+ * of a product of powers of the seven SI base units, which gives it a dimension; quantities can
+ * only be added or converted when their dimensions agree.
+ */
+
+use std::fmt;
+
+/// Powers of the SI base units, in the order of `BASE_UNITS`.
+pub type Dims = [i32; 7];
+
+const BASE_UNITS: [&str; 7] = ["kg", "m", "s", "A", "K", "mol", "cd"];
+
+const NONE: Dims = [0; 7];
+const MASS: Dims = [1, 0, 0, 0, 0, 0, 0];
+const LENGTH: Dims = [0, 1, 0, 0, 0, 0, 0];
+const TIME: Dims = [0, 0, 1, 0, 0, 0, 0];
+const CURRENT: Dims = [0, 0, 0, 1, 0, 0, 0];
+const TEMPERATURE: Dims = [0, 0, 0, 0, 1, 0, 0];
+const AMOUNT: Dims = [0, 0, 0, 0, 0, 1, 0];
+const LUMINOSITY: Dims = [0, 0, 0, 0, 0, 0, 1];
+const AREA: Dims = [0, 2, 0, 0, 0, 0, 0];
+const VOLUME: Dims = [0, 3, 0, 0, 0, 0, 0];
+const FREQUENCY: Dims = [0, 0, -1, 0, 0, 0, 0];
+const FORCE: Dims = [1, 1, -2, 0, 0, 0, 0];
+const PRESSURE: Dims = [1, -1, -2, 0, 0, 0, 0];
+const ENERGY: Dims = [1, 2, -2, 0, 0, 0, 0];
+const POWER: Dims = [1, 2, -3, 0, 0, 0, 0];
+const CHARGE: Dims = [0, 0, 1, 1, 0, 0, 0];
+const VOLTAGE: Dims = [1, 2, -3, -1, 0, 0, 0];
+const RESISTANCE: Dims = [1, 2, -3, -2, 0, 0, 0];
+
+/// A named unit: how many SI base units it is worth, and its dimension.
+pub struct UnitDef {
+    pub name: &'static str,
+    pub factor: f64,
+    pub dims: Dims,
+    pub help: &'static str,
+}
+
+const fn unit(name: &'static str, factor: f64, dims: Dims, help: &'static str) -> UnitDef {
+    UnitDef {
+        name,
+        factor,
+        dims,
+        help,
+    }
+}
+
+/// Every unit the calculator knows about.
+pub const UNITS: &[UnitDef] = &[
+    unit("kg", 1.0, MASS, "kilogram"),
+    unit("g", 1e-3, MASS, "gram"),
+    unit("t", 1e3, MASS, "metric ton"),
+    unit("lb", 0.453_592_37, MASS, "pound"),
+    unit("oz", 0.028_349_523_125, MASS, "ounce"),
+    unit("m", 1.0, LENGTH, "meter"),
+    unit("km", 1e3, LENGTH, "kilometer"),
+    unit("cm", 1e-2, LENGTH, "centimeter"),
+    unit("mm", 1e-3, LENGTH, "millimeter"),
+    unit("um", 1e-6, LENGTH, "micrometer"),
+    unit("in", 0.0254, LENGTH, "inch"),
+    unit("ft", 0.3048, LENGTH, "foot"),
+    unit("yd", 0.9144, LENGTH, "yard"),
+    unit("mi", 1609.344, LENGTH, "mile"),
+    unit("s", 1.0, TIME, "second"),
+    unit("ms", 1e-3, TIME, "millisecond"),
+    unit("us", 1e-6, TIME, "microsecond"),
+    unit("min", 60.0, TIME, "minute"),
+    unit("h", 3600.0, TIME, "hour"),
+    unit("A", 1.0, CURRENT, "ampere"),
+    unit("mA", 1e-3, CURRENT, "milliampere"),
+    unit("K", 1.0, TEMPERATURE, "kelvin"),
+    unit("mol", 1.0, AMOUNT, "mole"),
+    unit("cd", 1.0, LUMINOSITY, "candela"),
+    unit("ha", 1e4, AREA, "hectare"),
+    unit("L", 1e-3, VOLUME, "liter"),
+    unit("mL", 1e-6, VOLUME, "milliliter"),
+    unit("Hz", 1.0, FREQUENCY, "hertz"),
+    unit("kHz", 1e3, FREQUENCY, "kilohertz"),
+    unit("MHz", 1e6, FREQUENCY, "megahertz"),
+    unit("N", 1.0, FORCE, "newton"),
+    unit("Pa", 1.0, PRESSURE, "pascal"),
+    unit("bar", 1e5, PRESSURE, "bar"),
+    unit("J", 1.0, ENERGY, "joule"),
+    unit("kJ", 1e3, ENERGY, "kilojoule"),
+    unit("cal", 4.184, ENERGY, "calorie"),
+    unit("Wh", 3.6e3, ENERGY, "watt-hour"),
+    unit("kWh", 3.6e6, ENERGY, "kilowatt-hour"),
+    unit("eV", 1.602_176_634e-19, ENERGY, "electronvolt"),
+    unit("W", 1.0, POWER, "watt"),
+    unit("kW", 1e3, POWER, "kilowatt"),
+    unit("mW", 1e-3, POWER, "milliwatt"),
+    unit("C", 1.0, CHARGE, "coulomb"),
+    unit("V", 1.0, VOLTAGE, "volt"),
+    unit("mV", 1e-3, VOLTAGE, "millivolt"),
+    unit("ohm", 1.0, RESISTANCE, "ohm"),
+    unit("kohm", 1e3, RESISTANCE, "kiloohm"),
+];
+
+pub fn lookup(name: &str) -> Option<&'static UnitDef> {
+    UNITS.iter().find(|def| def.name == name)
+}
+
+/// A product of powers of named units, like `kg*m/s^2`, in the order they were written.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Unit {
+    terms: Vec<(&'static str, i32)>,
+}
+
+impl Unit {
+    /// No unit at all: a plain number.
+    pub fn none() -> Self {
+        Unit::default()
+    }
+
+    /// A single named unit, if we know it.
+    pub fn named(name: &str) -> Option<Self> {
+        lookup(name).map(|def| Unit {
+            terms: vec![(def.name, 1)],
+        })
+    }
+
+    /// Parse the tokens of a unit quotation like `[ km / h ]`: units written one after another
+    /// are multiplied, everything after a `/` divides, and `^n` raises a single unit to a power.
+    pub fn parse(tokens: &[String]) -> Option<Self> {
+        let mut unit = Unit::none();
+        let mut sign = 1;
+        for tok in tokens {
+            if tok == "/" && sign == 1 {
+                sign = -1;
+                continue;
+            }
+            let (name, exp) = match tok.split_once('^') {
+                Some((name, exp)) => (name, exp.parse::<i32>().ok()?),
+                None => (tok.as_str(), 1),
+            };
+            unit = unit.mul(&Unit::named(name)?.pow(exp.checked_mul(sign)?));
+        }
+        Some(unit)
+    }
+
+    /// Write out a dimension in SI base units.
+    pub fn base(dims: &Dims) -> Self {
+        Unit {
+            terms: BASE_UNITS
+                .iter()
+                .zip(dims)
+                .filter(|&(_, &exp)| exp != 0)
+                .map(|(&name, &exp)| (name, exp))
+                .collect(),
+        }
+    }
+
+    /// How many SI base units one of this unit is worth.
+    pub fn factor(&self) -> f64 {
+        self.terms
+            .iter()
+            .map(|&(name, exp)| lookup(name).map_or(1.0, |def| def.factor.powi(exp)))
+            .product()
+    }
+
+    pub fn dims(&self) -> Dims {
+        let mut dims = NONE;
+        for &(name, exp) in &self.terms {
+            if let Some(def) = lookup(name) {
+                for (total, base) in dims.iter_mut().zip(&def.dims) {
+                    *total = total.saturating_add(base.saturating_mul(exp));
+                }
+            }
+        }
+        dims
+    }
+
+    pub fn is_none(&self) -> bool {
+        self.terms.is_empty()
+    }
+
+    pub fn mul(&self, other: &Unit) -> Unit {
+        let mut terms = self.terms.clone();
+        for &(name, exp) in &other.terms {
+            match terms.iter_mut().find(|(have, _)| *have == name) {
+                Some((_, total)) => *total = total.saturating_add(exp),
+                None => terms.push((name, exp)),
+            }
+        }
+        terms.retain(|&(_, exp)| exp != 0);
+        Unit { terms }
+    }
+
+    pub fn pow(&self, exp: i32) -> Unit {
+        let mut terms: Vec<_> = self
+            .terms
+            .iter()
+            .map(|&(name, e)| (name, e.saturating_mul(exp)))
+            .collect();
+        terms.retain(|&(_, exp)| exp != 0);
+        Unit { terms }
+    }
+}
+
+impl fmt::Display for Unit {
+    /// Units with positive powers, then a `/` and the ones with negative powers: `J/(kg*K)`.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fn product(terms: &[(&str, i32)]) -> String {
+            let parts: Vec<String> = terms
+                .iter()
+                .map(|&(name, exp)| match exp {
+                    1 => name.to_string(),
+                    _ => format!("{}^{}", name, exp),
+                })
+                .collect();
+            parts.join("*")
+        }
+
+        let (num, den): (Vec<_>, Vec<_>) = self.terms.iter().partition(|&&(_, exp)| exp > 0);
+        let den: Vec<_> = den.into_iter().map(|(name, exp)| (name, -exp)).collect();
+
+        match (num.is_empty(), den.len()) {
+            (true, 0) => Ok(()),
+            (_, 0) => write!(f, "{}", product(&num)),
+            (true, _) => write!(f, "1/{}", product(&den)),
+            (false, 1) => write!(f, "{}/{}", product(&num), product(&den)),
+            (false, _) => write!(f, "{}/({})", product(&num), product(&den)),
+        }
+    }
+}
+
+/// A number measured in some unit.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Quantity {
+    pub value: f64,
+    pub unit: Unit,
+}
+
+/// Two quantities whose dimensions don't fit together; each side is written in SI base units.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DimensionMismatch(pub Unit, pub Unit);
+
+impl Quantity {
+    pub fn new(value: f64, unit: Unit) -> Self {
+        Quantity { value, unit }
+    }
+
+    /// The value in SI base units.
+    pub fn si(&self) -> f64 {
+        self.value * self.unit.factor()
+    }
+
+    /// The same quantity measured in another unit of the same dimension.
+    pub fn to(&self, unit: &Unit) -> Result<Quantity, DimensionMismatch> {
+        let (have, want) = (self.unit.dims(), unit.dims());
+        if have != want {
+            return Err(DimensionMismatch(Unit::base(&have), Unit::base(&want)));
+        }
+        Ok(Quantity::new(self.si() / unit.factor(), unit.clone()))
+    }
+
+    /// Sum of two quantities, in the unit of the first.
+    pub fn add(&self, other: &Quantity) -> Result<Quantity, DimensionMismatch> {
+        let other = other
+            .to(&self.unit)
+            .map_err(|DimensionMismatch(theirs, ours)| DimensionMismatch(ours, theirs))?;
+        Ok(Quantity::new(self.value + other.value, self.unit.clone()))
+    }
+
+    /// Difference of two quantities, in the unit of the first.
+    pub fn sub(&self, other: &Quantity) -> Result<Quantity, DimensionMismatch> {
+        self.add(&Quantity::new(-other.value, other.unit.clone()))
+    }
+
+    pub fn mul(&self, other: &Quantity) -> Quantity {
+        Quantity::new(self.value * other.value, self.unit.mul(&other.unit))
+    }
+
+    pub fn div(&self, other: &Quantity) -> Quantity {
+        Quantity::new(self.value / other.value, self.unit.mul(&other.unit.pow(-1)))
+    }
+
+    pub fn pow(&self, exp: i32) -> Quantity {
+        Quantity::new(self.value.powi(exp), self.unit.pow(exp))
+    }
+
+    /// Whether two quantities are the same amount, up to rounding.
+    pub fn same_as(&self, other: &Quantity) -> bool {
+        let (a, b) = (self.si(), other.si());
+        self.unit.dims() == other.unit.dims()
+            && (a == b || (a - b).abs() <= 1e-12 * a.abs().max(b.abs()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn qty(value: f64, unit: &str) -> Quantity {
+        let tokens: Vec<String> = unit.split_whitespace().map(String::from).collect();
+        Quantity::new(value, Unit::parse(&tokens).unwrap())
+    }
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() <= 1e-9 * a.abs().max(b.abs())
+    }
+
+    #[test]
+    fn test_parse_display() {
+        let show = |unit: &str| qty(1.0, unit).unit.to_string();
+        assert_eq!(show("km / h"), "km/h");
+        assert_eq!(show("kg m^2 / s^2"), "kg*m^2/s^2");
+        assert_eq!(show("J / kg K"), "J/(kg*K)");
+        assert_eq!(show("/ s"), "1/s");
+        assert_eq!(show("m m / m"), "m");
+        assert_eq!(show(""), "");
+        assert!(Unit::parse(&[String::from("furlong")]).is_none());
+        assert!(Unit::parse(&[String::from("m^x")]).is_none());
+    }
+
+    #[test]
+    fn test_convert() {
+        assert!(close(
+            qty(5.0, "km").to(&qty(1.0, "mi").unit).unwrap().value,
+            3.106_855_961_186_669_5
+        ));
+        assert!(close(
+            qty(1.8e7, "J").to(&qty(1.0, "kWh").unit).unwrap().value,
+            5.0
+        ));
+        assert!(close(
+            qty(100.0, "km / h")
+                .to(&qty(1.0, "m / s").unit)
+                .unwrap()
+                .value,
+            27.777_777_777_777_78
+        ));
+        assert!(close(
+            qty(1.0, "N m").to(&qty(1.0, "J").unit).unwrap().value,
+            1.0
+        ));
+        assert_eq!(
+            qty(1.0, "m").to(&qty(1.0, "s").unit),
+            Err(DimensionMismatch(Unit::base(&LENGTH), Unit::base(&TIME)))
+        );
+    }
+
+    #[test]
+    fn test_arithmetic() {
+        let sum = qty(1.0, "km").add(&qty(500.0, "m")).unwrap();
+        assert_eq!(sum.unit.to_string(), "km");
+        assert!(close(sum.value, 1.5));
+        assert!(qty(1.0, "km").add(&qty(1.0, "kg")).is_err());
+
+        let speed = qty(10.0, "m").div(&qty(4.0, "s"));
+        assert_eq!(
+            (speed.value, speed.unit.to_string()),
+            (2.5, String::from("m/s"))
+        );
+        let energy = qty(2.0, "kW").mul(&qty(3.0, "h"));
+        assert!(energy.same_as(&qty(6.0, "kWh")));
+        assert!(qty(3.0, "m").pow(2).same_as(&qty(90_000.0, "cm^2")));
+        assert_eq!(Unit::base(&ENERGY).to_string(), "kg*m^2/s^2");
+    }
+}
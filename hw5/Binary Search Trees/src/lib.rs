@@ -8,17 +8,23 @@
 #![allow(unused_imports)]
 #![forbid(unsafe_code)]
 
+use std::borrow::Borrow;
 use std::cmp::Ordering;
-use std::fmt::Debug;
-use std::iter::{FromIterator, IntoIterator};
+use std::collections::VecDeque;
+use std::fmt::{self, Debug};
+use std::iter::{FromIterator, FusedIterator, IntoIterator};
 use std::ops::{Bound, Index, IndexMut, RangeBounds};
 
 /// Type of BST nodes.
+///
+/// Besides the key and value, each node caches the size and the height of the subtree rooted at
+/// it. The height is what keeps the tree balanced (see `rebalance`).
 #[derive(Debug)]
 struct Node<K, V> {
     key: K,
     val: V,
     size: usize,
+    height: usize,
     lt: TreeMap<K, V>,
     rt: TreeMap<K, V>,
 }
@@ -31,6 +37,64 @@ pub struct TreeMap<K, V> {
     inner: Option<Box<Node<K, V>>>,
 }
 
+/// Nodes on the way down from the root, each with the side the path continues on.
+type Path<K, V> = Vec<(Box<Node<K, V>>, Ordering)>;
+
+/// Drop the nodes in a loop rather than recursively, in the same order as the default `Drop`: the
+/// root, then the left subtree, then the right subtree.
+impl<K, V> Drop for TreeMap<K, V> {
+    fn drop(&mut self) {
+        let mut stack: Vec<Box<Node<K, V>>> = self.inner.take().into_iter().collect();
+        while let Some(mut b) = stack.pop() {
+            let lt = b.lt.inner.take();
+            let rt = b.rt.inner.take();
+            drop(b);
+            stack.extend(rt);
+            stack.extend(lt);
+        }
+    }
+}
+
+impl<K, V> Node<K, V>
+where
+    K: Ord + Debug,
+    V: Debug,
+{
+    /// Make a new leaf node.
+    fn new(key: K, val: V) -> Self {
+        Node {
+            key,
+            val,
+            size: 1,
+            height: 1,
+            lt: TreeMap::new(),
+            rt: TreeMap::new(),
+        }
+    }
+
+    /// Recompute the size and height of a node from its children.
+    fn update(&mut self) {
+        self.size = self.lt.len() + self.rt.len() + 1;
+        self.height = self.lt.height().max(self.rt.height()) + 1;
+    }
+
+    /// The left child for `Less`, and the right child otherwise, like the sides of a `Path`.
+    fn child(&self, side: Ordering) -> &TreeMap<K, V> {
+        match side {
+            Ordering::Less => &self.lt,
+            _ => &self.rt,
+        }
+    }
+
+    /// The child on `side`, mutably.
+    fn child_mut(&mut self, side: Ordering) -> &mut TreeMap<K, V> {
+        match side {
+            Ordering::Less => &mut self.lt,
+            _ => &mut self.rt,
+        }
+    }
+}
+
 /// Part 1: Basic operations and traits
 ///
 /// Your first task is to implement the following operations on BSTs.
@@ -52,69 +116,51 @@ where
         self.inner = None
     }
 
-    /// Check if a `TreeMap` is empty.
-    pub fn is_empty(&self) -> bool {
-        TreeMap::len(&self) == 0
-    }
-
-    /// Compute the size of a `TreeMap`.
-    pub fn len(&self) -> usize {
-        match &self.inner {
-            Some(b) => (*b).size,
-            None => 0,
-        }
-    }
-
     /// Check if a `TreeMap` has a certain key.
-    pub fn has_key(&self, key: &K) -> bool {
-        match &self.inner {
-            Some(b) => {
-                if (*b).key == *key {
-                    true
-                } else if (*b).key > *key {
-                    (*b).lt.has_key(key)
-                } else {
-                    (*b).rt.has_key(key)
-                }
-            }
-            None => false,
-        }
+    pub fn has_key<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        self.get(key).is_some()
     }
 
     /// Get a reference to the value associated with a key, if present.
     ///
     /// If the key is not in the map, return `None`.
-    pub fn get(&self, key: &K) -> Option<&V> {
-        match &self.inner {
-            Some(b) => {
-                if (*b).key == *key {
-                    Some(&(*b).val)
-                } else if (*b).key > *key {
-                    (*b).lt.get(key)
-                } else {
-                    (*b).rt.get(key)
-                }
+    pub fn get<Q>(&self, key: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let mut tree = self;
+        while let Some(b) = &tree.inner {
+            match key.cmp(b.key.borrow()) {
+                Ordering::Equal => return Some(&b.val),
+                Ordering::Less => tree = &b.lt,
+                Ordering::Greater => tree = &b.rt,
             }
-            None => None,
         }
+        None
     }
 
     /// Get a mutable reference to the value associated with a key, if present.
     ///
     /// If the key is not in the map, return `None`.
-    pub fn get_mut(&mut self, key: &K) -> Option<&mut V> {
-        match &mut self.inner {
-            Some(b) => {
-                if (*b).key == *key {
-                    Some(&mut (*b).val)
-                } else if (*b).key > *key {
-                    (*b).lt.get_mut(key)
-                } else {
-                    (*b).rt.get_mut(key)
-                }
+    pub fn get_mut<Q>(&mut self, key: &Q) -> Option<&mut V>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let mut tree = self;
+        while let Some(b) = &mut tree.inner {
+            match key.cmp(b.key.borrow()) {
+                Ordering::Equal => return Some(&mut b.val),
+                Ordering::Less => tree = &mut b.lt,
+                Ordering::Greater => tree = &mut b.rt,
             }
-            None => None,
         }
+        None
     }
 
     /// Insert a (key, value) pair into a TreeMap.
@@ -122,106 +168,892 @@ where
     /// If the key is already present in the map, return the previous value and replace the old
     /// value with the new value. Otherwise, insert the new (key, value) pair and return `None`.
     pub fn insert(&mut self, key: K, val: V) -> Option<V> {
-        match &mut self.inner {
-            Some(b) => {
-                if (*b).key == key {
-                    let old_value = std::mem::replace(&mut b.val, val);
-                    Some(old_value)
-                } else if (*b).key > key {
-                    (*b).size += 1;
-                    (*b).lt.insert(key, val)
-                } else {
-                    (*b).size += 1;
-                    (*b).rt.insert(key, val)
+        let (mut tree, path) = self.split_path(|b| key.cmp(&b.key));
+        let old_value = match &mut tree.inner {
+            Some(b) => Some(std::mem::replace(&mut b.val, val)),
+            None => {
+                tree.inner = Some(Box::new(Node::new(key, val)));
+                None
+            }
+        };
+        *self = TreeMap::rebuild(tree, path);
+        old_value
+    }
+
+    /// Remove a key from a `TreeMap`.
+    ///
+    /// If the map contains the key, remove the key and return the associated value.
+    /// If the map does not contain the key, return None and leave the map unchanged.
+    ///
+    /// A node with two children is replaced by its successor, the smallest node of its right
+    /// subtree.
+    pub fn remove<Q>(&mut self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        self.remove_by(|b| key.cmp(b.key.borrow()))
+            .map(|(_, val)| val)
+    }
+
+    /// Remove the node `split_path` finds following `dir`, if any, and return its key and value.
+    fn remove_by(&mut self, dir: impl FnMut(&Node<K, V>) -> Ordering) -> Option<(K, V)> {
+        let (mut tree, path) = self.split_path(dir);
+        let removed = tree.remove_root();
+        *self = TreeMap::rebuild(tree, path);
+        removed
+    }
+
+    /// Remove the root node, if any, and return its key and value. A root with two children is
+    /// replaced by its successor.
+    fn remove_root(&mut self) -> Option<(K, V)> {
+        self.inner.take().map(|mut b| {
+            self.inner = match b.rt.pop_min() {
+                Some(mut succ) => {
+                    succ.lt.inner = b.lt.inner.take();
+                    succ.rt.inner = b.rt.inner.take();
+                    Some(succ)
+                }
+                None => b.lt.inner.take(),
+            };
+            self.rebalance();
+            let Node { key, val, .. } = *b;
+            (key, val)
+        })
+    }
+
+    /// Remove the node with the smallest key, and return it as a leaf.
+    fn pop_min(&mut self) -> Option<Box<Node<K, V>>> {
+        let (_, mut path) = self.split_path(|_| Ordering::Less);
+        let (mut min, _) = path.pop()?;
+        let rest = TreeMap {
+            inner: min.rt.inner.take(),
+        };
+        *self = TreeMap::rebuild(rest, path);
+        min.update();
+        Some(min)
+    }
+
+    /// Remove the node with the largest key, and return it as a leaf.
+    fn pop_max(&mut self) -> Option<Box<Node<K, V>>> {
+        let (_, mut path) = self.split_path(|_| Ordering::Greater);
+        let (mut max, _) = path.pop()?;
+        let rest = TreeMap {
+            inner: max.lt.inner.take(),
+        };
+        *self = TreeMap::rebuild(rest, path);
+        max.update();
+        Some(max)
+    }
+
+    /// Take the tree apart along a path from the root: `dir` compares the key being looked for to
+    /// the key of each node, and says which way to go. Returns the subtree where the path ends,
+    /// either at a node where `dir` says `Equal` or at an empty subtree, and the nodes above it
+    /// from the root down, each with the child on the path cut off and the side it was on.
+    ///
+    /// This leaves `self` empty; `rebuild` puts the tree back together.
+    fn split_path(&mut self, mut dir: impl FnMut(&Node<K, V>) -> Ordering) -> (Self, Path<K, V>) {
+        let mut path = Vec::new();
+        let mut tree = TreeMap {
+            inner: self.inner.take(),
+        };
+        while let Some(mut b) = tree.inner.take() {
+            let side = dir(&b);
+            let child = match side {
+                Ordering::Equal => {
+                    tree.inner = Some(b);
+                    break;
                 }
+                Ordering::Less => b.lt.inner.take(),
+                Ordering::Greater => b.rt.inner.take(),
+            };
+            path.push((b, side));
+            tree.inner = child;
+        }
+        (tree, path)
+    }
+
+    /// Undo `split_path`: hang `tree` back under the nodes of `path`, from the bottom up,
+    /// rebalancing each one on the way.
+    fn rebuild(mut tree: Self, mut path: Path<K, V>) -> Self {
+        while let Some((mut b, side)) = path.pop() {
+            match side {
+                Ordering::Less => b.lt = tree,
+                _ => b.rt = tree,
             }
-            None => {
-                let new_node: Node<K, V> = Node {
-                    key,
-                    val,
-                    size: 1,
-                    lt: TreeMap::new(),
-                    rt: TreeMap::new(),
-                };
-                self.inner = Some(Box::new(new_node));
+            tree = TreeMap { inner: Some(b) };
+            tree.rebalance();
+        }
+        tree
+    }
 
-                None
+    /// The number of keys in the tree smaller than `key`, and whether `key` itself is in it.
+    fn rank_of<Q>(&self, key: &Q) -> (usize, bool)
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let mut tree = self;
+        let mut rank = 0;
+        while let Some(b) = &tree.inner {
+            match key.cmp(b.key.borrow()) {
+                Ordering::Equal => return (rank + b.lt.len(), true),
+                Ordering::Less => tree = &b.lt,
+                Ordering::Greater => {
+                    rank += b.lt.len() + 1;
+                    tree = &b.rt;
+                }
             }
         }
+        (rank, false)
     }
 
-    /// Insert a nonempty `TreeMap` into a `TreeMap`.
-    ///
-    /// While real BSTs do careful rotations/rebalancing to keep the depth of the merged tree as
-    /// small as possible, we will just do a naive insert by going down to an appropriate leaf of
-    /// `self` and sticking `other` there.
+    /// The node with `rank` smaller keys in the tree, if there is one.
+    fn node_at(&self, mut rank: usize) -> Option<&Node<K, V>> {
+        let mut tree = self;
+        while let Some(b) = &tree.inner {
+            let lsize = b.lt.len();
+            match rank.cmp(&lsize) {
+                Ordering::Equal => return Some(b),
+                Ordering::Less => tree = &b.lt,
+                Ordering::Greater => {
+                    rank -= lsize + 1;
+                    tree = &b.rt;
+                }
+            }
+        }
+        None
+    }
+
+    /// Hang a new leaf at the bottom of `path`, which ends at an empty subtree, and put `map`
+    /// back together around it. Returns the leaf's value.
     ///
-    /// Since this function is not publicly visible (there is no `pub` before `fn`), it is not used
-    /// directly by clients. You may assume that `self` and `other` are non-overlapping: either all
-    /// nodes in self are strictly smaller than all nodes in other, or all nodes in self are
-    /// strictly larger than all nodes in other. Your implementation can panic (or just continue
-    /// silently) if this requirement is not met. However if the trees are not overlapping, you
-    /// must maintain the BST invariant.
-    fn insert_tree(&mut self, other: &mut Self) {
-        match &mut other.inner {
-            Some(b) => match &mut self.inner {
-                Some(b1) => {
-                    if (*b1).key == (*b).key {
-                        panic!("non-overlapping condition not met!");
-                    } else if (*b1).key > (*b).key {
-                        (*b1).size += (*b).size;
-                        (*b1).lt.insert_tree(other);
-                    } else {
-                        (*b1).size += (*b).size;
-                        (*b1).rt.insert_tree(other);
+    /// Unlike `rebuild`, this puts the nodes back from the top down, so that the leaf comes last
+    /// and the reference to its value can outlive the walk. So the rebalancing is worked out
+    /// first: a new leaf unbalances at most one node that matters, the lowest one whose subtrees
+    /// now differ in height by two, and a single or double rotation there restores its old height.
+    fn insert_leaf(map: &mut Self, mut path: Path<K, V>, mut leaf: Box<Node<K, V>>) -> &mut V {
+        // Find the lowest node that the leaf unbalances, if any
+        let mut height = 1;
+        let mut unbalanced = None;
+        for (idx, (b, side)) in path.iter().enumerate().rev() {
+            let other = b.child(side.reverse()).height();
+            if height > other + 1 {
+                unbalanced = Some(idx);
+                break;
+            }
+            height = height.max(other) + 1;
+        }
+
+        // Rotate the path there. Its nodes keep their cut children; the others get theirs back.
+        if let Some(top) = unbalanced {
+            let mut rest = path.split_off(top + 2).into_iter();
+            let (mut child, child_side) = path
+                .pop()
+                .expect("the unbalanced node's child is on the path");
+            let (mut root, side) = path.pop().expect("the unbalanced node is on the path");
+            let other = side.reverse();
+            if child_side == side {
+                // The child takes the root's place, and the root moves down the other side
+                root.child_mut(side).inner = child.child_mut(other).inner.take();
+                root.update();
+                child.child_mut(other).inner = Some(root);
+                path.push((child, side));
+            } else {
+                // The grandchild takes the root's place, with the child and root on either side
+                match rest.next() {
+                    Some((mut grand, grand_side)) if grand_side == side => {
+                        root.child_mut(side).inner = grand.child_mut(other).inner.take();
+                        root.update();
+                        grand.child_mut(other).inner = Some(root);
+                        path.push((grand, side));
+                        path.push((child, other));
+                    }
+                    Some((mut grand, _)) => {
+                        child.child_mut(other).inner = grand.child_mut(side).inner.take();
+                        child.update();
+                        grand.child_mut(side).inner = Some(child);
+                        path.push((grand, other));
+                        path.push((root, side));
+                    }
+                    None => {
+                        root.update();
+                        child.update();
+                        leaf.child_mut(side).inner = Some(child);
+                        leaf.child_mut(other).inner = Some(root);
+                        leaf.update();
                     }
                 }
-                None => {
-                    self.inner = Option::take(&mut other.inner);
+            }
+            path.extend(rest);
+        }
+
+        // Every node on the path has one more key below it, and maybe a new height
+        let (mut size, mut height) = (leaf.size, leaf.height);
+        for (b, side) in path.iter_mut().rev() {
+            let other = b.child(side.reverse());
+            let (other_size, other_height) = (other.len(), other.height());
+            b.size = size + other_size + 1;
+            b.height = height.max(other_height) + 1;
+            size = b.size;
+            height = b.height;
+        }
+
+        let slot = Split::reattach(map, TreeMap::new(), path);
+        &mut slot.inner.insert(leaf).val
+    }
+
+    /// Get the entry for a key, to look at, update, insert or remove it in place. The entry keeps
+    /// the map taken apart along the path to the key, so using it doesn't walk down again.
+    pub fn entry(&mut self, key: K) -> Entry<'_, K, V> {
+        let (tree, path) = self.split_path(|b| key.cmp(&b.key));
+        let vacant = tree.is_empty();
+        let split = Split {
+            map: Some(self),
+            tree,
+            path,
+        };
+        if vacant {
+            Entry::Vacant(VacantEntry { key, split })
+        } else {
+            Entry::Occupied(OccupiedEntry { split })
+        }
+    }
+
+    /// Height of the tree: 0 if it is empty, 1 if it is a single node, and so on.
+    fn height(&self) -> usize {
+        match &self.inner {
+            Some(b) => b.height,
+            None => 0,
+        }
+    }
+
+    /// Height of the left subtree minus the height of the right subtree.
+    fn balance(&self) -> isize {
+        match &self.inner {
+            Some(b) => b.lt.height() as isize - b.rt.height() as isize,
+            None => 0,
+        }
+    }
+
+    /// Turn the left child into the root, and the old root into its right child.
+    fn rotate_right(&mut self) {
+        if let Some(mut root) = self.inner.take() {
+            match root.lt.inner.take() {
+                Some(mut pivot) => {
+                    root.lt.inner = pivot.rt.inner.take();
+                    root.update();
+                    pivot.rt.inner = Some(root);
+                    pivot.update();
+                    self.inner = Some(pivot);
+                }
+                None => self.inner = Some(root),
+            }
+        }
+    }
+
+    /// Turn the right child into the root, and the old root into its left child.
+    fn rotate_left(&mut self) {
+        if let Some(mut root) = self.inner.take() {
+            match root.rt.inner.take() {
+                Some(mut pivot) => {
+                    root.rt.inner = pivot.lt.inner.take();
+                    root.update();
+                    pivot.lt.inner = Some(root);
+                    pivot.update();
+                    self.inner = Some(pivot);
                 }
-            },
-            None => (),
+                None => self.inner = Some(root),
+            }
         }
     }
 
-    /// Remove a key from a `TreeMap`.
+    /// Restore the AVL invariant at the root after one of its subtrees changed: the heights of the
+    /// two subtrees of every node differ by at most one, so a tree with `n` nodes is less than
+    /// `1.45 * log2(n + 2)` high.
     ///
-    /// If the map contains the key, remove the key and return the associated value.
-    /// If the map does not contain the key, return None and leave the map unchanged.
+    /// Both subtrees must already be balanced, with heights differing by at most two; this is the
+    /// case after inserting or removing a single node below the root. Also updates the size and
+    /// height of the root.
+    fn rebalance(&mut self) {
+        let b = match &mut self.inner {
+            Some(b) => b,
+            None => return,
+        };
+        b.update();
+        let balance = self.balance();
+        if balance > 1 {
+            if let Some(b) = &mut self.inner {
+                if b.lt.balance() < 0 {
+                    b.lt.rotate_left();
+                }
+            }
+            self.rotate_right();
+        } else if balance < -1 {
+            if let Some(b) = &mut self.inner {
+                if b.rt.balance() > 0 {
+                    b.rt.rotate_right();
+                }
+            }
+            self.rotate_left();
+        }
+    }
+}
+
+/// Order statistics
+///
+/// Since every node knows the size of its subtree, we can find the key with a given rank (the
+/// number of smaller keys), or the rank of a key, in one walk down the tree.
+impl<K, V> TreeMap<K, V>
+where
+    K: Ord + Debug,
+    V: Debug,
+{
+    /// The entry with `k` smaller keys in the map, that is, the `k`-th smallest counting from 0.
+    /// Returns `None` if the map has `k` entries or fewer.
+    pub fn select(&self, k: usize) -> Option<(&K, &V)> {
+        self.node_at(k).map(|b| (&b.key, &b.val))
+    }
+
+    /// The number of keys in the map smaller than `key`, whether or not `key` is in it.
+    pub fn rank<Q>(&self, key: &Q) -> usize
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        self.rank_of(key).0
+    }
+
+    /// The number of keys in `range`, without visiting them.
+    pub fn range_count<Q, R>(&self, range: R) -> usize
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+        R: RangeBounds<Q>,
+    {
+        // The number of keys before the start and up to the end of the range
+        let before = match range.start_bound() {
+            Bound::Included(start) => self.rank(start),
+            Bound::Excluded(start) => {
+                let (rank, found) = self.rank_of(start);
+                rank + found as usize
+            }
+            Bound::Unbounded => 0,
+        };
+        let through = match range.end_bound() {
+            Bound::Included(end) => {
+                let (rank, found) = self.rank_of(end);
+                rank + found as usize
+            }
+            Bound::Excluded(end) => self.rank(end),
+            Bound::Unbounded => self.len(),
+        };
+        through.saturating_sub(before)
+    }
+}
+
+/// Navigation
+///
+/// The smallest and largest entries, and the entries nearest to a key that may or may not be in
+/// the map. The searches for nearby keys walk down the tree once, remembering the last node that
+/// was on the right side of the key.
+impl<K, V> TreeMap<K, V>
+where
+    K: Ord + Debug,
+    V: Debug,
+{
+    /// The entry with the smallest key, if the map isn't empty.
+    pub fn first_key_value(&self) -> Option<(&K, &V)> {
+        self.select(0)
+    }
+
+    /// The entry with the largest key, if the map isn't empty.
+    pub fn last_key_value(&self) -> Option<(&K, &V)> {
+        self.select(self.len().checked_sub(1)?)
+    }
+
+    /// Remove and return the entry with the smallest key, if the map isn't empty.
+    pub fn pop_first(&mut self) -> Option<(K, V)> {
+        let Node { key, val, .. } = *self.pop_min()?;
+        Some((key, val))
+    }
+
+    /// Remove and return the entry with the largest key, if the map isn't empty.
+    pub fn pop_last(&mut self) -> Option<(K, V)> {
+        let Node { key, val, .. } = *self.pop_max()?;
+        Some((key, val))
+    }
+
+    /// The entry with the greatest key less than or equal to `key`.
+    pub fn floor<Q>(&self, key: &Q) -> Option<(&K, &V)>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        self.last_where(|k| k.borrow() <= key)
+    }
+
+    /// The entry with the smallest key greater than or equal to `key`.
+    pub fn ceiling<Q>(&self, key: &Q) -> Option<(&K, &V)>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        self.first_where(|k| k.borrow() >= key)
+    }
+
+    /// The entry with the greatest key strictly less than `key`: its predecessor, if `key` is in
+    /// the map.
+    pub fn lower<Q>(&self, key: &Q) -> Option<(&K, &V)>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        self.last_where(|k| k.borrow() < key)
+    }
+
+    /// The entry with the smallest key strictly greater than `key`: its successor, if `key` is in
+    /// the map.
+    pub fn higher<Q>(&self, key: &Q) -> Option<(&K, &V)>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        self.first_where(|k| k.borrow() > key)
+    }
+
+    /// The entry with the greatest key for which `before` holds, where `before` holds for all keys
+    /// up to some point and for none after it.
+    fn last_where(&self, before: impl Fn(&K) -> bool) -> Option<(&K, &V)> {
+        let mut found = None;
+        let mut tree = self;
+        while let Some(b) = &tree.inner {
+            if before(&b.key) {
+                found = Some((&b.key, &b.val));
+                tree = &b.rt;
+            } else {
+                tree = &b.lt;
+            }
+        }
+        found
+    }
+
+    /// The entry with the smallest key for which `after` holds, where `after` holds for all keys
+    /// from some point on and for none before it.
+    fn first_where(&self, after: impl Fn(&K) -> bool) -> Option<(&K, &V)> {
+        let mut found = None;
+        let mut tree = self;
+        while let Some(b) = &tree.inner {
+            if after(&b.key) {
+                found = Some((&b.key, &b.val));
+                tree = &b.lt;
+            } else {
+                tree = &b.rt;
+            }
+        }
+        found
+    }
+}
+
+/// Splitting and joining
+///
+/// Two balanced trees whose keys don't interleave can be joined under a middle node in time
+/// proportional to the difference of their heights: go down the side of the higher tree until
+/// the heights match, hang the lower tree there, and rebalance on the way back up. Splitting a
+/// tree at a key joins the pieces hanging off the path to the key, and also takes O(log n) steps
+/// in all, since the heights of the pieces add up along the path.
+impl<K, V> TreeMap<K, V>
+where
+    K: Ord + Debug,
+    V: Debug,
+{
+    /// Split the map in two at `key`: the entries with smaller keys stay in `self`, and the
+    /// others are returned in a new map.
+    pub fn split_off<Q>(&mut self, key: &Q) -> Self
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let (_, mut path) = self.split_path(|b| {
+            if key <= b.key.borrow() {
+                Ordering::Less
+            } else {
+                Ordering::Greater
+            }
+        });
+        let mut lt = TreeMap::new();
+        let mut rt = TreeMap::new();
+        // The nodes where the path turned left go to the right of the split with their right
+        // subtrees, and the other way around
+        while let Some((mut b, side)) = path.pop() {
+            if side == Ordering::Less {
+                let b_rt = TreeMap {
+                    inner: b.rt.inner.take(),
+                };
+                rt = TreeMap::join3(rt, b, b_rt);
+            } else {
+                let b_lt = TreeMap {
+                    inner: b.lt.inner.take(),
+                };
+                lt = TreeMap::join3(b_lt, b, lt);
+            }
+        }
+        *self = lt;
+        rt
+    }
+
+    /// Move all entries of `other` into `self`, when the keys of one map all come before the keys
+    /// of the other, in O(log n) steps. If their keys interleave or overlap, nothing changes and
+    /// `other` is handed back.
+    pub fn join(&mut self, other: Self) -> Result<(), Self> {
+        let before = match (self.last_key_value(), other.first_key_value()) {
+            (Some((last, _)), Some((first, _))) => last < first,
+            _ => true,
+        };
+        let after = match (other.last_key_value(), self.first_key_value()) {
+            (Some((last, _)), Some((first, _))) => last < first,
+            _ => true,
+        };
+        let this = std::mem::replace(self, TreeMap::new());
+        let (lt, mut rt) = match (before, after) {
+            (true, _) => (this, other),
+            (false, true) => (other, this),
+            (false, false) => {
+                *self = this;
+                return Err(other);
+            }
+        };
+        *self = match rt.pop_min() {
+            Some(mid) => TreeMap::join3(lt, mid, rt),
+            None => lt,
+        };
+        Ok(())
+    }
+
+    /// Move all entries of `other` into `self`, leaving `other` empty. If a key is in both maps,
+    /// the value from `other` wins.
     ///
-    /// Hint: take the two child trees of the node you're removing, insert one into the other.
-    pub fn remove(&mut self, key: K) -> Option<V> {
-        let owned_data = Option::take(&mut self.inner);
-        match owned_data {
-            Some(mut b) => {
-                if b.key == key {
-                    let associated_value = b.val;
-                    b.lt.insert_tree(&mut b.rt);
-                    *self = TreeMap {
-                        inner: Option::take(&mut b.lt.inner),
-                    };
-                    Some(associated_value)
+    /// Maps whose keys don't interleave are joined in O(log n) steps; otherwise the entries of the
+    /// smaller map are inserted into the larger one.
+    pub fn append(&mut self, other: &mut Self) {
+        let other = std::mem::replace(other, TreeMap::new());
+        if let Err(other) = self.join(other) {
+            if self.len() >= other.len() {
+                for (key, val) in other {
+                    self.insert(key, val);
+                }
+            } else {
+                let this = std::mem::replace(self, other);
+                for (key, val) in this {
+                    self.entry(key).or_insert(val);
+                }
+            }
+        }
+    }
+
+    /// Join `lt`, `mid` and `rt` into one balanced tree, when the keys in `lt` are all smaller
+    /// than the key of `mid`, and the keys in `rt` all larger. The children of `mid` are replaced.
+    fn join3(lt: Self, mut mid: Box<Node<K, V>>, rt: Self) -> Self {
+        let (lheight, rheight) = (lt.height(), rt.height());
+        let (mid, path) = if lheight > rheight + 1 {
+            // Hang `rt` on the right side of `lt`, where the heights match
+            let mut lt = lt;
+            let (sub, path) = lt.split_path(|b| {
+                if b.height > rheight + 1 {
+                    Ordering::Greater
                 } else {
-                    let owned_value: Option<V>;
+                    Ordering::Equal
+                }
+            });
+            mid.lt = sub;
+            mid.rt = rt;
+            (mid, path)
+        } else if rheight > lheight + 1 {
+            let mut rt = rt;
+            let (sub, path) = rt.split_path(|b| {
+                if b.height > lheight + 1 {
+                    Ordering::Less
+                } else {
+                    Ordering::Equal
+                }
+            });
+            mid.lt = lt;
+            mid.rt = sub;
+            (mid, path)
+        } else {
+            mid.lt = lt;
+            mid.rt = rt;
+            (mid, Vec::new())
+        };
+        let mut tree = TreeMap { inner: Some(mid) };
+        tree.rebalance();
+        TreeMap::rebuild(tree, path)
+    }
+}
+
+/// A view into a single entry of a `TreeMap`, which is either vacant or occupied. Made by
+/// `TreeMap::entry`.
+#[derive(Debug)]
+pub enum Entry<'a, K, V> {
+    Vacant(VacantEntry<'a, K, V>),
+    Occupied(OccupiedEntry<'a, K, V>),
+}
+
+/// An entry for a key that isn't in the map.
+#[derive(Debug)]
+pub struct VacantEntry<'a, K, V> {
+    key: K,
+    split: Split<'a, K, V>,
+}
+
+/// An entry for a key that is in the map.
+#[derive(Debug)]
+pub struct OccupiedEntry<'a, K, V> {
+    split: Split<'a, K, V>,
+}
+
+/// A map taken apart by `split_path` for an entry: the subtree where the key is or would be, and
+/// the nodes above it. Dropping it puts the map back together as it was.
+#[derive(Debug)]
+struct Split<'a, K, V> {
+    map: Option<&'a mut TreeMap<K, V>>,
+    tree: TreeMap<K, V>,
+    path: Path<K, V>,
+}
+
+/// The map of a `Split`, still empty, with the subtree and the path that go back into it.
+type SplitParts<'a, K, V> = (&'a mut TreeMap<K, V>, TreeMap<K, V>, Path<K, V>);
+
+// An occupied entry's subtree is never empty, and the map only goes back together once
+const OCCUPIED: &str = "an occupied entry holds its node";
+const SPLIT: &str = "the map is still taken apart";
+
+impl<'a, K, V> Split<'a, K, V> {
+    /// Hand over the map and its pieces, leaving nothing to put back on drop.
+    fn into_parts(mut self) -> SplitParts<'a, K, V> {
+        let map = self.map.take().expect(SPLIT);
+        let tree = TreeMap {
+            inner: self.tree.inner.take(),
+        };
+        (map, tree, std::mem::take(&mut self.path))
+    }
+
+    /// Hang `tree` back under the nodes of `path` from the top down, without rebalancing, and
+    /// return the place in `map` where it ended up. The nodes' sizes and heights must be right.
+    fn reattach(
+        map: &'a mut TreeMap<K, V>,
+        tree: TreeMap<K, V>,
+        path: Path<K, V>,
+    ) -> &'a mut TreeMap<K, V> {
+        let mut slot = map;
+        for (b, side) in path {
+            slot = match side {
+                Ordering::Less => &mut slot.inner.insert(b).lt,
+                _ => &mut slot.inner.insert(b).rt,
+            };
+        }
+        *slot = tree;
+        slot
+    }
+}
+
+/// An entry that wasn't used up puts the map back unchanged.
+impl<K, V> Drop for Split<'_, K, V> {
+    fn drop(&mut self) {
+        if let Some(map) = self.map.take() {
+            let tree = TreeMap {
+                inner: self.tree.inner.take(),
+            };
+            Split::reattach(map, tree, std::mem::take(&mut self.path));
+        }
+    }
+}
+
+impl<'a, K, V> Entry<'a, K, V>
+where
+    K: Ord + Debug,
+    V: Debug,
+{
+    /// The key of the entry.
+    pub fn key(&self) -> &K {
+        match self {
+            Entry::Vacant(entry) => entry.key(),
+            Entry::Occupied(entry) => entry.key(),
+        }
+    }
+
+    /// Insert `default` if the entry is vacant, and return a mutable reference to the value.
+    pub fn or_insert(self, default: V) -> &'a mut V {
+        self.or_insert_with(|| default)
+    }
+
+    /// Insert the result of `default` if the entry is vacant, and return a mutable reference to
+    /// the value. `default` is only called if the entry is vacant.
+    pub fn or_insert_with<F: FnOnce() -> V>(self, default: F) -> &'a mut V {
+        match self {
+            Entry::Vacant(entry) => entry.insert(default()),
+            Entry::Occupied(entry) => entry.into_mut(),
+        }
+    }
+
+    /// Insert the default value if the entry is vacant, and return a mutable reference to the
+    /// value.
+    pub fn or_default(self) -> &'a mut V
+    where
+        V: Default,
+    {
+        self.or_insert_with(V::default)
+    }
+
+    /// Update the value in place if the entry is occupied.
+    pub fn and_modify<F: FnOnce(&mut V)>(mut self, f: F) -> Self {
+        if let Entry::Occupied(entry) = &mut self {
+            f(entry.get_mut());
+        }
+        self
+    }
+}
+
+impl<'a, K, V> VacantEntry<'a, K, V>
+where
+    K: Ord + Debug,
+    V: Debug,
+{
+    /// The key that would be inserted.
+    pub fn key(&self) -> &K {
+        &self.key
+    }
+
+    /// Take back the key without inserting anything.
+    pub fn into_key(self) -> K {
+        self.key
+    }
+
+    /// Insert the key with `val`, and return a mutable reference to the value.
+    pub fn insert(self, val: V) -> &'a mut V {
+        let (map, _, path) = self.split.into_parts();
+        TreeMap::insert_leaf(map, path, Box::new(Node::new(self.key, val)))
+    }
+}
+
+impl<'a, K, V> OccupiedEntry<'a, K, V>
+where
+    K: Ord + Debug,
+    V: Debug,
+{
+    fn node(&self) -> &Node<K, V> {
+        self.split.tree.inner.as_ref().expect(OCCUPIED)
+    }
+
+    fn node_mut(&mut self) -> &mut Node<K, V> {
+        self.split.tree.inner.as_mut().expect(OCCUPIED)
+    }
+
+    /// The key in the map.
+    pub fn key(&self) -> &K {
+        &self.node().key
+    }
+
+    /// A reference to the value.
+    pub fn get(&self) -> &V {
+        &self.node().val
+    }
+
+    /// A mutable reference to the value, that lives as long as the entry.
+    pub fn get_mut(&mut self) -> &mut V {
+        &mut self.node_mut().val
+    }
+
+    /// A mutable reference to the value, that lives as long as the map.
+    pub fn into_mut(self) -> &'a mut V {
+        let (map, tree, path) = self.split.into_parts();
+        let slot = Split::reattach(map, tree, path);
+        &mut slot.inner.as_mut().expect(OCCUPIED).val
+    }
+
+    /// Replace the value, and return the old one.
+    pub fn insert(&mut self, val: V) -> V {
+        std::mem::replace(self.get_mut(), val)
+    }
+
+    /// Remove the entry from the map, and return its value.
+    pub fn remove(self) -> V {
+        self.remove_entry().1
+    }
+
+    /// Remove the entry from the map, and return its key and value.
+    pub fn remove_entry(self) -> (K, V) {
+        let (map, mut tree, path) = self.split.into_parts();
+        let removed = tree.remove_root().expect(OCCUPIED);
+        *map = TreeMap::rebuild(tree, path);
+        removed
+    }
+}
+
+/// An invariant of `TreeMap`, checked by `check_invariants`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Invariant {
+    /// Keys in the left subtree of a node are smaller than its key, and keys in the right subtree
+    /// are larger.
+    Ordering,
+    /// Every node records the number of nodes in its subtree.
+    Size,
+    /// Every node records the height of its subtree, and the heights of its two subtrees differ by
+    /// at most one.
+    Balance,
+}
 
-                    if b.key > key {
-                        b.size -= 1;
-                        owned_value = b.lt.remove(key);
-                    } else {
-                        b.size -= 1;
-                        owned_value = b.rt.remove(key);
-                    }
+/// The first invariant `check_invariants` found broken, and the key of the node where it broke.
+#[derive(Debug, PartialEq, Eq)]
+pub struct InvariantError<'a, K> {
+    pub invariant: Invariant,
+    pub key: &'a K,
+}
 
-                    if owned_value.is_none() {
-                        b.size += 1;
-                    }
+impl<'a, K: Debug> fmt::Display for InvariantError<'a, K> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self.invariant {
+            Invariant::Ordering => "ordering",
+            Invariant::Size => "size",
+            Invariant::Balance => "balance",
+        };
+        write!(f, "{} invariant broken at key {:?}", name, self.key)
+    }
+}
 
-                    self.inner = Some(b);
-                    owned_value
-                }
+impl<K, V> TreeMap<K, V>
+where
+    K: Ord + Debug,
+    V: Debug,
+{
+    /// Check the invariants of the tree (see `Invariant`), visiting the nodes in pre-order. The
+    /// public operations keep them, so this is meant for tests: a broken invariant is a bug in
+    /// `TreeMap`.
+    pub fn check_invariants(&self) -> Result<(), InvariantError<'_, K>> {
+        // Each subtree left to check, with the keys its keys have to lie strictly between
+        let mut stack = vec![(self, None, None)];
+        while let Some((tree, lo, hi)) = stack.pop() {
+            let b = match &tree.inner {
+                Some(b) => b,
+                None => continue,
+            };
+            let broken = |invariant| InvariantError {
+                invariant,
+                key: &b.key,
+            };
+            if lo.is_some_and(|lo| &b.key <= lo) || hi.is_some_and(|hi| &b.key >= hi) {
+                return Err(broken(Invariant::Ordering));
+            }
+            if b.size != b.lt.len() + b.rt.len() + 1 {
+                return Err(broken(Invariant::Size));
             }
-            None => None,
+            if b.height != b.lt.height().max(b.rt.height()) + 1 || tree.balance().abs() > 1 {
+                return Err(broken(Invariant::Balance));
+            }
+            stack.push((&b.rt, Some(&b.key), hi));
+            stack.push((&b.lt, lo, Some(&b.key)));
         }
+        Ok(())
     }
 }
 
@@ -277,7 +1109,7 @@ where
 /// reference to the value for a given key. If the key is not in the map, panic. You will probably
 /// want to take a look at the documentation for `Index` and `IndexMut`.
 ///
-/// Note: the Rust `BTreeMap` actually has a more general type for these operations, something like:
+/// Note: the Rust `BTreeMap` actually has a more general type for these operations, and so do we:
 ///
 /// > fn index<Q>(&self, key: &Q) -> &V
 /// > where
@@ -294,14 +1126,15 @@ where
 /// for an example of how this works:
 ///
 /// https://doc.rust-lang.org/std/borrow/trait.Borrow.html
-impl<'a, K, V> Index<&'a K> for TreeMap<K, V>
+impl<K, V, Q> Index<&Q> for TreeMap<K, V>
 where
-    K: Ord + Debug,
+    K: Ord + Debug + Borrow<Q>,
     V: Debug,
+    Q: Ord + ?Sized,
 {
     type Output = V;
 
-    fn index(&self, key: &K) -> &V {
+    fn index(&self, key: &Q) -> &V {
         match self.get(key) {
             Some(value) => value,
             None => panic!("Key is not in the map"),
@@ -309,12 +1142,13 @@ where
     }
 }
 
-impl<'a, K, V> IndexMut<&'a K> for TreeMap<K, V>
+impl<K, V, Q> IndexMut<&Q> for TreeMap<K, V>
 where
-    K: Ord + Debug,
+    K: Ord + Debug + Borrow<Q>,
     V: Debug,
+    Q: Ord + ?Sized,
 {
-    fn index_mut(&mut self, key: &'a K) -> &mut V {
+    fn index_mut(&mut self, key: &Q) -> &mut V {
         match self.get_mut(key) {
             Some(value) => value,
             None => panic!("Key is not in the map"),
@@ -327,63 +1161,53 @@ where
 /// Now, you will soup up your BST by implementing various kinds of iterators to perform in-order
 /// traversals of the map, i.e., from smallest to largest key.
 ///
-/// The first iterator you will design is a so-called *consuming iterator*, which takes ownership
-/// of all elements in the map. You will use the following types to track the current state of the
-/// iterator:
-
+/// All of our iterators track their state the same way: a double-ended queue of `Next`s, holding
+/// the items and whole subtrees left to visit, in order. Taking an item from the front unfolds the
+/// subtree at the front into its left subtree, its root and its right subtree, until an item comes
+/// up; taking one from the back does the same at the back. Each node is unfolded once, and the two
+/// ends of the queue never share a subtree, which is what lets even the mutable iterators run from
+/// both ends. The iterators also count the items left, using the sizes of the subtrees.
 enum Next<I, T> {
     Item(I),
     Tree(T),
 }
 
+// Queues of the items and subtrees left to visit
+type IntoQueue<K, V> = VecDeque<Next<(K, V), TreeMap<K, V>>>;
+type IterQueue<'a, K, V> = VecDeque<Next<(&'a K, &'a V), &'a TreeMap<K, V>>>;
+type IterMutQueue<'a, K, V> = VecDeque<Next<(&'a K, &'a mut V), &'a mut TreeMap<K, V>>>;
+
+// A subtree taken apart: its left subtree, its root and its right subtree, if it isn't empty
+type Unfolded<I, T> = Option<(T, I, T)>;
+
+/// The first iterator is a so-called *consuming iterator*, which takes ownership of all elements
+/// in the map.
 pub struct IntoIter<K, V> {
-    next_nodes: Vec<Next<(K, V), TreeMap<K, V>>>,
-    current_val: Option<(K, V)>,
+    next_nodes: IntoQueue<K, V>,
+    len: usize,
 }
 
-/// `IntoIter` is a struct tracking the iterator state, and it has two main parts. There is the
-/// current key value pair (or nothing, if the iterator is already finished), and stack of `Next`
-/// recording the next elements of the iterator.  For instance, if the iterator is currently at a
-/// node that is a left-child of its parent, the top of the stack would be the data at the parent,
-/// the 2nd-from-top of the stack would store the whole subtree from the right sibling, and so on.
-///
-/// Define a few functions to set up the consuming iterator.
 impl<K, V> IntoIter<K, V> {
     /// Make a new consuming iterator from a `TreeMap`
     ///
     /// Note that this function takes ownership, because we are building a *consuming* iterator.
     fn new(tree: TreeMap<K, V>) -> Self {
-        let vec: Vec<Next<(K, V), TreeMap<K, V>>> = Vec::new();
-        let mut iter_struct = IntoIter {
-            next_nodes: vec,
-            current_val: None,
+        let len = tree.len();
+        let mut next_nodes = VecDeque::new();
+        next_nodes.push_back(Next::Tree(tree));
+        IntoIter { next_nodes, len }
+    }
+
+    fn unfold(mut tree: TreeMap<K, V>) -> Unfolded<(K, V), TreeMap<K, V>> {
+        let mut b = tree.inner.take()?;
+        let lt = TreeMap {
+            inner: b.lt.inner.take(),
         };
-        iter_struct.descend_left(tree);
-        iter_struct
-    }
-
-    /// This is the main workhorse function for setting up the iterator. In words, this function
-    /// should descends to the left-furthermost non-leaf child. Along the way, it sets up the
-    /// iterator state: the current value, and the next-nodes-to-visit stack. Make sure you add
-    /// nodes in the correct order---we want an in-order traversal, so the iterator should visit
-    /// left-child, parent, right-child as we pop things off the stack.
-    fn descend_left(&mut self, tree: TreeMap<K, V>) {
-        match tree.inner {
-            Some(b) => {
-                if b.rt.inner.is_none() {
-                    self.next_nodes.push(Next::Item((b.key, b.val)));
-                    self.descend_left(b.lt);
-                } else {
-                    self.next_nodes.push(Next::Tree(b.rt));
-                    self.next_nodes.push(Next::Item((b.key, b.val)));
-                    self.descend_left(b.lt);
-                }
-            }
-            None => match self.next_nodes.pop() {
-                Some(Next::Item(pair)) => self.current_val = Some(pair),
-                _ => self.current_val = None,
-            },
-        }
+        let rt = TreeMap {
+            inner: b.rt.inner.take(),
+        };
+        let Node { key, val, .. } = *b;
+        Some((lt, (key, val), rt))
     }
 }
 
@@ -397,220 +1221,769 @@ impl<K, V> IntoIterator for TreeMap<K, V> {
     }
 }
 
-/// Now, we are ready to implement the `Iterator` trait for `IntoIter`.
-///
-/// The main function `next` should get the current value, update the iterator to prepare the next
-/// value, and then update the nodes to visit.
-///
-/// Hint: you'll want to use `descend_left`.
-impl<K, V> Iterator for IntoIter<K, V> {
-    type Item = (K, V);
+impl<K, V> Iterator for IntoIter<K, V> {
+    type Item = (K, V);
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match self.next_nodes.pop_front()? {
+                Next::Item(pair) => {
+                    self.len -= 1;
+                    return Some(pair);
+                }
+                Next::Tree(tree) => {
+                    if let Some((lt, pair, rt)) = IntoIter::unfold(tree) {
+                        self.next_nodes.push_front(Next::Tree(rt));
+                        self.next_nodes.push_front(Next::Item(pair));
+                        self.next_nodes.push_front(Next::Tree(lt));
+                    }
+                }
+            }
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.len, Some(self.len))
+    }
+}
+
+impl<K, V> DoubleEndedIterator for IntoIter<K, V> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        loop {
+            match self.next_nodes.pop_back()? {
+                Next::Item(pair) => {
+                    self.len -= 1;
+                    return Some(pair);
+                }
+                Next::Tree(tree) => {
+                    if let Some((lt, pair, rt)) = IntoIter::unfold(tree) {
+                        self.next_nodes.push_back(Next::Tree(lt));
+                        self.next_nodes.push_back(Next::Item(pair));
+                        self.next_nodes.push_back(Next::Tree(rt));
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl<K, V> ExactSizeIterator for IntoIter<K, V> {}
+
+impl<K, V> FusedIterator for IntoIter<K, V> {}
+
+/// Now, the variants of iterators that do not consume the data. These are also called *borrowing*
+/// iterators, since they borrow data instead of consuming it. The main idea behind a borrowing
+/// iterator is that we should produce references to data, rather than data.
+///
+/// 1. Borrowing iterator. This implements the `Iterator` trait with `Item` type `(&K, &V)`.
+/// 2. Mutable iterator. This implements the `Iterator` trait with `Item` type `(&K, &mut V)`.
+///
+/// Note that we need to be a bit careful with mutable iterators: clients should be allowed to
+/// modify the value, but they should not be allowed to modify the keys---if they did, they might
+/// screw up the BST property. The mutable iterator hands out a pair of references: an *immutable*
+/// reference to the key, and a *mutable* reference to the value.
+///
+/// The code for the borrowing and mutable iterators is a nearly exact copy of the code for the
+/// consuming iterator.
+pub struct Iter<'a, K, V> {
+    next_nodes: IterQueue<'a, K, V>,
+    len: usize,
+}
+
+pub struct IterMut<'a, K, V> {
+    next_nodes: IterMutQueue<'a, K, V>,
+    len: usize,
+}
+
+impl<'a, K, V> Iter<'a, K, V> {
+    fn new(tree: &'a TreeMap<K, V>) -> Self {
+        let mut next_nodes = VecDeque::new();
+        next_nodes.push_back(Next::Tree(tree));
+        Iter {
+            next_nodes,
+            len: tree.len(),
+        }
+    }
+
+    fn unfold(tree: &'a TreeMap<K, V>) -> Unfolded<(&'a K, &'a V), &'a TreeMap<K, V>> {
+        let b = tree.inner.as_ref()?;
+        Some((&b.lt, (&b.key, &b.val), &b.rt))
+    }
+}
+
+impl<'a, K, V> IterMut<'a, K, V> {
+    fn new(tree: &'a mut TreeMap<K, V>) -> Self {
+        let len = tree.len();
+        let mut next_nodes = VecDeque::new();
+        next_nodes.push_back(Next::Tree(tree));
+        IterMut { next_nodes, len }
+    }
+
+    fn unfold(tree: &'a mut TreeMap<K, V>) -> Unfolded<(&'a K, &'a mut V), &'a mut TreeMap<K, V>> {
+        let Node {
+            key, val, lt, rt, ..
+        } = &mut **tree.inner.as_mut()?;
+        Some((lt, (&*key, val), rt))
+    }
+}
+
+impl<K, V> TreeMap<K, V> {
+    /// Check if a `TreeMap` is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Compute the size of a `TreeMap`.
+    pub fn len(&self) -> usize {
+        match &self.inner {
+            Some(b) => b.size,
+            None => 0,
+        }
+    }
+
+    pub fn iter(&self) -> Iter<'_, K, V> {
+        Iter::new(self)
+    }
+    pub fn iter_mut(&mut self) -> IterMut<'_, K, V> {
+        IterMut::new(self)
+    }
+
+    /// Iterate over the keys, in order.
+    pub fn keys(&self) -> Keys<'_, K, V> {
+        Keys { inner: self.iter() }
+    }
+
+    /// Iterate over the values, in the order of their keys.
+    pub fn values(&self) -> Values<'_, K, V> {
+        Values { inner: self.iter() }
+    }
+
+    /// Iterate over mutable references to the values, in the order of their keys.
+    pub fn values_mut(&mut self) -> ValuesMut<'_, K, V> {
+        ValuesMut {
+            inner: self.iter_mut(),
+        }
+    }
+
+    /// Turn the map into an iterator over its keys, in order.
+    pub fn into_keys(self) -> IntoKeys<K, V> {
+        IntoKeys {
+            inner: self.into_iter(),
+        }
+    }
+
+    /// Turn the map into an iterator over its values, in the order of their keys.
+    pub fn into_values(self) -> IntoValues<K, V> {
+        IntoValues {
+            inner: self.into_iter(),
+        }
+    }
+}
+
+impl<'a, K, V> Iterator for Iter<'a, K, V> {
+    type Item = (&'a K, &'a V);
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match self.next_nodes.pop_front()? {
+                Next::Item(pair) => {
+                    self.len -= 1;
+                    return Some(pair);
+                }
+                Next::Tree(tree) => {
+                    if let Some((lt, pair, rt)) = Iter::unfold(tree) {
+                        self.next_nodes.push_front(Next::Tree(rt));
+                        self.next_nodes.push_front(Next::Item(pair));
+                        self.next_nodes.push_front(Next::Tree(lt));
+                    }
+                }
+            }
+        }
+    }
+
+    /// Skip whole subtrees that come before the item, which takes O(log n) steps.
+    fn nth(&mut self, mut n: usize) -> Option<Self::Item> {
+        loop {
+            match self.next_nodes.pop_front()? {
+                Next::Item(pair) => {
+                    self.len -= 1;
+                    if n == 0 {
+                        return Some(pair);
+                    }
+                    n -= 1;
+                }
+                Next::Tree(tree) if tree.len() <= n => {
+                    n -= tree.len();
+                    self.len -= tree.len();
+                }
+                Next::Tree(tree) => {
+                    if let Some((lt, pair, rt)) = Iter::unfold(tree) {
+                        self.next_nodes.push_front(Next::Tree(rt));
+                        self.next_nodes.push_front(Next::Item(pair));
+                        self.next_nodes.push_front(Next::Tree(lt));
+                    }
+                }
+            }
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.len, Some(self.len))
+    }
+}
+
+impl<'a, K, V> DoubleEndedIterator for Iter<'a, K, V> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        loop {
+            match self.next_nodes.pop_back()? {
+                Next::Item(pair) => {
+                    self.len -= 1;
+                    return Some(pair);
+                }
+                Next::Tree(tree) => {
+                    if let Some((lt, pair, rt)) = Iter::unfold(tree) {
+                        self.next_nodes.push_back(Next::Tree(lt));
+                        self.next_nodes.push_back(Next::Item(pair));
+                        self.next_nodes.push_back(Next::Tree(rt));
+                    }
+                }
+            }
+        }
+    }
+
+    fn nth_back(&mut self, mut n: usize) -> Option<Self::Item> {
+        loop {
+            match self.next_nodes.pop_back()? {
+                Next::Item(pair) => {
+                    self.len -= 1;
+                    if n == 0 {
+                        return Some(pair);
+                    }
+                    n -= 1;
+                }
+                Next::Tree(tree) if tree.len() <= n => {
+                    n -= tree.len();
+                    self.len -= tree.len();
+                }
+                Next::Tree(tree) => {
+                    if let Some((lt, pair, rt)) = Iter::unfold(tree) {
+                        self.next_nodes.push_back(Next::Tree(lt));
+                        self.next_nodes.push_back(Next::Item(pair));
+                        self.next_nodes.push_back(Next::Tree(rt));
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl<'a, K, V> ExactSizeIterator for Iter<'a, K, V> {}
+
+impl<'a, K, V> FusedIterator for Iter<'a, K, V> {}
+
+impl<'a, K, V> Iterator for IterMut<'a, K, V> {
+    type Item = (&'a K, &'a mut V);
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match self.next_nodes.pop_front()? {
+                Next::Item(pair) => {
+                    self.len -= 1;
+                    return Some(pair);
+                }
+                Next::Tree(tree) => {
+                    if let Some((lt, pair, rt)) = IterMut::unfold(tree) {
+                        self.next_nodes.push_front(Next::Tree(rt));
+                        self.next_nodes.push_front(Next::Item(pair));
+                        self.next_nodes.push_front(Next::Tree(lt));
+                    }
+                }
+            }
+        }
+    }
+
+    /// Skip whole subtrees that come before the item, which takes O(log n) steps.
+    fn nth(&mut self, mut n: usize) -> Option<Self::Item> {
+        loop {
+            match self.next_nodes.pop_front()? {
+                Next::Item(pair) => {
+                    self.len -= 1;
+                    if n == 0 {
+                        return Some(pair);
+                    }
+                    n -= 1;
+                }
+                Next::Tree(tree) if tree.len() <= n => {
+                    n -= tree.len();
+                    self.len -= tree.len();
+                }
+                Next::Tree(tree) => {
+                    if let Some((lt, pair, rt)) = IterMut::unfold(tree) {
+                        self.next_nodes.push_front(Next::Tree(rt));
+                        self.next_nodes.push_front(Next::Item(pair));
+                        self.next_nodes.push_front(Next::Tree(lt));
+                    }
+                }
+            }
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.len, Some(self.len))
+    }
+}
+
+impl<'a, K, V> DoubleEndedIterator for IterMut<'a, K, V> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        loop {
+            match self.next_nodes.pop_back()? {
+                Next::Item(pair) => {
+                    self.len -= 1;
+                    return Some(pair);
+                }
+                Next::Tree(tree) => {
+                    if let Some((lt, pair, rt)) = IterMut::unfold(tree) {
+                        self.next_nodes.push_back(Next::Tree(lt));
+                        self.next_nodes.push_back(Next::Item(pair));
+                        self.next_nodes.push_back(Next::Tree(rt));
+                    }
+                }
+            }
+        }
+    }
+
+    fn nth_back(&mut self, mut n: usize) -> Option<Self::Item> {
+        loop {
+            match self.next_nodes.pop_back()? {
+                Next::Item(pair) => {
+                    self.len -= 1;
+                    if n == 0 {
+                        return Some(pair);
+                    }
+                    n -= 1;
+                }
+                Next::Tree(tree) if tree.len() <= n => {
+                    n -= tree.len();
+                    self.len -= tree.len();
+                }
+                Next::Tree(tree) => {
+                    if let Some((lt, pair, rt)) = IterMut::unfold(tree) {
+                        self.next_nodes.push_back(Next::Tree(lt));
+                        self.next_nodes.push_back(Next::Item(pair));
+                        self.next_nodes.push_back(Next::Tree(rt));
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl<'a, K, V> ExactSizeIterator for IterMut<'a, K, V> {}
+
+impl<'a, K, V> FusedIterator for IterMut<'a, K, V> {}
+
+/// Adapters that hand out only the keys or only the values of the iterators above.
+pub struct Keys<'a, K, V> {
+    inner: Iter<'a, K, V>,
+}
+
+pub struct Values<'a, K, V> {
+    inner: Iter<'a, K, V>,
+}
+
+pub struct ValuesMut<'a, K, V> {
+    inner: IterMut<'a, K, V>,
+}
+
+pub struct IntoKeys<K, V> {
+    inner: IntoIter<K, V>,
+}
+
+pub struct IntoValues<K, V> {
+    inner: IntoIter<K, V>,
+}
+
+impl<'a, K, V> Iterator for Keys<'a, K, V> {
+    type Item = &'a K;
+    fn next(&mut self) -> Option<Self::Item> {
+        self.inner.next().map(|(key, _)| key)
+    }
+
+    fn nth(&mut self, n: usize) -> Option<Self::Item> {
+        self.inner.nth(n).map(|(key, _)| key)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+impl<'a, K, V> DoubleEndedIterator for Keys<'a, K, V> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.inner.next_back().map(|(key, _)| key)
+    }
+
+    fn nth_back(&mut self, n: usize) -> Option<Self::Item> {
+        self.inner.nth_back(n).map(|(key, _)| key)
+    }
+}
+
+impl<'a, K, V> ExactSizeIterator for Keys<'a, K, V> {}
+
+impl<'a, K, V> FusedIterator for Keys<'a, K, V> {}
+
+impl<'a, K, V> Iterator for Values<'a, K, V> {
+    type Item = &'a V;
+    fn next(&mut self) -> Option<Self::Item> {
+        self.inner.next().map(|(_, val)| val)
+    }
+
+    fn nth(&mut self, n: usize) -> Option<Self::Item> {
+        self.inner.nth(n).map(|(_, val)| val)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+impl<'a, K, V> DoubleEndedIterator for Values<'a, K, V> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.inner.next_back().map(|(_, val)| val)
+    }
+
+    fn nth_back(&mut self, n: usize) -> Option<Self::Item> {
+        self.inner.nth_back(n).map(|(_, val)| val)
+    }
+}
+
+impl<'a, K, V> ExactSizeIterator for Values<'a, K, V> {}
+
+impl<'a, K, V> FusedIterator for Values<'a, K, V> {}
+
+impl<'a, K, V> Iterator for ValuesMut<'a, K, V> {
+    type Item = &'a mut V;
+    fn next(&mut self) -> Option<Self::Item> {
+        self.inner.next().map(|(_, val)| val)
+    }
+
+    fn nth(&mut self, n: usize) -> Option<Self::Item> {
+        self.inner.nth(n).map(|(_, val)| val)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+impl<'a, K, V> DoubleEndedIterator for ValuesMut<'a, K, V> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.inner.next_back().map(|(_, val)| val)
+    }
+
+    fn nth_back(&mut self, n: usize) -> Option<Self::Item> {
+        self.inner.nth_back(n).map(|(_, val)| val)
+    }
+}
+
+impl<'a, K, V> ExactSizeIterator for ValuesMut<'a, K, V> {}
+
+impl<'a, K, V> FusedIterator for ValuesMut<'a, K, V> {}
+
+impl<K, V> Iterator for IntoKeys<K, V> {
+    type Item = K;
+    fn next(&mut self) -> Option<Self::Item> {
+        self.inner.next().map(|(key, _)| key)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+impl<K, V> DoubleEndedIterator for IntoKeys<K, V> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.inner.next_back().map(|(key, _)| key)
+    }
+}
+
+impl<K, V> ExactSizeIterator for IntoKeys<K, V> {}
+
+impl<K, V> FusedIterator for IntoKeys<K, V> {}
+
+impl<K, V> Iterator for IntoValues<K, V> {
+    type Item = V;
     fn next(&mut self) -> Option<Self::Item> {
-        let curr_val = Option::take(&mut self.current_val);
+        self.inner.next().map(|(_, val)| val)
+    }
 
-        match self.next_nodes.pop() {
-            Some(Next::Item(pair)) => {
-                self.current_val = Some(pair);
-            }
-            Some(Next::Tree(tree)) => {
-                self.descend_left(tree);
-            }
-            None => self.current_val = None,
-        }
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
 
-        curr_val
+impl<K, V> DoubleEndedIterator for IntoValues<K, V> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.inner.next_back().map(|(_, val)| val)
     }
 }
 
-/// Now, you'll define a few variants of iterators that do not consume the data. These are also
-/// called *borrowing* iterators, since they borrow data instead of consuming it. The overall
-/// strategy will be quite similar to what you already did above, but with a few tweaks. The main
-/// idea behind a borrowing iterator is that we should produce references to data, rather than data.
-///
-/// For this part, you'll be designing the type structures and implementing the necessary functions.
-/// You should design two kinds of iterators:
-///
-/// 1. Borrowing iterator. This should implement the `Iterator` trait with `Item` type `(&K, &V)`.
-/// 2. Mutable iterator. This should implement the `Iterator` trait with `Item` type `(&K, &mut V)`.
-///
-/// Note that we need to be a bit careful with mutable iterators: clients should be allowed to
-/// modify the value, but they should not be allowed to modify the keys---if they did, they might
-/// screw up the BST property. Your mutable iterator should hand out a pair of references: an
-/// *immutable* reference to the key, and a *mutable* reference to the value.
-///
-/// To construct these two iterators, design two structs for tracking the state of
-/// borrowing/mutable iterators (`Iter<K, V>` and `IterMut<K, V>`), and implement the following two
-/// methods for `TreeMap`:
-///
-/// 1. `pub fn iter(&self) -> Iter<K, V>`
-/// 2. `pub fn iter_mut(&mut self) -> IterMut<K, V>`
-///
-/// `Iter` and `IterMut` should implement the Iterator trait.
-///
-/// You should also implement versions of all the associated functions you did for `IntoIter`, but do
-/// not implement `IntoIterator` for `TreeMap` again.
+impl<K, V> ExactSizeIterator for IntoValues<K, V> {}
+
+impl<K, V> FusedIterator for IntoValues<K, V> {}
+
+/// Extension: range iterators
 ///
-/// Hint: don't implement these iterators from scratch. You'll need to sprinkle in some extra
-/// annotations and tweaks in a few key places, but your code for the borrowing and mutable
-/// iterators should be a nearly exact copy of your code for consuming iterators.
+/// `range` and `range_mut` iterate over the keys in a range, in order, from either end. They are
+/// borrowing and mutable iterators whose queue starts out holding just the keys in the range:
+/// setting it up only visits the nodes on the paths to the two ends of the range, so a range with
+/// `k` keys takes O(log n + k) steps in all.
+pub struct Range<'a, K, V> {
+    iter: Iter<'a, K, V>,
+}
 
-pub struct Iter<'a, K, V> {
-    next_nodes: Vec<Next<(&'a K, &'a V), &'a TreeMap<K, V>>>,
-    current_val: Option<(&'a K, &'a V)>,
+pub struct RangeMut<'a, K, V> {
+    iter: IterMut<'a, K, V>,
 }
 
-pub struct IterMut<'a, K, V> {
-    next_nodes: Vec<Next<(&'a K, &'a mut V), &'a mut TreeMap<K, V>>>,
-    current_val: Option<(&'a K, &'a mut V)>,
+/// Whether `key` comes before the start of `range`.
+fn before_start<Q, R>(range: &R, key: &Q) -> bool
+where
+    Q: Ord + ?Sized,
+    R: RangeBounds<Q>,
+{
+    match range.start_bound() {
+        Bound::Included(start) => key < start,
+        Bound::Excluded(start) => key <= start,
+        Bound::Unbounded => false,
+    }
 }
 
-impl<'a, K, V> Iter<'a, K, V> {
-    fn new(tree: &'a TreeMap<K, V>) -> Self {
-        let vec: Vec<Next<(&'a K, &'a V), &'a TreeMap<K, V>>> = Vec::new();
-        let mut iter_struct = Iter {
-            next_nodes: vec,
-            current_val: None,
-        };
-        iter_struct.descend_left(tree);
-        iter_struct
+/// Whether `key` comes after the end of `range`.
+fn after_end<Q, R>(range: &R, key: &Q) -> bool
+where
+    Q: Ord + ?Sized,
+    R: RangeBounds<Q>,
+{
+    match range.end_bound() {
+        Bound::Included(end) => key > end,
+        Bound::Excluded(end) => key >= end,
+        Bound::Unbounded => false,
     }
+}
 
-    fn descend_left(&mut self, tree: &'a TreeMap<K, V>) {
-        match &tree.inner {
-            Some(b) => {
-                if b.rt.inner.is_none() {
-                    self.next_nodes.push(Next::Item((&b.key, &b.val)));
-                    self.descend_left(&b.lt);
-                } else {
-                    self.next_nodes.push(Next::Tree(&b.rt));
-                    self.next_nodes.push(Next::Item((&b.key, &b.val)));
-                    self.descend_left(&b.lt);
+impl<'a, K, V> Range<'a, K, V> {
+    /// Queue up the keys of `tree` in `range`. If the range is empty, or its start comes after its
+    /// end, so is the queue.
+    fn new<Q, R>(tree: &'a TreeMap<K, V>, range: R) -> Self
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+        R: RangeBounds<Q>,
+    {
+        let mut next_nodes = VecDeque::new();
+        let mut len = 0;
+        let mut tree = tree;
+        // Look for the highest node in the range: the two ends of the range part ways there
+        while let Some(b) = &tree.inner {
+            if before_start(&range, b.key.borrow()) {
+                tree = &b.rt;
+            } else if after_end(&range, b.key.borrow()) {
+                tree = &b.lt;
+            } else {
+                next_nodes.push_back(Next::Item((&b.key, &b.val)));
+                len += 1;
+                // Nodes on the path to the start, with their right subtrees, come before it ...
+                let mut lt = &b.lt;
+                while let Some(l) = &lt.inner {
+                    if before_start(&range, l.key.borrow()) {
+                        lt = &l.rt;
+                    } else {
+                        next_nodes.push_front(Next::Tree(&l.rt));
+                        next_nodes.push_front(Next::Item((&l.key, &l.val)));
+                        len += l.rt.len() + 1;
+                        lt = &l.lt;
+                    }
+                }
+                // ... and nodes on the path to the end, with their left subtrees, after it
+                let mut rt = &b.rt;
+                while let Some(r) = &rt.inner {
+                    if after_end(&range, r.key.borrow()) {
+                        rt = &r.lt;
+                    } else {
+                        next_nodes.push_back(Next::Tree(&r.lt));
+                        next_nodes.push_back(Next::Item((&r.key, &r.val)));
+                        len += r.lt.len() + 1;
+                        rt = &r.rt;
+                    }
                 }
+                break;
             }
-            None => match self.next_nodes.pop() {
-                Some(Next::Item(pair)) => self.current_val = Some(pair),
-                _ => self.current_val = None,
-            },
+        }
+        Range {
+            iter: Iter { next_nodes, len },
         }
     }
 }
 
-impl<'a, K, V> IterMut<'a, K, V> {
-    fn new(tree: &'a mut TreeMap<K, V>) -> Self {
-        let vec: Vec<Next<(&'a K, &'a mut V), &'a mut TreeMap<K, V>>> = Vec::new();
-        let mut iter_struct = IterMut {
-            next_nodes: vec,
-            current_val: None,
-        };
-        iter_struct.descend_left(tree);
-        iter_struct
-    }
-
-    fn descend_left(&mut self, tree: &'a mut TreeMap<K, V>) {
-        match &mut tree.inner {
-            Some(b) => {
-                if b.rt.inner.is_none() {
-                    self.next_nodes.push(Next::Item((&b.key, &mut b.val)));
-                    self.descend_left(&mut b.lt);
-                } else {
-                    self.next_nodes.push(Next::Tree(&mut b.rt));
-                    self.next_nodes.push(Next::Item((&b.key, &mut b.val)));
-                    self.descend_left(&mut b.lt);
+impl<'a, K, V> RangeMut<'a, K, V> {
+    fn new<Q, R>(tree: &'a mut TreeMap<K, V>, range: R) -> Self
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+        R: RangeBounds<Q>,
+    {
+        let mut next_nodes = VecDeque::new();
+        let mut len = 0;
+        let mut tree = tree;
+        while let Some(b) = &mut tree.inner {
+            let Node {
+                key, val, lt, rt, ..
+            } = &mut **b;
+            if before_start(&range, (*key).borrow()) {
+                tree = rt;
+            } else if after_end(&range, (*key).borrow()) {
+                tree = lt;
+            } else {
+                next_nodes.push_back(Next::Item((&*key, val)));
+                len += 1;
+                let mut lt = lt;
+                while let Some(l) = &mut lt.inner {
+                    let Node {
+                        key,
+                        val,
+                        lt: llt,
+                        rt: lrt,
+                        ..
+                    } = &mut **l;
+                    if before_start(&range, (*key).borrow()) {
+                        lt = lrt;
+                    } else {
+                        len += lrt.len() + 1;
+                        next_nodes.push_front(Next::Tree(lrt));
+                        next_nodes.push_front(Next::Item((&*key, val)));
+                        lt = llt;
+                    }
+                }
+                let mut rt = rt;
+                while let Some(r) = &mut rt.inner {
+                    let Node {
+                        key,
+                        val,
+                        lt: rlt,
+                        rt: rrt,
+                        ..
+                    } = &mut **r;
+                    if after_end(&range, (*key).borrow()) {
+                        rt = rlt;
+                    } else {
+                        len += rlt.len() + 1;
+                        next_nodes.push_back(Next::Tree(rlt));
+                        next_nodes.push_back(Next::Item((&*key, val)));
+                        rt = rrt;
+                    }
                 }
+                break;
             }
-            None => match self.next_nodes.pop() {
-                Some(Next::Item(pair)) => self.current_val = Some(pair),
-                _ => self.current_val = None,
-            },
+        }
+        RangeMut {
+            iter: IterMut { next_nodes, len },
         }
     }
 }
 
 impl<K, V> TreeMap<K, V> {
-    pub fn iter(&self) -> Iter<K, V> {
-        Iter::new(self)
+    /// Iterate over the keys in `range`, in order, with their values. An empty range, or one whose
+    /// start comes after its end, has no keys in it.
+    pub fn range<Q, R>(&self, range: R) -> Range<'_, K, V>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+        R: RangeBounds<Q>,
+    {
+        Range::new(self, range)
     }
-    pub fn iter_mut(&mut self) -> IterMut<K, V> {
-        IterMut::new(self)
+
+    /// Iterate over the keys in `range`, in order, with mutable references to their values.
+    pub fn range_mut<Q, R>(&mut self, range: R) -> RangeMut<'_, K, V>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+        R: RangeBounds<Q>,
+    {
+        RangeMut::new(self, range)
     }
 }
 
-impl<'a, K, V> Iterator for Iter<'a, K, V> {
+impl<'a, K, V> Iterator for Range<'a, K, V> {
     type Item = (&'a K, &'a V);
     fn next(&mut self) -> Option<Self::Item> {
-        let curr_val = Option::take(&mut self.current_val);
+        self.iter.next()
+    }
 
-        match self.next_nodes.pop() {
-            Some(Next::Item(pair)) => {
-                self.current_val = Some(pair);
-            }
-            Some(Next::Tree(tree)) => {
-                self.descend_left(tree);
-            }
-            None => self.current_val = None,
-        }
+    fn nth(&mut self, n: usize) -> Option<Self::Item> {
+        self.iter.nth(n)
+    }
 
-        curr_val
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.iter.size_hint()
     }
 }
 
-impl<'a, K, V> Iterator for IterMut<'a, K, V> {
+impl<'a, K, V> DoubleEndedIterator for Range<'a, K, V> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.iter.next_back()
+    }
+
+    fn nth_back(&mut self, n: usize) -> Option<Self::Item> {
+        self.iter.nth_back(n)
+    }
+}
+
+impl<'a, K, V> ExactSizeIterator for Range<'a, K, V> {}
+
+impl<'a, K, V> FusedIterator for Range<'a, K, V> {}
+
+impl<'a, K, V> Iterator for RangeMut<'a, K, V> {
     type Item = (&'a K, &'a mut V);
     fn next(&mut self) -> Option<Self::Item> {
-        let curr_val = Option::take(&mut self.current_val);
+        self.iter.next()
+    }
 
-        match self.next_nodes.pop() {
-            Some(Next::Item(pair)) => {
-                self.current_val = Some(pair);
-            }
-            Some(Next::Tree(tree)) => {
-                self.descend_left(tree);
-            }
-            None => self.current_val = None,
-        }
+    fn nth(&mut self, n: usize) -> Option<Self::Item> {
+        self.iter.nth(n)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.iter.size_hint()
+    }
+}
+
+impl<'a, K, V> DoubleEndedIterator for RangeMut<'a, K, V> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.iter.next_back()
+    }
 
-        curr_val
+    fn nth_back(&mut self, n: usize) -> Option<Self::Item> {
+        self.iter.nth_back(n)
     }
 }
 
+impl<'a, K, V> ExactSizeIterator for RangeMut<'a, K, V> {}
+
+impl<'a, K, V> FusedIterator for RangeMut<'a, K, V> {}
+
 #[cfg(test)]
 mod test {
-    use super::TreeMap;
+    use super::{Entry, Invariant, InvariantError, Node, TreeMap};
     use std::fmt::Debug;
+    use std::thread;
 
     impl<K, V> TreeMap<K, V>
     where
         K: Ord + Debug,
         V: Debug,
     {
-        /// Test function: checks the BST invariant.
-        fn is_bst(&self) -> bool {
-            let mut lsize = 0;
-            let mut rsize = 0;
-            if let Some(boxed) = &self.inner {
-                let lt = &boxed.lt;
-                let rt = &boxed.rt;
-
-                if let Some(lnode) = &lt.inner {
-                    if lnode.key >= boxed.key || !lt.is_bst() {
-                        return false;
-                    }
-
-                    lsize = lnode.size;
-                }
-
-                if let Some(rnode) = &rt.inner {
-                    if rnode.key <= boxed.key || !rt.is_bst() {
-                        return false;
-                    }
-
-                    rsize = rnode.size;
-                }
-
-                boxed.size == lsize + rsize + 1
-            } else {
-                true
-            }
+        /// Test function: the largest height an AVL tree of this size may have.
+        fn max_height(&self) -> usize {
+            (1.45 * ((self.len() + 2) as f64).log2()) as usize
         }
     }
 
@@ -626,14 +1999,77 @@ mod test {
         assert_eq!(tree.get(&2), Some(&22));
         assert_eq!(tree.get(&3), Some(&33));
 
-        assert!(tree.is_bst());
+        assert_eq!(tree.check_invariants(), Ok(()));
 
         let insert_res = tree.insert(1, 111);
 
         assert_eq!(tree.get(&1), Some(&111));
         assert_eq!(insert_res, Some(11));
 
-        assert!(tree.is_bst());
+        assert_eq!(tree.check_invariants(), Ok(()));
+    }
+
+    #[test]
+    fn test_overwrite() {
+        let mut tree: TreeMap<i32, i32> = (0..10).map(|i| (i, i)).collect();
+
+        // Overwriting keys deep down doesn't change the sizes along the way
+        for i in 0..10 {
+            assert_eq!(tree.insert(i, i * 10), Some(i));
+        }
+        assert_eq!(tree.len(), 10);
+        assert_eq!(tree.check_invariants(), Ok(()));
+        assert_eq!(tree.get(&9), Some(&90));
+    }
+
+    #[test]
+    fn test_check_invariants() {
+        let tree = || -> TreeMap<i32, i32> { (1..=7).map(|i| (i, i)).collect() };
+        let broken = |tree: &TreeMap<i32, i32>| {
+            tree.check_invariants()
+                .map_err(|err| (err.invariant, *err.key))
+        };
+        assert_eq!(broken(&tree()), Ok(()));
+        assert_eq!(broken(&TreeMap::new()), Ok(()));
+
+        // The root is 4, with 2 and 6 below it
+        let mut swapped = tree();
+        if let Some(b) = &mut swapped.inner {
+            b.key = 6;
+        }
+        assert_eq!(broken(&swapped), Err((Invariant::Ordering, 6)));
+
+        let mut deep = tree();
+        if let Some(b) = &mut deep.inner {
+            if let Some(lt) = &mut b.lt.inner {
+                if let Some(lrt) = &mut lt.rt.inner {
+                    lrt.key = 5;
+                }
+            }
+        }
+        assert_eq!(broken(&deep), Err((Invariant::Ordering, 5)));
+
+        let mut miscounted = tree();
+        if let Some(b) = &mut miscounted.inner {
+            if let Some(rt) = &mut b.rt.inner {
+                rt.size += 1;
+            }
+        }
+        // The size of 6 is now one more than the root's size accounts for
+        assert_eq!(broken(&miscounted), Err((Invariant::Size, 4)));
+
+        let mut lopsided = tree();
+        if let Some(b) = &mut lopsided.inner {
+            b.lt = TreeMap::new();
+            b.update();
+        }
+        assert_eq!(broken(&lopsided), Err((Invariant::Balance, 4)));
+
+        let err = InvariantError {
+            invariant: Invariant::Size,
+            key: &"b",
+        };
+        assert_eq!(err.to_string(), "size invariant broken at key \"b\"");
     }
 
     #[test]
@@ -659,13 +2095,13 @@ mod test {
         tree.insert(3, 33);
         tree.insert(2, 22);
 
-        assert_eq!(tree.remove(1), Some(11));
+        assert_eq!(tree.remove(&1), Some(11));
         assert_eq!(tree.len(), 2);
-        assert!(tree.is_bst());
+        assert_eq!(tree.check_invariants(), Ok(()));
 
-        assert_eq!(tree.remove(1), None);
+        assert_eq!(tree.remove(&1), None);
         assert_eq!(tree.len(), 2);
-        assert!(tree.is_bst());
+        assert_eq!(tree.check_invariants(), Ok(()));
     }
 
     #[test]
@@ -682,7 +2118,7 @@ mod test {
 
         assert_eq!(tree.get(&3), Some(&333));
 
-        assert!(tree.is_bst());
+        assert_eq!(tree.check_invariants(), Ok(()));
     }
 
     #[test]
@@ -699,7 +2135,87 @@ mod test {
 
         assert_eq!(tree.get(&2), Some(&22));
 
-        assert!(tree.is_bst());
+        assert_eq!(tree.check_invariants(), Ok(()));
+    }
+
+    #[test]
+    fn test_borrowed_keys() {
+        let mut tree: TreeMap<String, usize> = ["apple", "pear", "fig"]
+            .iter()
+            .map(|s| (s.to_string(), s.len()))
+            .collect();
+
+        // Look up `String` keys with `&str`s, without allocating
+        assert!(tree.has_key("fig"));
+        assert!(!tree.has_key("plum"));
+        assert_eq!(tree.get("pear"), Some(&4));
+        *tree.get_mut("pear").unwrap() += 1;
+        assert_eq!(tree["pear"], 5);
+        tree["apple"] = 0;
+        assert_eq!(tree.get("apple"), Some(&0));
+
+        assert_eq!(tree.remove("fig"), Some(3));
+        assert_eq!(tree.remove("fig"), None);
+        assert_eq!(tree.len(), 2);
+        assert_eq!(tree.check_invariants(), Ok(()));
+
+        let mut tree: TreeMap<Vec<u8>, ()> = TreeMap::new();
+        tree.insert(vec![1, 2], ());
+        assert!(tree.has_key(&[1, 2][..]));
+    }
+
+    #[test]
+    fn test_entry() {
+        let text = "the cat and the dog and the bird";
+        let mut counts: TreeMap<&str, usize> = TreeMap::new();
+        for word in text.split(' ') {
+            *counts.entry(word).or_insert(0) += 1;
+        }
+        let counts: Vec<_> = counts.into_iter().collect();
+        assert_eq!(
+            counts,
+            [("and", 2), ("bird", 1), ("cat", 1), ("dog", 1), ("the", 3)]
+        );
+
+        let mut tree: TreeMap<i32, Vec<i32>> = TreeMap::new();
+        for i in 0..100 {
+            tree.entry(i % 7).or_default().push(i);
+        }
+        assert_eq!(tree.len(), 7);
+        assert_eq!(tree.get(&3).map(Vec::len), Some(14));
+        assert_eq!(tree.check_invariants(), Ok(()));
+
+        let mut tree: TreeMap<i32, i32> = (0..10).map(|i| (i * 2, i)).collect();
+        assert_eq!(*tree.entry(4).and_modify(|v| *v += 10).or_insert(0), 12);
+        assert_eq!(*tree.entry(5).and_modify(|v| *v += 10).or_insert(0), 0);
+        assert_eq!(*tree.entry(7).or_insert_with(|| 70), 70);
+        assert_eq!(*tree.entry(7).or_insert_with(|| panic!()), 70);
+        assert_eq!(tree.len(), 12);
+        assert_eq!(tree.check_invariants(), Ok(()));
+
+        match tree.entry(6) {
+            Entry::Occupied(mut entry) => {
+                assert_eq!(entry.key(), &6);
+                assert_eq!(entry.get(), &3);
+                assert_eq!(entry.insert(33), 3);
+                assert_eq!(entry.remove_entry(), (6, 33));
+            }
+            Entry::Vacant(_) => panic!("6 is in the tree"),
+        }
+        match tree.entry(100) {
+            Entry::Vacant(entry) => {
+                assert_eq!(entry.key(), &100);
+                assert_eq!(entry.into_key(), 100);
+            }
+            Entry::Occupied(_) => panic!("100 is not in the tree"),
+        }
+        assert_eq!(tree.entry(8).key(), &8);
+        if let Entry::Occupied(entry) = tree.entry(0) {
+            assert_eq!(entry.remove(), 0);
+        }
+        assert_eq!(tree.len(), 10);
+        assert_eq!(tree.get(&6), None);
+        assert_eq!(tree.check_invariants(), Ok(()));
     }
 
     #[should_panic]
@@ -719,7 +2235,7 @@ mod test {
         let vec = vec![(1, 11), (3, 33), (2, 22)];
         let tree: TreeMap<i32, i32> = vec.into_iter().collect();
 
-        assert!(tree.is_bst());
+        assert_eq!(tree.check_invariants(), Ok(()));
 
         assert_eq!(tree.get(&1), Some(&11));
         assert_eq!(tree.get(&2), Some(&22));
@@ -761,7 +2277,7 @@ mod test {
             *v = k + 100;
         }
 
-        assert!(tree.is_bst());
+        assert_eq!(tree.check_invariants(), Ok(()));
         assert_eq!(tree.len(), 3);
 
         assert_eq!(tree.get(&1), Some(&101));
@@ -769,6 +2285,456 @@ mod test {
         assert_eq!(tree.get(&3), Some(&103));
     }
 
+    #[test]
+    fn test_sorted_inserts() {
+        let n = 100_000;
+        let mut tree = TreeMap::new();
+        for i in 0..n {
+            tree.insert(i, i);
+        }
+        assert_eq!(tree.len(), n);
+        assert!(tree.height() <= tree.max_height());
+        assert_eq!(tree.check_invariants(), Ok(()));
+
+        let mut tree = TreeMap::new();
+        for i in (0..n).rev() {
+            tree.insert(i, i);
+        }
+        assert!(tree.height() <= tree.max_height());
+        assert_eq!(tree.check_invariants(), Ok(()));
+
+        // Remove everything from the left
+        for i in 0..n {
+            assert_eq!(tree.remove(&i), Some(i));
+            if i % 1000 == 0 {
+                assert!(tree.height() <= tree.max_height());
+                assert_eq!(tree.check_invariants(), Ok(()));
+            }
+        }
+        assert!(tree.is_empty());
+    }
+
+    #[test]
+    fn test_random_ops() {
+        use rand::rngs::StdRng;
+        use rand::{Rng, SeedableRng};
+        use std::collections::BTreeMap;
+
+        let mut rng = StdRng::seed_from_u64(538);
+        let mut tree = TreeMap::new();
+        let mut model = BTreeMap::new();
+        for step in 0..20_000 {
+            let key = rng.gen_range(0, 1000);
+            if rng.gen_bool(0.5) {
+                assert_eq!(tree.insert(key, step), model.insert(key, step));
+            } else if rng.gen_bool(0.2) {
+                *tree.entry(key).or_insert(0) += step;
+                *model.entry(key).or_insert(0) += step;
+            } else {
+                assert_eq!(tree.remove(&key), model.remove(&key));
+            }
+            assert_eq!(tree.len(), model.len());
+            if step % 100 == 0 {
+                assert_eq!(tree.check_invariants(), Ok(()));
+                assert!(tree.height() <= tree.max_height());
+            }
+            assert_eq!(tree.floor(&key), model.range(..=key).next_back());
+            assert_eq!(tree.higher(&key), model.range(key + 1..).next());
+        }
+        assert!(tree.iter().eq(model.iter()));
+    }
+
+    #[test]
+    fn test_random_entries() {
+        use rand::rngs::StdRng;
+        use rand::{Rng, SeedableRng};
+        use std::collections::btree_map::{self, BTreeMap};
+
+        // Inserting through an entry rebalances by itself, so every kind of rotation needs to
+        // come up: check the whole tree after every step
+        let mut rng = StdRng::seed_from_u64(538);
+        let mut tree = TreeMap::new();
+        let mut model = BTreeMap::new();
+        for step in 0..5_000 {
+            let key = rng.gen_range(0, 200);
+            match (tree.entry(key), model.entry(key)) {
+                (Entry::Occupied(entry), btree_map::Entry::Occupied(expected)) => {
+                    assert_eq!(entry.key(), expected.key());
+                    match rng.gen_range(0, 3) {
+                        0 => assert_eq!(entry.remove_entry(), expected.remove_entry()),
+                        1 => {
+                            *entry.into_mut() += step;
+                            *expected.into_mut() += step;
+                        }
+                        _ => (), // Dropping the entry leaves the map as it was
+                    }
+                }
+                (Entry::Vacant(entry), btree_map::Entry::Vacant(expected)) => {
+                    if rng.gen_bool(0.8) {
+                        assert_eq!(*entry.insert(step), *expected.insert(step));
+                    }
+                }
+                _ => panic!("the entries for {} differ", key),
+            }
+            assert_eq!(tree.check_invariants(), Ok(()));
+            assert!(tree.iter().eq(model.iter()));
+        }
+
+        // Runs of keys in order, reversed and alternating cover the single and double rotations
+        let mut tree: TreeMap<i32, u32> = TreeMap::new();
+        let keys = (0..100).chain((200..300).rev());
+        let zigzag = (0..100).map(|i| if i % 2 == 0 { 500 + i } else { 700 - i });
+        for key in keys.chain(zigzag) {
+            *tree.entry(key).or_default() += 1;
+            assert_eq!(tree.check_invariants(), Ok(()));
+        }
+        assert_eq!(tree.len(), 300);
+    }
+
+    #[test]
+    fn test_double_ended() {
+        let tree: TreeMap<i32, i32> = (0..100).map(|i| (i, i * 10)).collect();
+
+        let backwards = tree.iter().rev().map(|(k, v)| (*k, *v));
+        assert!(backwards.eq((0..100).rev().map(|i| (i, i * 10))));
+        assert_eq!(tree.iter().len(), 100);
+
+        // Alternate between the ends, checking the count as we go
+        let mut iter = tree.iter();
+        for i in 0..50 {
+            assert_eq!(iter.len(), 100 - 2 * i as usize);
+            assert_eq!(iter.next(), Some((&i, &(i * 10))));
+            assert_eq!(iter.next_back(), Some((&(99 - i), &((99 - i) * 10))));
+        }
+        assert_eq!(iter.size_hint(), (0, Some(0)));
+        assert_eq!(iter.next(), None);
+        assert_eq!(iter.next_back(), None);
+        assert_eq!(iter.next(), None);
+
+        let mut tree = tree;
+        let mut iter = tree.iter_mut();
+        *iter.next_back().unwrap().1 = -1;
+        assert_eq!(iter.len(), 99);
+        for (k, v) in iter.rev().take(10) {
+            *v = -k;
+        }
+        assert_eq!(tree.get(&99), Some(&-1));
+        assert_eq!(tree.get(&98), Some(&-98));
+        assert_eq!(tree.get(&89), Some(&-89));
+        assert_eq!(tree.get(&88), Some(&880));
+
+        let mut iter = tree.into_iter();
+        assert_eq!(iter.next_back(), Some((99, -1)));
+        assert_eq!(iter.next(), Some((0, 0)));
+        assert_eq!(iter.len(), 98);
+        let rest: Vec<_> = iter.rev().map(|(k, _)| k).collect();
+        assert!(rest.into_iter().eq((1..99).rev()));
+    }
+
+    #[test]
+    fn test_keys_values() {
+        let mut tree: TreeMap<i32, String> = (1..=5).map(|i| (i, i.to_string())).collect();
+
+        assert!(tree.keys().eq(&[1, 2, 3, 4, 5]));
+        assert!(tree.keys().rev().eq(&[5, 4, 3, 2, 1]));
+        assert_eq!(tree.keys().len(), 5);
+        let values: Vec<_> = tree.values().map(String::as_str).collect();
+        assert_eq!(values, ["1", "2", "3", "4", "5"]);
+        assert_eq!(tree.values().next_back().map(String::as_str), Some("5"));
+
+        for val in tree.values_mut().rev().take(2) {
+            val.push('!');
+        }
+        assert_eq!(tree.values_mut().len(), 5);
+        assert_eq!(tree.get(&4).map(String::as_str), Some("4!"));
+        assert_eq!(tree.get(&3).map(String::as_str), Some("3"));
+
+        let copy: TreeMap<i32, String> = tree.iter().map(|(k, v)| (*k, v.clone())).collect();
+        assert!(copy.into_keys().rev().eq(vec![5, 4, 3, 2, 1]));
+        let mut values = tree.into_values();
+        assert_eq!(values.len(), 5);
+        assert_eq!(values.next_back(), Some(String::from("5!")));
+        assert_eq!(values.next(), Some(String::from("1")));
+        assert_eq!(values.size_hint(), (3, Some(3)));
+    }
+
+    #[test]
+    fn test_order_statistics() {
+        use std::ops::Bound::{self, Excluded, Included, Unbounded};
+        use std::ops::RangeBounds;
+
+        let keys: Vec<i32> = (0..200).map(|i| i * 3).collect();
+        let tree: TreeMap<i32, i32> = keys.iter().map(|&k| (k, -k)).collect();
+
+        for (i, k) in keys.iter().enumerate() {
+            assert_eq!(tree.select(i), Some((k, &-k)));
+            assert_eq!(tree.rank(k), i);
+            assert_eq!(tree.rank(&(k + 1)), i + 1);
+        }
+        assert_eq!(tree.select(200), None);
+        assert_eq!(tree.rank(&-1), 0);
+        assert_eq!(TreeMap::<i32, i32>::new().select(0), None);
+
+        let bounds = |i| [Included(i), Excluded(i), Unbounded];
+        for lo in (-2..602).step_by(7) {
+            for hi in (lo..602).step_by(5) {
+                for &start in &bounds(lo) {
+                    for &end in &bounds(hi) {
+                        let range: (Bound<i32>, Bound<i32>) = (start, end);
+                        let count = keys.iter().filter(|k| range.contains(k)).count();
+                        assert_eq!(tree.range_count(range), count, "{:?}", range);
+                        assert_eq!(tree.range(range).len(), count, "{:?}", range);
+                    }
+                }
+            }
+        }
+        #[allow(clippy::reversed_empty_ranges)]
+        let backwards = tree.range_count(30..10);
+        assert_eq!(backwards, 0);
+    }
+
+    #[test]
+    fn test_nth() {
+        let keys: Vec<i32> = (0..1000).collect();
+        let mut tree: TreeMap<i32, i32> = keys.iter().map(|&k| (k, k)).collect();
+
+        for n in [0, 1, 2, 17, 500, 998, 999, 1000, 5000].iter().copied() {
+            assert_eq!(tree.iter().nth(n).map(|(k, _)| *k), keys.get(n).copied());
+            let back = keys.len().checked_sub(n + 1).map(|i| keys[i]);
+            assert_eq!(tree.iter().nth_back(n).map(|(k, _)| *k), back);
+            assert_eq!(tree.keys().nth(n), keys.get(n));
+            assert_eq!(tree.values_mut().nth_back(n).map(|v| *v), back);
+        }
+
+        // Jump around from both ends
+        let mut iter = tree.iter();
+        assert_eq!(iter.nth(10), Some((&10, &10)));
+        assert_eq!(iter.nth_back(10), Some((&989, &989)));
+        assert_eq!(iter.next(), Some((&11, &11)));
+        assert_eq!(iter.len(), 1000 - 23);
+        assert_eq!(iter.nth(976), Some((&988, &988)));
+        assert_eq!(iter.next(), None);
+
+        let mut range = tree.range(100..200);
+        assert_eq!(range.nth(50), Some((&150, &150)));
+        assert_eq!(range.nth_back(0), Some((&199, &199)));
+        assert_eq!(range.len(), 48);
+
+        let mut iter = tree.iter_mut();
+        *iter.nth(400).unwrap().1 = -1;
+        *iter.nth_back(400).unwrap().1 = -2;
+        assert_eq!(iter.len(), 1000 - 802);
+        assert_eq!(tree.get(&400), Some(&-1));
+        assert_eq!(tree.get(&599), Some(&-2));
+    }
+
+    #[test]
+    fn test_navigation() {
+        let mut tree: TreeMap<i32, char> = TreeMap::new();
+        assert_eq!(tree.first_key_value(), None);
+        assert_eq!(tree.last_key_value(), None);
+        assert_eq!(tree.pop_first(), None);
+        assert_eq!(tree.pop_last(), None);
+        assert_eq!(tree.floor(&0), None);
+
+        for (i, c) in "abcdefghij".chars().enumerate() {
+            tree.insert(i as i32 * 10, c);
+        }
+        assert_eq!(tree.first_key_value(), Some((&0, &'a')));
+        assert_eq!(tree.last_key_value(), Some((&90, &'j')));
+
+        assert_eq!(tree.floor(&40), Some((&40, &'e')));
+        assert_eq!(tree.floor(&45), Some((&40, &'e')));
+        assert_eq!(tree.floor(&-1), None);
+        assert_eq!(tree.ceiling(&40), Some((&40, &'e')));
+        assert_eq!(tree.ceiling(&45), Some((&50, &'f')));
+        assert_eq!(tree.ceiling(&91), None);
+        assert_eq!(tree.lower(&40), Some((&30, &'d')));
+        assert_eq!(tree.lower(&0), None);
+        assert_eq!(tree.higher(&40), Some((&50, &'f')));
+        assert_eq!(tree.higher(&90), None);
+        assert_eq!(tree.higher(&-100), Some((&0, &'a')));
+
+        assert_eq!(tree.pop_first(), Some((0, 'a')));
+        assert_eq!(tree.pop_last(), Some((90, 'j')));
+        assert_eq!(tree.len(), 8);
+        assert_eq!(tree.check_invariants(), Ok(()));
+
+        // Drain a bigger map from both ends, checking the tree as we go
+        let mut tree: TreeMap<i32, i32> = (0..1000).map(|i| (i, i)).collect();
+        for i in 0..500 {
+            assert_eq!(tree.pop_first(), Some((i, i)));
+            assert_eq!(tree.pop_last(), Some((999 - i, 999 - i)));
+            assert_eq!(tree.len(), 998 - 2 * i as usize);
+            if i % 50 == 0 {
+                assert_eq!(tree.check_invariants(), Ok(()));
+            }
+        }
+        assert!(tree.is_empty());
+    }
+
+    #[test]
+    fn test_split_off() {
+        for n in [0, 1, 2, 10, 100, 1000].iter().copied() {
+            for at in [-1, 0, 1, n / 3, n / 2, n - 1, n, n + 5].iter().copied() {
+                let mut lt: TreeMap<i32, i32> = (0..n).map(|i| (i, i)).collect();
+                let rt = lt.split_off(&at);
+                let at = at.max(0).min(n);
+                assert!(lt.keys().copied().eq(0..at), "{} at {}", n, at);
+                assert!(rt.keys().copied().eq(at..n), "{} at {}", n, at);
+                assert_eq!(lt.check_invariants(), Ok(()));
+                assert_eq!(rt.check_invariants(), Ok(()));
+
+                // ... and back together
+                assert!(lt.join(rt).is_ok());
+                assert!(lt.keys().copied().eq(0..n));
+                assert_eq!(lt.check_invariants(), Ok(()));
+            }
+        }
+
+        // Split at keys that aren't in the map
+        let mut tree: TreeMap<String, usize> = ["ant", "bee", "cat", "dog", "eel"]
+            .iter()
+            .map(|s| (s.to_string(), s.len()))
+            .collect();
+        let rest = tree.split_off("c");
+        assert!(tree.keys().eq(&["ant", "bee"]));
+        assert!(rest.keys().eq(&["cat", "dog", "eel"]));
+    }
+
+    #[test]
+    fn test_join() {
+        // Trees of very different heights, in both orders
+        for (small, big) in [(0, 0), (1, 0), (0, 1), (3, 5000), (5000, 3), (700, 900)].iter() {
+            let mut lt: TreeMap<i32, i32> = (0..*small).map(|i| (i, i)).collect();
+            let rt: TreeMap<i32, i32> = (*small..small + big).map(|i| (i, i)).collect();
+            assert!(lt.join(rt).is_ok());
+            assert!(lt.keys().copied().eq(0..small + big));
+            assert_eq!(lt.check_invariants(), Ok(()));
+            assert!(lt.height() <= lt.max_height());
+
+            let mut rt: TreeMap<i32, i32> = (*small..small + big).map(|i| (i, i)).collect();
+            let lt: TreeMap<i32, i32> = (0..*small).map(|i| (i, i)).collect();
+            assert!(rt.join(lt).is_ok());
+            assert!(rt.keys().copied().eq(0..small + big));
+            assert_eq!(rt.check_invariants(), Ok(()));
+        }
+
+        // Keys that interleave can't be joined
+        let mut evens: TreeMap<i32, i32> = (0..10).map(|i| (i * 2, i)).collect();
+        let odds: TreeMap<i32, i32> = (0..10).map(|i| (i * 2 + 1, i)).collect();
+        let odds = evens.join(odds).unwrap_err();
+        assert_eq!(evens.len(), 10);
+        assert_eq!(odds.len(), 10);
+        let mut same: TreeMap<i32, i32> = TreeMap::new();
+        same.insert(18, 0);
+        assert!(evens.join(same).is_err());
+    }
+
+    #[test]
+    fn test_append() {
+        let mut tree: TreeMap<i32, &str> = (0..100).map(|i| (i, "a")).collect();
+        let mut other: TreeMap<i32, &str> = (50..300).map(|i| (i, "b")).collect();
+        tree.append(&mut other);
+        assert!(other.is_empty());
+        assert_eq!(tree.len(), 300);
+        assert_eq!(tree.get(&49), Some(&"a"));
+        assert_eq!(tree.get(&50), Some(&"b"));
+        assert_eq!(tree.check_invariants(), Ok(()));
+
+        // The bigger map on either side; `other` still wins
+        let mut small: TreeMap<i32, &str> = (0..10).map(|i| (i * 3, "c")).collect();
+        small.append(&mut tree);
+        assert_eq!(small.len(), 300);
+        assert_eq!(small.get(&3), Some(&"a"));
+        assert_eq!(small.get(&60), Some(&"b"));
+        assert_eq!(small.check_invariants(), Ok(()));
+
+        let mut after: TreeMap<i32, &str> = (300..310).map(|i| (i, "d")).collect();
+        small.append(&mut after);
+        assert_eq!(small.len(), 310);
+        assert!(small.keys().copied().eq(0..310));
+        assert_eq!(small.check_invariants(), Ok(()));
+    }
+
+    #[test]
+    fn test_range() {
+        use std::collections::BTreeMap;
+        use std::ops::Bound::{self, Excluded, Included, Unbounded};
+
+        let tree: TreeMap<i32, i32> = (0..50).map(|i| (i * 2, i)).collect();
+        let model: BTreeMap<i32, i32> = (0..50).map(|i| (i * 2, i)).collect();
+
+        assert!(tree.range(10..20).eq(model.range(10..20)));
+        assert!(tree.range(11..=21).rev().eq(model.range(11..=21).rev()));
+        assert!(tree.range(..7).eq(model.range(..7)));
+        assert!(tree.range(90..).eq(model.range(90..)));
+        assert!(tree.range(..).eq(tree.iter()));
+        assert_eq!(tree.range(20..20).next(), None);
+        assert_eq!(tree.range(200..).next(), None);
+        #[allow(clippy::reversed_empty_ranges)]
+        let backwards = tree.range(30..10).next();
+        assert_eq!(backwards, None);
+
+        // Every kind of bound, with both ends
+        let bounds = |i| [Included(i), Excluded(i), Unbounded];
+        for lo in -1..102 {
+            for hi in lo..102 {
+                for &start in &bounds(lo) {
+                    for &end in &bounds(hi) {
+                        let range: (Bound<i32>, Bound<i32>) = (start, end);
+                        if lo == hi && (start, end) == (Excluded(lo), Excluded(hi)) {
+                            continue; // `BTreeMap` panics on these
+                        }
+                        assert!(tree.range(range).eq(model.range(range)), "{:?}", range);
+                    }
+                }
+            }
+        }
+
+        // Taking from both ends meets in the middle
+        let mut range = tree.range(10..=30);
+        assert_eq!(range.next(), Some((&10, &5)));
+        assert_eq!(range.next_back(), Some((&30, &15)));
+        let middle: Vec<_> = range.map(|(k, _)| *k).collect();
+        assert_eq!(middle, [12, 14, 16, 18, 20, 22, 24, 26, 28]);
+
+        let words: TreeMap<String, usize> = ["ant", "bee", "cat", "dog"]
+            .iter()
+            .map(|s| (s.to_string(), s.len()))
+            .collect();
+        let keys: Vec<_> = words
+            .range::<str, _>((Included("b"), Excluded("d")))
+            .map(|(k, _)| k.as_str())
+            .collect();
+        assert_eq!(keys, ["bee", "cat"]);
+    }
+
+    #[test]
+    fn test_range_mut() {
+        let mut tree: TreeMap<i32, i32> = (0..100).map(|i| (i, 0)).collect();
+        for (k, v) in tree.range_mut(20..40) {
+            *v = *k;
+        }
+        for (k, v) in tree.range_mut(30..=50).rev().take(5) {
+            *v = -k;
+        }
+        let mut range = tree.range_mut(..3);
+        *range.next_back().unwrap().1 = 1;
+        *range.next().unwrap().1 = 1;
+        assert_eq!(range.next().map(|(k, v)| (*k, *v)), Some((1, 0)));
+        assert_eq!(range.next_back().map(|(k, _)| *k), None);
+
+        assert_eq!(tree.get(&0), Some(&1));
+        assert_eq!(tree.get(&2), Some(&1));
+        assert_eq!(tree.get(&19), Some(&0));
+        assert_eq!(tree.get(&35), Some(&35));
+        assert_eq!(tree.get(&46), Some(&-46));
+        assert_eq!(tree.get(&45), Some(&0));
+        assert_eq!(tree.len(), 100);
+        assert_eq!(tree.check_invariants(), Ok(()));
+    }
+
     use std::cell::RefCell;
 
     #[derive(Debug)]
@@ -815,13 +2781,11 @@ mod test {
         assert_eq!(*drops.borrow(), contents);
     }
 
-    /* Optional test: uncomment if you are doing the `Drop` extension.
     #[test]
     fn test_drop_big() {
-        // This is slow (~1-2 min on my machine) but it shouldn't overflow!
         let v = (1..100000).collect::<Vec<u32>>();
 
-        // Make a really deep binary tree
+        // Make a big binary tree
         {
             let mut tree = TreeMap::new();
             for i in v {
@@ -829,6 +2793,80 @@ mod test {
             }
         } // ... and drop it
 
-        println!("All done!");
-    } */
+        // `insert` keeps the tree balanced, so also drop one that is a single long path
+        drop(left_spine(1_000_000));
+    }
+
+    /// Test function: a tree that is a single path down the left, with keys `0..n`. `insert`
+    /// can't build such a tree, since it keeps the tree balanced.
+    fn left_spine(n: u32) -> TreeMap<u32, u32> {
+        let mut tree = TreeMap::new();
+        for i in 0..n {
+            let mut node = Node::new(i, i);
+            node.lt = tree;
+            node.update();
+            tree = TreeMap {
+                inner: Some(Box::new(node)),
+            };
+        }
+        tree
+    }
+
+    #[test]
+    fn test_deep_tree() {
+        // Deep enough to overflow the stack of a test thread with recursive traversals
+        let n = 1_000_000;
+        let mut tree = left_spine(n);
+        assert_eq!(tree.height(), n as usize);
+
+        assert!(tree.has_key(&0));
+        assert_eq!(tree.get(&1), Some(&1));
+        *tree.get_mut(&2).unwrap() = 22;
+        assert_eq!(tree.get(&2), Some(&22));
+        assert_eq!(tree.get(&n), None);
+
+        assert_eq!(tree.iter().count(), n as usize);
+        assert!(tree.iter().map(|(k, _)| *k).eq(0..n));
+        for (_, v) in tree.iter_mut() {
+            *v += 1;
+        }
+
+        // Updates rebalance the nodes along their path
+        assert_eq!(tree.insert(n, n), None);
+        assert_eq!(tree.remove(&0), Some(1));
+        assert_eq!(tree.len(), n as usize);
+        let broken = tree.check_invariants().map_err(|err| err.invariant);
+        assert_eq!(broken, Err(Invariant::Balance));
+
+        assert!(tree.into_iter().map(|(k, _)| k).eq(1..=n));
+        drop(left_spine(n));
+    }
+
+    /// Slow in debug builds; run with `cargo test --release -- --ignored`.
+    #[test]
+    #[ignore]
+    fn test_deep_tree_ten_million() {
+        // Ten million nodes in a single path, on a thread with the default 8 MiB main stack
+        let n = 10_000_000;
+        let test = move || {
+            let mut tree = left_spine(n);
+            assert_eq!(tree.get(&0), Some(&0));
+            assert!(tree.iter().map(|(k, _)| *k).eq(0..n));
+            for (_, v) in tree.iter_mut() {
+                *v += 1;
+            }
+            assert_eq!(tree.insert(n, n), None);
+            assert_eq!(tree.remove(&0), Some(1));
+            assert_eq!(tree.len(), n as usize);
+            drop(tree);
+
+            assert!(left_spine(n).into_iter().map(|(k, _)| k).eq(0..n));
+        };
+        thread::Builder::new()
+            .stack_size(8 << 20)
+            .spawn(test)
+            .unwrap()
+            .join()
+            .unwrap();
+    }
 }